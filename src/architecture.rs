@@ -1,9 +1,324 @@
-#[cfg(target_arch = "x86_64")]
-pub mod x86_64;
-#[cfg(target_arch = "x86_64")]
-pub use self::x86_64::*;
+use crate::generator::OptLevel;
+use crate::ir::{BinOp, Function, VReg};
+use crate::regalloc::{allocate, Assignment, Registers};
+use x86_64::Syntax;
 
-#[cfg(target_arch = "aarch64")]
 pub mod aarch64;
-#[cfg(target_arch = "aarch64")]
-pub use self::aarch64::*;
+pub mod riscv64;
+pub mod x86_64;
+
+/// Writes the assembly for functions in the IR.
+pub trait Backend {
+    fn program_prologue(&self) -> String;
+    /// The registers the ABI leaves to the register allocator.
+    fn registers(&self) -> Registers;
+    /// From `-O1` on, the x86_64 and aarch64 backends run the peephole pass over the code.
+    fn function(&self, function: &Function, frame: &Frame, opt_level: OptLevel) -> String;
+}
+
+/// What a comparison tests, for setting a register or branching on the flags.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Cond {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Cond {
+    /// `None` for the arithmetic operations.
+    pub fn new(op: BinOp) -> Option<Cond> {
+        match op {
+            BinOp::Eq => Some(Cond::Eq),
+            BinOp::Ne => Some(Cond::Ne),
+            BinOp::Lt => Some(Cond::Lt),
+            BinOp::Le => Some(Cond::Le),
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div => None,
+        }
+    }
+
+    /// The condition that holds exactly when this one doesn't.
+    pub fn inverse(self) -> Cond {
+        match self {
+            Cond::Eq => Cond::Ne,
+            Cond::Ne => Cond::Eq,
+            Cond::Lt => Cond::Ge,
+            Cond::Le => Cond::Gt,
+            Cond::Gt => Cond::Le,
+            Cond::Ge => Cond::Lt,
+        }
+    }
+}
+
+/// Where a virtual register lives.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Location {
+    /// a register the backend numbers like `Backend::registers`
+    Register(usize),
+    /// the offset of a slot below the frame pointer
+    Stack(usize),
+}
+
+/// Where a function keeps its values. Below the frame pointer come an 8-byte slot for every
+/// variable, the slots of spilled virtual registers and then the callee-saved registers the
+/// function saves.
+pub struct Frame {
+    vars: usize,
+    assignments: Vec<Option<Assignment>>,
+    spill_slots: usize,
+    callee_saved: Vec<usize>,
+    /// the bytes below the frame pointer, a multiple of 16 so calls see an aligned stack
+    pub size: usize,
+}
+
+impl Frame {
+    /// Allocates `registers` to the virtual registers; with none, every one gets a slot.
+    pub fn new(function: &Function, registers: Registers) -> Frame {
+        let allocation = allocate(function, registers);
+        let vars = function.vars.len();
+        let slots = vars + allocation.spill_slots + allocation.callee_saved.len();
+        Frame {
+            vars,
+            assignments: allocation.assignments,
+            spill_slots: allocation.spill_slots,
+            callee_saved: allocation.callee_saved,
+            size: (slots * 8).div_ceil(16) * 16,
+        }
+    }
+
+    /// The offset below the frame pointer of variable `var`.
+    pub fn var(&self, var: usize) -> usize {
+        (var + 1) * 8
+    }
+
+    pub fn location(&self, vreg: VReg) -> Location {
+        match self.assignments[vreg].expect("every virtual register in use is allocated") {
+            Assignment::Register(register) => Location::Register(register),
+            Assignment::Spill(slot) => Location::Stack((self.vars + slot + 1) * 8),
+        }
+    }
+
+    /// The callee-saved registers the prologue saves and every return restores, with the offset
+    /// of their slots.
+    pub fn saved(&self) -> Vec<(usize, usize)> {
+        let base = self.vars + self.spill_slots;
+        self.callee_saved
+            .iter()
+            .enumerate()
+            .map(|(n, register)| (*register, (base + n + 1) * 8))
+            .collect()
+    }
+}
+
+/// The object file conventions of the operating system a target runs on: ELF on Linux and
+/// Mach-O on Darwin.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Os {
+    Linux,
+    Darwin,
+}
+
+impl Os {
+    /// On ELF, also marks the stack as non-executable.
+    pub fn text_section(&self) -> &'static str {
+        match self {
+            Os::Linux => ".section .note.GNU-stack,\"\",%progbits\n.text\n",
+            Os::Darwin => ".section __TEXT,__text,regular,pure_instructions\n",
+        }
+    }
+
+    /// The assembler name of a C symbol. Mach-O prefixes an underscore.
+    pub fn symbol(&self, name: &str) -> String {
+        match self {
+            Os::Linux => name.to_owned(),
+            Os::Darwin => format!("_{}", name),
+        }
+    }
+
+    /// A label that stays out of the symbol table, like `.Lbegin001` on ELF and `Lbegin001` on
+    /// Mach-O.
+    pub fn local_label(&self, name: &str, n: usize) -> String {
+        let prefix = match self {
+            Os::Linux => ".L",
+            Os::Darwin => "L",
+        };
+        format!("{}{}{:0width$}", prefix, name, n, width = 3)
+    }
+
+    pub fn main_func(&self) -> String {
+        let main = self.symbol("main");
+        format!(".globl {}\n{}:\n", main, main)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Target {
+    X86_64Linux,
+    X86_64Darwin,
+    Aarch64Linux,
+    Aarch64Darwin,
+    Riscv64Linux,
+    /// WebAssembly text run under WASI
+    Wasm32Wasi,
+}
+
+impl Target {
+    pub fn host() -> Target {
+        if cfg!(all(target_arch = "aarch64", target_os = "macos")) {
+            Target::Aarch64Darwin
+        } else if cfg!(target_arch = "aarch64") {
+            Target::Aarch64Linux
+        } else if cfg!(target_arch = "riscv64") {
+            Target::Riscv64Linux
+        } else if cfg!(target_os = "macos") {
+            Target::X86_64Darwin
+        } else {
+            Target::X86_64Linux
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Target, &'static str> {
+        match s {
+            "x86_64-linux" => Ok(Target::X86_64Linux),
+            "x86_64-darwin" => Ok(Target::X86_64Darwin),
+            "aarch64-linux" => Ok(Target::Aarch64Linux),
+            "aarch64-darwin" => Ok(Target::Aarch64Darwin),
+            "riscv64-linux" => Ok(Target::Riscv64Linux),
+            "wasm32-wasi" => Ok(Target::Wasm32Wasi),
+            _ => Err("unsupported target"),
+        }
+    }
+
+    /// `None` for wasm32, which has no OS to speak of.
+    pub fn os(&self) -> Option<Os> {
+        match self {
+            Target::X86_64Linux | Target::Aarch64Linux | Target::Riscv64Linux => Some(Os::Linux),
+            Target::X86_64Darwin | Target::Aarch64Darwin => Some(Os::Darwin),
+            Target::Wasm32Wasi => None,
+        }
+    }
+
+    /// `syntax` only matters for x86_64. `None` for wasm32: its structured control flow can't be
+    /// built from the jumps between basic blocks, so `wasm` lowers the trees itself.
+    pub fn backend(&self, syntax: Syntax) -> Option<Box<dyn Backend>> {
+        let os = self.os()?;
+        Some(match self {
+            Target::X86_64Linux | Target::X86_64Darwin => Box::new(x86_64::X86_64 { syntax, os }),
+            Target::Aarch64Linux | Target::Aarch64Darwin => Box::new(aarch64::Aarch64 { os }),
+            Target::Riscv64Linux => Box::new(riscv64::Riscv64 { os }),
+            Target::Wasm32Wasi => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(Ok(Target::X86_64Linux), Target::parse("x86_64-linux"));
+        assert_eq!(Ok(Target::X86_64Darwin), Target::parse("x86_64-darwin"));
+        assert_eq!(Ok(Target::Aarch64Linux), Target::parse("aarch64-linux"));
+        assert_eq!(Ok(Target::Aarch64Darwin), Target::parse("aarch64-darwin"));
+        assert_eq!(Ok(Target::Riscv64Linux), Target::parse("riscv64-linux"));
+        assert_eq!(Ok(Target::Wasm32Wasi), Target::parse("wasm32-wasi"));
+        assert_eq!(Err("unsupported target"), Target::parse("mips-linux"));
+    }
+
+    #[test]
+    fn os_test() {
+        assert_eq!("main", Os::Linux.symbol("main"));
+        assert_eq!("_main", Os::Darwin.symbol("main"));
+        assert_eq!(".Lbegin001", Os::Linux.local_label("begin", 1));
+        assert_eq!("Lend010", Os::Darwin.local_label("end", 10));
+    }
+
+    #[test]
+    fn backend_test() {
+        use crate::ir::{Block, Function, Inst, Terminator};
+
+        let function = Function {
+            name: "main".to_owned(),
+            vars: vec![".last".to_owned()],
+            blocks: vec![Block {
+                insts: vec![Inst::Call {
+                    dst: 0,
+                    name: "foo".to_owned(),
+                }],
+                terminator: Terminator::Return(0),
+                stmts: vec![],
+            }],
+            vregs: 1,
+        };
+        let function = |target: Target| {
+            let backend = target.backend(Syntax::Intel).unwrap();
+            let frame = Frame::new(&function, backend.registers());
+            backend.function(&function, &frame, OptLevel::O1)
+        };
+
+        assert_eq!(
+            ".intel_syntax noprefix\n.section .note.GNU-stack,\"\",%progbits\n.text\n",
+            Target::X86_64Linux
+                .backend(Syntax::Intel)
+                .unwrap()
+                .program_prologue()
+        );
+        assert!(function(Target::Aarch64Linux).contains("\tbl foo\n"));
+        assert!(function(Target::X86_64Linux).contains("\tcall foo@PLT\n"));
+        assert!(function(Target::X86_64Darwin).contains("\tcall _foo\n"));
+        assert!(function(Target::Aarch64Darwin).starts_with(".globl _main\n_main:\n"));
+        assert!(function(Target::Riscv64Linux).starts_with(".globl main\nmain:\n"));
+        assert!(Target::Wasm32Wasi.backend(Syntax::Intel).is_none());
+    }
+
+    #[test]
+    fn frame_test() {
+        use crate::ir::{BinOp, Block, Function, Inst, Terminator};
+
+        // v1 is live across the call
+        let function = Function {
+            name: "main".to_owned(),
+            vars: vec!["a".to_owned(), ".last".to_owned()],
+            blocks: vec![Block {
+                insts: vec![
+                    Inst::Const { dst: 1, value: 7 },
+                    Inst::Call {
+                        dst: 2,
+                        name: "foo".to_owned(),
+                    },
+                    Inst::Binary {
+                        op: BinOp::Add,
+                        dst: 3,
+                        lhs: 1,
+                        rhs: 2,
+                    },
+                ],
+                terminator: Terminator::Return(3),
+                stmts: vec![],
+            }],
+            vregs: 4,
+        };
+
+        let frame = Frame::new(&function, Registers::default());
+        assert_eq!(16, frame.var(1));
+        assert_eq!(Location::Stack(24), frame.location(1));
+        assert_eq!(Location::Stack(32), frame.location(2));
+        // v3 reuses the slot of v1, which dies where v3 is defined
+        assert_eq!(Location::Stack(24), frame.location(3));
+        assert_eq!(32, frame.size);
+        assert!(frame.saved().is_empty());
+
+        let registers = Registers {
+            caller_saved: 2,
+            callee_saved: 2,
+        };
+        let frame = Frame::new(&function, registers);
+        assert_eq!(Location::Register(2), frame.location(1));
+        assert_eq!(Location::Register(0), frame.location(2));
+        assert_eq!(vec![(2, 24)], frame.saved());
+        assert_eq!(32, frame.size);
+    }
+}
@@ -1,14 +1,17 @@
-use crate::token::Int;
+use super::{Backend, Cond, Frame, Location, Os};
+use crate::generator::OptLevel;
+use crate::ir::{BinOp, BlockId, Function, Inst, Terminator, VReg};
+use crate::peephole::{self, live, peephole, Flow, Rule};
+use crate::regalloc::Registers;
 use core::fmt;
 
-pub enum Register {
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Register {
     /// x0
     R0,
     /// x1
     R1,
-    /// x8(rbp)
-    R8,
-    /// x9(rsp)
+    /// x9, for addresses out of reach of an immediate offset
     R9,
     /// sp
     R13,
@@ -16,12 +19,24 @@ pub enum Register {
     R29,
     /// LR
     R30,
+    /// one of `ALLOCATABLE`
+    Allocated(usize),
 }
 
-pub enum Operand {
-    Num(Int),
+/// The registers the allocator hands out, the caller-saved ones first. x16 and x17 are for the
+/// linker and x18 is reserved on Darwin.
+const ALLOCATABLE: [&str; 16] = [
+    "x10", "x11", "x12", "x13", "x14", "x15", "x19", "x20", "x21", "x22", "x23", "x24", "x25",
+    "x26", "x27", "x28",
+];
+const CALLER_SAVED: usize = 6;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Operand {
+    Num(usize),
     Register(Register),
-    Address(Register),
+    /// `[register, #offset]`
+    Address(Register, usize),
 }
 
 impl fmt::Display for Register {
@@ -29,11 +44,11 @@ impl fmt::Display for Register {
         let name = match self {
             Register::R0 => "x0",
             Register::R1 => "x1",
-            Register::R8 => "x8",
             Register::R9 => "x9",
             Register::R13 => "sp",
             Register::R29 => "x29",
             Register::R30 => "x30",
+            Register::Allocated(n) => ALLOCATABLE[*n],
         };
         write!(f, "{}", name)
     }
@@ -43,372 +58,539 @@ impl fmt::Display for Operand {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let name: String = match self {
             Operand::Register(r) => r.to_string(),
-            Operand::Address(r) => format!("[{}]", r),
+            Operand::Address(r, 0) => format!("[{}]", r),
+            Operand::Address(r, n) => format!("[{}, #{}]", r, n),
             Operand::Num(n) => format!("#{}", n),
         };
         write!(f, "{}", name)
     }
 }
 
-pub fn program_prologue() -> String {
-    ".text\n".to_owned()
-}
-
-pub fn main_func() -> String {
-    ".globl _main\n_main:\n".to_owned()
-}
-
-/// mov rbp(r8), sp(r13)
-/// mov rsp(r9), sp(r13)
-/// push rbp(r9)
-/// sub rsp(r9), $bytes
-pub fn memory_allocate(bytes: usize) -> String {
-    format!(
-        "; memory allocate\n{}{}{}{}",
-        mov(
-            Operand::Register(Register::R8),
-            Operand::Register(Register::R13)
-        ),
-        mov(
-            Operand::Register(Register::R9),
-            Operand::Register(Register::R13)
-        ),
-        push(Operand::Register(Register::R9)),
-        sub(Operand::Register(Register::R9), Operand::Num(bytes))
-    )
-}
-
-fn ldr(rd: Operand, rn: Operand) -> String {
-    format!("\tldr {}, {}\n", rd, rn)
-}
-
-fn str(rd: Operand, rn: Operand) -> String {
-    format!("\tstr {}, {}\n", rd, rn)
-}
-
-fn ldp(rd: Operand, rn: Operand) -> String {
-    format!("\tldp {}, {}, [sp], 16\n", rd, rn)
-}
-
-fn stp(rd: Operand, rn: Operand) -> String {
-    format!("\tstp {}, {}, [sp, -16]!\n", rd, rn)
-}
-
-pub fn stmt_epilogue() -> String {
-    format!(
-        "; statement epilogue\n{}",
-        pop(Operand::Register(Register::R0))
-    )
-}
-
-/// mov rsp(r9), rbp(r8)
-/// pop rbp(r8)
-/// ret
-pub fn program_epilogue() -> String {
-    format!(
-        "; program epilogue\n{}{}{}",
-        mov(
-            Operand::Register(Register::R9),
-            Operand::Register(Register::R8)
-        ),
-        pop(Operand::Register(Register::R8)),
-        ret()
-    )
-}
-
-/// mov r0, rbp(r8)
-/// sub rax, offset
-/// push rax
-pub fn gen_val(name: &str, offset: usize) -> String {
-    format!(
-        "; value: {}\n{}{}{}",
-        name,
-        mov(
-            Operand::Register(Register::R0),
-            Operand::Register(Register::R8)
-        ),
-        sub(Operand::Register(Register::R0), Operand::Num(offset)),
-        push(Operand::Register(Register::R0))
-    )
-}
-
-/// pop r0
-/// mov r0, [r0]
-/// push r0
-pub fn pop_val() -> String {
-    format!(
-        "; pop value\n{}{}{}",
-        pop(Operand::Register(Register::R0)),
-        ldr(
-            Operand::Register(Register::R0),
-            Operand::Address(Register::R0)
-        ),
-        push(Operand::Register(Register::R0))
-    )
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Sdiv,
+}
+
+/// A line of the assembly, written out once the peephole pass is done.
+#[derive(Debug, PartialEq, Clone)]
+enum Line {
+    /// written as is, like `.globl main`
+    Directive(String),
+    Label(String),
+    Comment(String),
+    Ldr(Register, Operand),
+    Str(Register, Operand),
+    /// stp rd, rn, [sp, -16]!
+    Stp(Register, Register),
+    /// ldp rd, rn, [sp], 16
+    Ldp(Register, Register),
+    Movz(Register, u64),
+    /// movk rd, #chunk, lsl #shift
+    Movk(Register, u64, u32),
+    /// rd <- rn op operand
+    Arith(Arith, Register, Register, Operand),
+    Cmp(Register, Register),
+    Cset(Register, Cond),
+    Mov(Register, Register),
+    /// `b` without a condition, `b.<cond>` with one
+    B(Option<Cond>, String),
+    Cbz(Register, String),
+    Cbnz(Register, String),
+    Bl(String),
+    Ret,
+}
+
+fn cond_name(cond: Cond) -> &'static str {
+    match cond {
+        Cond::Eq => "EQ",
+        Cond::Ne => "NE",
+        Cond::Lt => "LT",
+        Cond::Le => "LE",
+        Cond::Gt => "GT",
+        Cond::Ge => "GE",
+    }
 }
 
-/// pop r1
-/// pop r0
-/// mov [r0], r1
-/// push r1
-pub fn pop_lvar() -> String {
-    format!(
-        "; pop lvar\n{}{}{}{}",
-        pop(Operand::Register(Register::R1)),
-        pop(Operand::Register(Register::R0)),
-        str(
-            Operand::Register(Register::R1),
-            Operand::Address(Register::R0)
-        ),
-        push(Operand::Register(Register::R1))
-    )
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Directive(text) => write!(f, "{}", text),
+            Line::Label(label) => writeln!(f, "{}:", label),
+            Line::Comment(text) => writeln!(f, "// {}", text),
+            Line::Ldr(rd, rn) => writeln!(f, "\tldr {}, {}", rd, rn),
+            Line::Str(rd, rn) => writeln!(f, "\tstr {}, {}", rd, rn),
+            Line::Stp(rd, rn) => writeln!(f, "\tstp {}, {}, [sp, -16]!", rd, rn),
+            Line::Ldp(rd, rn) => writeln!(f, "\tldp {}, {}, [sp], 16", rd, rn),
+            Line::Movz(rd, n) => writeln!(f, "\tmovz {}, #{}", rd, n),
+            Line::Movk(rd, chunk, shift) => {
+                writeln!(f, "\tmovk {}, #{}, lsl #{}", rd, chunk, shift)
+            }
+            Line::Arith(arith, rd, rn, rm) => {
+                let mnemonic = match arith {
+                    Arith::Add => "add",
+                    Arith::Sub => "sub",
+                    Arith::Mul => "mul",
+                    Arith::Sdiv => "sdiv",
+                };
+                writeln!(f, "\t{} {}, {}, {}", mnemonic, rd, rn, rm)
+            }
+            Line::Cmp(rn, rm) => writeln!(f, "\tcmp {}, {}", rn, rm),
+            Line::Cset(rd, cond) => writeln!(f, "\tcset {}, {}", rd, cond_name(*cond)),
+            Line::Mov(rd, rn) => writeln!(f, "\tmov {}, {}", rd, rn),
+            Line::B(None, label) => writeln!(f, "\tb {}", label),
+            Line::B(Some(cond), label) => {
+                writeln!(f, "\tb.{} {}", cond_name(*cond).to_lowercase(), label)
+            }
+            Line::Cbz(rn, label) => writeln!(f, "\tcbz {}, {}", rn, label),
+            Line::Cbnz(rn, label) => writeln!(f, "\tcbnz {}, {}", rn, label),
+            Line::Bl(symbol) => writeln!(f, "\tbl {}", symbol),
+            Line::Ret => writeln!(f, "\tret"),
+        }
+    }
 }
 
-/// pop r1
-/// pop r0
-pub fn pop_arg() -> String {
-    format!(
-        "; pop arg\n{}{}",
-        pop(Operand::Register(Register::R1)),
-        pop(Operand::Register(Register::R0))
-    )
+fn uses(operand: Operand, register: Register) -> bool {
+    match operand {
+        Operand::Register(r) | Operand::Address(r, _) => r == register,
+        Operand::Num(_) => false,
+    }
 }
 
-/// sub r9, #8
-/// mov r0, rd
-/// str r0, r9
-pub fn push(rd: Operand) -> String {
-    format!(
-        "; push\n{}{}{}",
-        sub(Operand::Register(Register::R9), Operand::Num(8)),
-        mov(Operand::Register(Register::R1), rd),
-        str(
-            Operand::Register(Register::R1),
-            Operand::Address(Register::R9)
-        )
-    )
-}
+impl peephole::Line for Line {
+    type Register = Register;
 
-/// ldr rd, r9
-/// add r9, #8
-pub fn pop(rd: Operand) -> String {
-    format!(
-        "; pop\n{}{}",
-        ldr(rd, Operand::Address(Register::R9)),
-        add(Operand::Register(Register::R9), Operand::Num(8))
-    )
-}
+    fn is_comment(&self) -> bool {
+        matches!(self, Line::Comment(_))
+    }
 
-pub fn add_arg() -> String {
-    add(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
-}
+    fn label(&self) -> Option<&str> {
+        match self {
+            Line::Label(label) => Some(label),
+            _ => None,
+        }
+    }
 
-fn add(rd: Operand, rn: Operand) -> String {
-    format!("\tadd {}, {}, {}\n", rd, rd, rn) // rd <- rn + src2
-}
+    fn flow(&self) -> Flow<'_> {
+        match self {
+            Line::B(None, label) => Flow::Jump(label),
+            Line::B(Some(_), label) | Line::Cbz(_, label) | Line::Cbnz(_, label) => {
+                Flow::Branch(label)
+            }
+            Line::Ret => Flow::Return,
+            _ => Flow::Next,
+        }
+    }
 
-pub fn sub_arg() -> String {
-    sub(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
-}
+    /// A directive could do anything, so it reads every register. `ret` reads the result and
+    /// what the caller expects preserved.
+    fn reads(&self, register: Register) -> bool {
+        match *self {
+            Line::Ldr(_, rn) => uses(rn, register),
+            Line::Str(rd, rn) => rd == register || uses(rn, register),
+            Line::Stp(rd, rn) => [rd, rn, Register::R13].contains(&register),
+            Line::Ldp(..) | Line::Bl(_) => register == Register::R13,
+            Line::Movk(rd, ..) => rd == register,
+            Line::Arith(_, _, rn, rm) => rn == register || uses(rm, register),
+            Line::Cmp(rn, rm) => rn == register || rm == register,
+            Line::Mov(_, rn) | Line::Cbz(rn, _) | Line::Cbnz(rn, _) => rn == register,
+            Line::Directive(_) => true,
+            Line::Ret => match register {
+                Register::Allocated(n) => n >= CALLER_SAVED,
+                _ => register != Register::R1 && register != Register::R9,
+            },
+            Line::Label(_) | Line::Comment(_) | Line::Movz(..) | Line::Cset(..) | Line::B(..) => {
+                false
+            }
+        }
+    }
 
-fn sub(rd: Operand, rn: Operand) -> String {
-    format!("\tsub {}, {}, {}\n", rd, rd, rn) // rd <- rn - src2
+    fn writes(&self, register: Register) -> bool {
+        match *self {
+            Line::Ldr(rd, _)
+            | Line::Movz(rd, _)
+            | Line::Movk(rd, ..)
+            | Line::Arith(_, rd, ..)
+            | Line::Cset(rd, _)
+            | Line::Mov(rd, _) => rd == register,
+            Line::Ldp(rd, rn) => rd == register || rn == register,
+            // a call clobbers the caller-saved registers
+            Line::Bl(_) => match register {
+                Register::R0 | Register::R1 | Register::R9 | Register::R30 => true,
+                Register::Allocated(n) => n < CALLER_SAVED,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
 }
 
-pub fn mul_arg() -> String {
-    mul(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
-}
+/// The rules of the peephole pass, tried in order at every line.
+const RULES: [Rule<Line>; 4] = [self_move, reload, fuse_branch, dead_set];
 
-fn mul(rd: Operand, rn: Operand) -> String {
-    format!("\tmul {}, {}, {}\n", rd, rd, rn) // rd <- rn x rm
+/// mov rd, rd
+/// does nothing
+fn self_move(code: &[Line], at: usize) -> Option<(usize, Vec<Line>)> {
+    match code[at] {
+        Line::Mov(rd, rn) if rd == rn => Some((1, vec![])),
+        _ => None,
+    }
 }
 
-pub fn div_arg() -> String {
-    div(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
+/// str rd, [address]
+/// ldr rd, [address]
+/// needs no load, and neither does the second of two moves between the same registers
+fn reload(code: &[Line], at: usize) -> Option<(usize, Vec<Line>)> {
+    match (&code[at], code.get(at + 1)?) {
+        (Line::Str(rd, rn), Line::Ldr(rd2, rn2)) if rd == rd2 && rn == rn2 && !uses(*rn, *rd) => {
+            Some((2, vec![code[at].clone()]))
+        }
+        (Line::Mov(rd, rn), Line::Mov(rd2, rn2)) if rd == rn2 && rn == rd2 => {
+            Some((2, vec![code[at].clone()]))
+        }
+        _ => None,
+    }
 }
 
-fn div(rd: Operand, rn: Operand) -> String {
-    format!("\tudiv {}, {}, {}\n", rd, rd, rn)
+/// cset rd, <cc>
+/// cbz rd, label
+/// branches on the flags of the comparison instead, with b.<!cc> label, or b.<cc> for a cbnz.
+/// Stores and moves that leave rd alone, like the copies into phis, may come before the cbz.
+fn fuse_branch(code: &[Line], at: usize) -> Option<(usize, Vec<Line>)> {
+    let (rd, cond) = match code[at] {
+        Line::Cset(rd, cond) => (rd, cond),
+        _ => return None,
+    };
+    let moves = code[at + 1..]
+        .iter()
+        .take_while(|line| match line {
+            Line::Str(..) => true,
+            Line::Mov(rn, _) => *rn != rd,
+            _ => false,
+        })
+        .count();
+    let end = at + 1 + moves;
+
+    let (cond, label) = match code.get(end)? {
+        Line::Cbz(rn, label) if *rn == rd => (cond.inverse(), label),
+        Line::Cbnz(rn, label) if *rn == rd => (cond, label),
+        _ => return None,
+    };
+    let mut lines = code[at..end].to_vec();
+    lines.push(Line::B(Some(cond), label.clone()));
+    Some((end + 1 - at, lines))
+}
+
+/// cset rd, <cc>
+/// goes when rd is not read after, as a fused branch leaves it
+fn dead_set(code: &[Line], at: usize) -> Option<(usize, Vec<Line>)> {
+    match code[at] {
+        Line::Cset(rd, _) if !live(code, at + 1, rd) => Some((1, vec![])),
+        _ => None,
+    }
 }
 
-fn mov(rd: Operand, src2: Operand) -> String {
-    format!("\tmov {}, {}\n", rd, src2)
+pub struct Aarch64 {
+    pub os: Os,
 }
 
-pub fn eq_arg() -> String {
-    eq(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
-}
+impl Backend for Aarch64 {
+    fn program_prologue(&self) -> String {
+        program_prologue(self.os)
+    }
 
-fn eq(rd: Operand, rn: Operand) -> String {
-    format!("\tcmp {}, {}\n\tcset {}, EQ\n", rd, rn, rd)
-}
+    fn registers(&self) -> Registers {
+        Registers {
+            caller_saved: CALLER_SAVED,
+            callee_saved: ALLOCATABLE.len() - CALLER_SAVED,
+        }
+    }
 
-pub fn neq_arg() -> String {
-    neq(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
+    /// Values in the frame are loaded into x0 and x1 and computed in x0.
+    fn function(&self, function: &Function, frame: &Frame, opt_level: OptLevel) -> String {
+        let mut lines = vec![Line::Directive(self.os.main_func())];
+        lines.extend(memory_allocate(frame));
+        for (n, block) in function.blocks.iter().enumerate() {
+            lines.push(Line::Label(self.os.local_label("block", n)));
+            for inst in &block.insts {
+                lines.push(Line::Comment(function.inst(inst)));
+                lines.extend(self.inst(frame, inst));
+            }
+            lines.push(Line::Comment(block.terminator.to_string()));
+            lines.extend(self.terminator(frame, &block.terminator, n + 1));
+        }
+        if opt_level != OptLevel::O0 {
+            lines = peephole(lines, &RULES);
+        }
+        lines.iter().map(|line| line.to_string()).collect()
+    }
 }
 
-fn neq(rd: Operand, rn: Operand) -> String {
-    format!("\tcmp {}, {}\n\tcset {}, NE\n", rd, rn, rd)
-}
+impl Aarch64 {
+    fn inst(&self, frame: &Frame, inst: &Inst) -> Vec<Line> {
+        match inst {
+            Inst::Const { dst, value } => {
+                let (rd, store) = write(frame, *dst);
+                [load_imm(rd, *value), store].concat()
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                let (load_lhs, rn) = read(frame, *lhs, Register::R0);
+                let (load_rhs, rm) = read(frame, *rhs, Register::R1);
+                let (rd, store) = write(frame, *dst);
+                [load_lhs, load_rhs, binary(*op, rd, rn, rm), store].concat()
+            }
+            Inst::Load { dst, var } => {
+                let (rd, store) = write(frame, *dst);
+                [ldr(rd, frame, frame.var(*var)), store].concat()
+            }
+            Inst::Store { var, src } => {
+                let (load, rn) = read(frame, *src, Register::R0);
+                [load, str(rn, frame, frame.var(*var))].concat()
+            }
+            Inst::Call { dst, name } => {
+                let (rd, store) = write(frame, *dst);
+                [
+                    vec![Line::Bl(self.os.symbol(name))],
+                    mov(rd, Register::R0),
+                    store,
+                ]
+                .concat()
+            }
+            Inst::Copy { dst, src } => {
+                let (load, rn) = read(frame, *src, Register::R0);
+                let copy = match frame.location(*dst) {
+                    Location::Register(n) => mov(Register::Allocated(n), rn),
+                    Location::Stack(offset) => str(rn, frame, offset),
+                };
+                [load, copy].concat()
+            }
+            Inst::Phi { .. } => unreachable!("phis are gone before emission"),
+        }
+    }
 
-pub fn less_arg() -> String {
-    less(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
-}
+    /// `next` is the block laid out right after, which needs no branch.
+    fn terminator(&self, frame: &Frame, terminator: &Terminator, next: BlockId) -> Vec<Line> {
+        match *terminator {
+            Terminator::Return(value) => {
+                let (load, rn) = read(frame, value, Register::R0);
+                [load, mov(Register::R0, rn), program_epilogue(frame)].concat()
+            }
+            Terminator::Jump(block) if block == next => vec![],
+            Terminator::Jump(block) => vec![Line::B(None, self.label(block))],
+            Terminator::Branch {
+                cond,
+                then_block,
+                else_block,
+            } => {
+                let (load, rn) = read(frame, cond, Register::R0);
+                let branches = if then_block == next {
+                    vec![Line::Cbz(rn, self.label(else_block))]
+                } else if else_block == next {
+                    vec![Line::Cbnz(rn, self.label(then_block))]
+                } else {
+                    vec![
+                        Line::Cbz(rn, self.label(else_block)),
+                        Line::B(None, self.label(then_block)),
+                    ]
+                };
+                [load, branches].concat()
+            }
+        }
+    }
 
-fn less(rd: Operand, rn: Operand) -> String {
-    format!("\tcmp {}, {}\n\tcset {}, LT\n", rd, rn, rd)
+    fn label(&self, block: BlockId) -> String {
+        self.os.local_label("block", block)
+    }
 }
 
-pub fn less_or_eq_arg() -> String {
-    less_or_eq(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
+fn program_prologue(os: Os) -> String {
+    os.text_section().to_owned()
+}
+
+/// stp x29, x30, [sp, -16]!
+/// mov x29, sp
+/// sub sp, sp, #bytes
+/// and the callee-saved registers in use into their slots
+fn memory_allocate(frame: &Frame) -> Vec<Line> {
+    let bytes = frame.size;
+    let allocate = if bytes < 4096 {
+        vec![sub(Register::R13, Operand::Num(bytes))]
+    } else {
+        [
+            load_imm(Register::R9, bytes as i64),
+            vec![sub(Register::R13, Operand::Register(Register::R9))],
+        ]
+        .concat()
+    };
+    let mut lines = [
+        vec![
+            Line::Comment("memory allocate".to_owned()),
+            Line::Stp(Register::R29, Register::R30),
+        ],
+        mov(Register::R29, Register::R13),
+        allocate,
+    ]
+    .concat();
+    for (register, offset) in frame.saved() {
+        lines.extend(str(Register::Allocated(register), frame, offset));
+    }
+    lines
 }
 
-fn less_or_eq(rd: Operand, rn: Operand) -> String {
-    format!("\tcmp {}, {}\n\tcset {}, LS\n", rd, rn, rd)
+/// the callee-saved registers back from their slots
+/// mov sp, x29
+/// ldp x29, x30, [sp], 16
+/// ret
+fn program_epilogue(frame: &Frame) -> Vec<Line> {
+    let mut lines = vec![];
+    for (register, offset) in frame.saved() {
+        lines.extend(ldr(Register::Allocated(register), frame, offset));
+    }
+    lines.extend(mov(Register::R13, Register::R29));
+    lines.extend([Line::Ldp(Register::R29, Register::R30), Line::Ret]);
+    lines
 }
 
-pub fn gen_ret() -> String {
-    format!(
-        "; return\n{}{}{}{}",
-        pop(Operand::Register(Register::R0)),
-        mov(
-            Operand::Register(Register::R9),
-            Operand::Register(Register::R8)
-        ),
-        pop(Operand::Register(Register::R8)),
-        ret()
+/// The slot `offset` bytes below x29, addressed from sp since `ldr` and `str` only take
+/// positive scaled offsets. Far slots get their address computed in x9.
+fn local(frame: &Frame, offset: usize) -> (Vec<Line>, Operand) {
+    let offset = frame.size - offset;
+    if offset <= 32760 {
+        return (vec![], Operand::Address(Register::R13, offset));
+    }
+    (
+        [
+            load_imm(Register::R9, offset as i64),
+            vec![Line::Arith(
+                Arith::Add,
+                Register::R9,
+                Register::R13,
+                Operand::Register(Register::R9),
+            )],
+        ]
+        .concat(),
+        Operand::Address(Register::R9, 0),
     )
 }
 
-fn ret() -> String {
-    "\tret\n".to_owned()
-}
-
-fn lbegin(n: usize) -> String {
-    format!("Lbegin{:0width$}:\n", n, width = 3)
-}
-
-fn lend(n: usize) -> String {
-    format!("Lend{:0width$}:\n", n, width = 3)
-}
-
-fn lelse(n: usize) -> String {
-    format!("Lelse{:0width$}:\n", n, width = 3)
+/// The register holding `vreg`, loaded into `scratch` when it lives in the frame.
+fn read(frame: &Frame, vreg: VReg, scratch: Register) -> (Vec<Line>, Register) {
+    match frame.location(vreg) {
+        Location::Register(n) => (vec![], Register::Allocated(n)),
+        Location::Stack(offset) => (ldr(scratch, frame, offset), scratch),
+    }
 }
 
-fn b_lbegin(n: usize) -> String {
-    format!("\tb Lbegin{:0width$}\n", n, width = 3)
+/// The register to compute `vreg` in, and the store after when it lives in the frame.
+fn write(frame: &Frame, vreg: VReg) -> (Register, Vec<Line>) {
+    match frame.location(vreg) {
+        Location::Register(n) => (Register::Allocated(n), vec![]),
+        Location::Stack(offset) => (Register::R0, str(Register::R0, frame, offset)),
+    }
 }
 
-fn b_lend(n: usize) -> String {
-    format!("\tb Lend{:0width$}\n", n, width = 3)
+fn ldr(rd: Register, frame: &Frame, offset: usize) -> Vec<Line> {
+    let (address, rn) = local(frame, offset);
+    [address, vec![Line::Ldr(rd, rn)]].concat()
 }
 
-fn b_eq_lend(n: usize) -> String {
-    format!("\tb.EQ Lend{:0width$}\n", n, width = 3)
+fn str(rd: Register, frame: &Frame, offset: usize) -> Vec<Line> {
+    let (address, rn) = local(frame, offset);
+    [address, vec![Line::Str(rd, rn)]].concat()
 }
 
-fn b_eq_lelse(n: usize) -> String {
-    format!("\tb.EQ Lelse{:0width$}\n", n, width = 3)
+/// `movz` and `movk` build any 64-bit value 16 bits at a time.
+fn load_imm(rd: Register, n: i64) -> Vec<Line> {
+    let n = n as u64;
+    let mut lines = vec![Line::Movz(rd, n & 0xffff)];
+    for shift in [16, 32, 48] {
+        let chunk = (n >> shift) & 0xffff;
+        if chunk != 0 {
+            lines.push(Line::Movk(rd, chunk, shift));
+        }
+    }
+    lines
 }
 
-pub fn gen_if(expr: &str, stmt: &str, n: usize) -> String {
-    format!(
-        "{}\tcmp {}, {}\n{}{}{}",
-        expr,
-        Operand::Register(Register::R0),
-        Operand::Num(0),
-        b_eq_lend(n),
-        stmt,
-        lend(n)
-    )
+/// rd <- rn op rm
+fn binary(op: BinOp, rd: Register, rn: Register, rm: Register) -> Vec<Line> {
+    let arith = match op {
+        BinOp::Add => Arith::Add,
+        BinOp::Sub => Arith::Sub,
+        BinOp::Mul => Arith::Mul,
+        BinOp::Div => Arith::Sdiv,
+        BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le => {
+            return set(Cond::new(op).expect("a comparison"), rd, rn, rm)
+        }
+    };
+    vec![Line::Arith(arith, rd, rn, Operand::Register(rm))]
 }
 
-pub fn gen_if_else(expr: &str, stmt: &str, stmt_else: &str, n: usize) -> String {
-    format!(
-        "{}\tcmp {}, {}\n{}{}{}{}{}{}",
-        expr,
-        Operand::Register(Register::R0),
-        Operand::Num(0),
-        b_eq_lelse(n),
-        stmt,
-        b_lend(n),
-        lelse(n),
-        stmt_else,
-        lend(n),
-    )
+/// cmp rn, rm
+/// cset rd, <cc>
+fn set(cond: Cond, rd: Register, rn: Register, rm: Register) -> Vec<Line> {
+    vec![Line::Cmp(rn, rm), Line::Cset(rd, cond)]
 }
 
-pub fn gen_while(expr: &str, stmt: &str, n: usize) -> String {
-    format!(
-        "{}{}\tcmp {}, {}\n{}{}{}{}",
-        lbegin(n),
-        expr,
-        Operand::Register(Register::R0),
-        Operand::Num(0),
-        b_eq_lend(n),
-        stmt,
-        b_lbegin(n),
-        lend(n),
-    )
+fn sub(rd: Register, rn: Operand) -> Line {
+    Line::Arith(Arith::Sub, rd, rd, rn) // rd <- rd - rn
 }
 
-pub fn gen_for(init_expr: &str, cond_expr: &str, loop_expr: &str, stmt: &str, n: usize) -> String {
-    format!(
-        "{}{}{}\tcmp {}, {}\n{}{}{}{}{}",
-        init_expr,
-        lbegin(n),
-        cond_expr,
-        Operand::Register(Register::R0),
-        Operand::Num(0),
-        b_eq_lend(n),
-        stmt,
-        loop_expr,
-        b_lbegin(n),
-        lend(n)
-    )
-}
+/// Nothing when rd already is rn.
+fn mov(rd: Register, rn: Register) -> Vec<Line> {
+    if rd == rn {
+        return vec![];
+    }
+    vec![Line::Mov(rd, rn)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passes::Pipeline;
+    use crate::{lexer::lexer, parser::parser, variable::variable_analysis};
+
+    fn function(src: &str) -> String {
+        let (tokens, ident_count) = variable_analysis(lexer(src).unwrap()).unwrap();
+        let pipeline = Pipeline::new(OptLevel::O1.passes());
+        let function = crate::generator::function(&parser(tokens).unwrap(), ident_count, &pipeline)
+            .unwrap()
+            .0;
+        let backend = Aarch64 { os: Os::Linux };
+        let frame = Frame::new(&function, backend.registers());
+        backend.function(&function, &frame, OptLevel::O1)
+    }
 
-pub fn gen_func(name: &str) -> String {
-    format!(
-        "; func: {}\n{}{}\tbl _{}\n{}",
-        name,
-        stp(
-            Operand::Register(Register::R29),
-            Operand::Register(Register::R30)
-        ),
-        mov(
-            Operand::Register(Register::R29),
-            Operand::Register(Register::R13)
-        ),
-        name,
-        ldp(
-            Operand::Register(Register::R29),
-            Operand::Register(Register::R30)
-        )
-    )
+    #[test]
+    fn peephole_test() {
+        let (x0, x10) = (Register::R0, Register::Allocated(0));
+        let slot = Operand::Address(Register::R13, 8);
+        let lines = vec![
+            Line::Mov(x10, x10),
+            Line::Str(x0, slot),
+            Line::Comment("reload".to_owned()),
+            Line::Ldr(x0, slot),
+            Line::Ret,
+        ];
+        assert_eq!(
+            vec![
+                Line::Str(x0, slot),
+                Line::Comment("reload".to_owned()),
+                Line::Ret
+            ],
+            peephole(lines, &RULES)
+        );
+
+        let asm = function("a = x(); b = y(); if (a < b) 1;");
+        assert!(asm.contains("\tcmp x19, x10\n"));
+        assert!(asm.contains("\tb.ge .Lblock002\n"));
+        assert!(!asm.contains("cset"));
+        // and in a loop, where the copies into the phis come before the branch
+        let asm =
+            function("s = 0; n = x(); for (i = 0; i < n; i = i + 1) if (i < 3) s = s + i; s;");
+        assert!(asm.contains("\tb.ge .Lblock"));
+        assert!(!asm.contains("cset"));
+    }
 }
@@ -0,0 +1,338 @@
+use super::{Backend, Frame, Location, Os};
+use crate::generator::OptLevel;
+use crate::ir::{BinOp, BlockId, Function, Inst, Terminator, VReg};
+use crate::regalloc::Registers;
+use core::fmt;
+
+#[derive(PartialEq, Clone, Copy)]
+enum Register {
+    /// a0
+    R0,
+    /// a1
+    R1,
+    /// t0
+    T0,
+    /// s0, the frame pointer
+    Fp,
+    /// sp
+    Sp,
+    /// ra
+    Ra,
+    /// one of `ALLOCATABLE`
+    Allocated(usize),
+}
+
+/// The registers the allocator hands out, the caller-saved ones first.
+const ALLOCATABLE: [&str; 23] = [
+    "t1", "t2", "t3", "t4", "t5", "t6", "a2", "a3", "a4", "a5", "a6", "a7", "s1", "s2", "s3", "s4",
+    "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+];
+const CALLER_SAVED: usize = 12;
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Register::R0 => "a0",
+            Register::R1 => "a1",
+            Register::T0 => "t0",
+            Register::Fp => "s0",
+            Register::Sp => "sp",
+            Register::Ra => "ra",
+            Register::Allocated(n) => ALLOCATABLE[*n],
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub struct Riscv64 {
+    pub os: Os,
+}
+
+impl Backend for Riscv64 {
+    fn program_prologue(&self) -> String {
+        program_prologue(self.os)
+    }
+
+    fn registers(&self) -> Registers {
+        Registers {
+            caller_saved: CALLER_SAVED,
+            callee_saved: ALLOCATABLE.len() - CALLER_SAVED,
+        }
+    }
+
+    /// Values in the frame are loaded into a0 and a1 and computed in a0.
+    fn function(&self, function: &Function, frame: &Frame, _: OptLevel) -> String {
+        let mut asm = format!("{}{}", self.os.main_func(), memory_allocate(frame));
+        for (n, block) in function.blocks.iter().enumerate() {
+            asm.push_str(&format!("{}:\n", self.os.local_label("block", n)));
+            for inst in &block.insts {
+                asm.push_str(&format!("# {}\n", function.inst(inst)));
+                asm.push_str(&self.inst(frame, inst));
+            }
+            asm.push_str(&format!("# {}\n", block.terminator));
+            asm.push_str(&self.terminator(frame, &block.terminator, n + 1));
+        }
+        asm
+    }
+}
+
+impl Riscv64 {
+    fn inst(&self, frame: &Frame, inst: &Inst) -> String {
+        match inst {
+            Inst::Const { dst, value } => {
+                let (rd, store) = write(frame, *dst);
+                format!("{}{}", li(rd, *value), store)
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                let (load_lhs, rs1) = read(frame, *lhs, Register::R0);
+                let (load_rhs, rs2) = read(frame, *rhs, Register::R1);
+                let (rd, store) = write(frame, *dst);
+                format!(
+                    "{}{}{}{}",
+                    load_lhs,
+                    load_rhs,
+                    binary(*op, rd, rs1, rs2),
+                    store
+                )
+            }
+            Inst::Load { dst, var } => {
+                let (rd, store) = write(frame, *dst);
+                format!("{}{}", ld(rd, frame.var(*var)), store)
+            }
+            Inst::Store { var, src } => {
+                let (load, rs) = read(frame, *src, Register::R0);
+                format!("{}{}", load, sd(rs, frame.var(*var)))
+            }
+            Inst::Call { dst, name } => {
+                let (rd, store) = write(frame, *dst);
+                format!(
+                    "\tcall {}\n{}{}",
+                    self.os.symbol(name),
+                    mv(rd, Register::R0),
+                    store
+                )
+            }
+            Inst::Copy { dst, src } => {
+                let (load, rs) = read(frame, *src, Register::R0);
+                let copy = match frame.location(*dst) {
+                    Location::Register(n) => mv(Register::Allocated(n), rs),
+                    Location::Stack(offset) => sd(rs, offset),
+                };
+                format!("{}{}", load, copy)
+            }
+            Inst::Phi { .. } => unreachable!("phis are gone before emission"),
+        }
+    }
+
+    /// `next` is the block laid out right after, which needs no jump.
+    fn terminator(&self, frame: &Frame, terminator: &Terminator, next: BlockId) -> String {
+        match *terminator {
+            Terminator::Return(value) => {
+                let (load, rs) = read(frame, value, Register::R0);
+                format!("{}{}{}", load, mv(Register::R0, rs), leave(frame))
+            }
+            Terminator::Jump(block) if block == next => String::new(),
+            Terminator::Jump(block) => self.jump("j", block),
+            Terminator::Branch {
+                cond,
+                then_block,
+                else_block,
+            } => {
+                let (load, rs) = read(frame, cond, Register::R0);
+                let (beqz, bnez) = (format!("beqz {},", rs), format!("bnez {},", rs));
+                let jumps = if then_block == next {
+                    self.jump(&beqz, else_block)
+                } else if else_block == next {
+                    self.jump(&bnez, then_block)
+                } else {
+                    format!(
+                        "{}{}",
+                        self.jump(&beqz, else_block),
+                        self.jump("j", then_block)
+                    )
+                };
+                format!("{}{}", load, jumps)
+            }
+        }
+    }
+
+    fn jump(&self, mnemonic: &str, block: BlockId) -> String {
+        format!("\t{} {}\n", mnemonic, self.os.local_label("block", block))
+    }
+}
+
+fn program_prologue(os: Os) -> String {
+    os.text_section().to_owned()
+}
+
+/// addi sp, sp, -16
+/// sd ra, 8(sp)
+/// sd s0, 0(sp)
+/// mv s0, sp
+/// sp <- sp - bytes
+/// and the callee-saved registers in use into their slots
+fn memory_allocate(frame: &Frame) -> String {
+    let mut asm = format!(
+        "# memory allocate\n{}\tsd {}, 8({})\n\tsd {}, 0({})\n{}{}",
+        addi(Register::Sp, Register::Sp, -16),
+        Register::Ra,
+        Register::Sp,
+        Register::Fp,
+        Register::Sp,
+        mv(Register::Fp, Register::Sp),
+        sub_imm(Register::Sp, Register::Sp, frame.size)
+    );
+    for (register, offset) in frame.saved() {
+        asm.push_str(&sd(Register::Allocated(register), offset));
+    }
+    asm
+}
+
+/// the callee-saved registers back from their slots
+/// mv sp, s0
+/// ld s0, 0(sp)
+/// ld ra, 8(sp)
+/// addi sp, sp, 16
+/// ret
+fn leave(frame: &Frame) -> String {
+    let mut asm = String::new();
+    for (register, offset) in frame.saved() {
+        asm.push_str(&ld(Register::Allocated(register), offset));
+    }
+    format!(
+        "{}{}\tld {}, 0({})\n\tld {}, 8({})\n{}{}",
+        asm,
+        mv(Register::Sp, Register::Fp),
+        Register::Fp,
+        Register::Sp,
+        Register::Ra,
+        Register::Sp,
+        addi(Register::Sp, Register::Sp, 16),
+        ret()
+    )
+}
+
+/// The slot `offset` bytes below s0. Loads and stores take a 12-bit offset, so farther slots
+/// get their address computed in t0.
+fn local(offset: usize) -> (String, String) {
+    if offset <= 2048 {
+        return (String::new(), address(Register::Fp, -(offset as i64)));
+    }
+    (
+        sub_imm(Register::T0, Register::Fp, offset),
+        address(Register::T0, 0),
+    )
+}
+
+/// `offset(r)`
+fn address(r: Register, offset: i64) -> String {
+    format!("{}({})", offset, r)
+}
+
+/// The register holding `vreg`, loaded into `scratch` when it lives in the frame.
+fn read(frame: &Frame, vreg: VReg, scratch: Register) -> (String, Register) {
+    match frame.location(vreg) {
+        Location::Register(n) => (String::new(), Register::Allocated(n)),
+        Location::Stack(offset) => (ld(scratch, offset), scratch),
+    }
+}
+
+/// The register to compute `vreg` in, and the store after when it lives in the frame.
+fn write(frame: &Frame, vreg: VReg) -> (Register, String) {
+    match frame.location(vreg) {
+        Location::Register(n) => (Register::Allocated(n), String::new()),
+        Location::Stack(offset) => (Register::R0, sd(Register::R0, offset)),
+    }
+}
+
+fn ld(rd: Register, offset: usize) -> String {
+    let (prepare, rs) = local(offset);
+    format!("{}\tld {}, {}\n", prepare, rd, rs)
+}
+
+fn sd(rs: Register, offset: usize) -> String {
+    let (prepare, rd) = local(offset);
+    format!("{}\tsd {}, {}\n", prepare, rs, rd)
+}
+
+fn li(rd: Register, n: i64) -> String {
+    format!("\tli {}, {}\n", rd, n)
+}
+
+/// Nothing when rd already is rs.
+fn mv(rd: Register, rs: Register) -> String {
+    if rd == rs {
+        return String::new();
+    }
+    format!("\tmv {}, {}\n", rd, rs)
+}
+
+fn addi(rd: Register, rs: Register, imm: i16) -> String {
+    format!("\taddi {}, {}, {}\n", rd, rs, imm)
+}
+
+/// `addi` only takes a 12-bit immediate, so frame sizes and offsets go through t0.
+fn sub_imm(rd: Register, rs: Register, n: usize) -> String {
+    format!(
+        "{}\tsub {}, {}, {}\n",
+        li(Register::T0, n as i64),
+        rd,
+        rs,
+        Register::T0
+    )
+}
+
+/// rd <- rs1 op rs2
+fn binary(op: BinOp, rd: Register, rs1: Register, rs2: Register) -> String {
+    match op {
+        BinOp::Add => format!("\tadd {}, {}, {}\n", rd, rs1, rs2),
+        BinOp::Sub => format!("\tsub {}, {}, {}\n", rd, rs1, rs2),
+        BinOp::Mul => format!("\tmul {}, {}, {}\n", rd, rs1, rs2),
+        BinOp::Div => format!("\tdiv {}, {}, {}\n", rd, rs1, rs2),
+        BinOp::Eq => format!("\tsub {}, {}, {}\n\tseqz {}, {}\n", rd, rs1, rs2, rd, rd),
+        BinOp::Ne => format!("\tsub {}, {}, {}\n\tsnez {}, {}\n", rd, rs1, rs2, rd, rd),
+        BinOp::Lt => format!("\tslt {}, {}, {}\n", rd, rs1, rs2),
+        // rs1 <= rs2 is !(rs2 < rs1)
+        BinOp::Le => format!("\tslt {}, {}, {}\n\txori {}, {}, 1\n", rd, rs2, rs1, rd, rd),
+    }
+}
+
+fn ret() -> String {
+    "\tret\n".to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_test() {
+        assert_eq!("\tld a1, -16(s0)\n", ld(Register::R1, 16));
+        assert_eq!(
+            "\tli t0, 4096\n\tsub t0, s0, t0\n\tsd a0, 0(t0)\n",
+            sd(Register::R0, 4096)
+        );
+    }
+
+    #[test]
+    fn compare_test() {
+        let (a0, a1) = (Register::R0, Register::R1);
+        assert_eq!(
+            "\tsub a0, a0, a1\n\tseqz a0, a0\n",
+            binary(BinOp::Eq, a0, a0, a1)
+        );
+        assert_eq!(
+            "\tslt a0, a1, a0\n\txori a0, a0, 1\n",
+            binary(BinOp::Le, a0, a0, a1)
+        );
+        assert_eq!(
+            "\tslt t1, s1, a0\n\txori t1, t1, 1\n",
+            binary(
+                BinOp::Le,
+                Register::Allocated(0),
+                a0,
+                Register::Allocated(12)
+            )
+        );
+    }
+}
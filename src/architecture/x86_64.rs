@@ -1,365 +1,854 @@
-use crate::token::Int;
+use super::{Backend, Cond, Frame, Location, Os};
+use crate::generator::OptLevel;
+use crate::ir::{BinOp, BlockId, Function, Inst, Terminator, VReg};
+use crate::peephole::{self, live, peephole, Flow, Rule};
+use crate::regalloc::Registers;
 use core::fmt;
 
-pub enum Register {
+/// The assembler syntax the x86_64 backend writes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Syntax {
+    /// `mov rax, 1` under `.intel_syntax noprefix`
+    Intel,
+    /// `movq $1, %rax`, the GNU assembler default
+    Att,
+}
+
+impl Syntax {
+    pub fn parse(s: &str) -> Result<Syntax, &'static str> {
+        match s {
+            "intel" => Ok(Syntax::Intel),
+            "att" => Ok(Syntax::Att),
+            _ => Err("unsupported assembler syntax"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Register {
     /// rax
     R0,
+    /// al, the lowest byte of rax
+    R0B,
     /// rdi
     R1,
     /// rbp
     R5,
     /// rsp
     R6,
+    /// one of `ALLOCATABLE`
+    Allocated(usize),
 }
 
-pub enum Operand {
-    Num(Int),
+/// The registers the allocator hands out, the caller-saved ones first. rax, rdi and rdx, which
+/// `idiv` clobbers, stay free for the instructions themselves.
+const ALLOCATABLE: [&str; 11] = [
+    "rcx", "rsi", "r8", "r9", "r10", "r11", "rbx", "r12", "r13", "r14", "r15",
+];
+const CALLER_SAVED: usize = 6;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Operand {
+    Num(i64),
     Register(Register),
-    Address(Register),
+    /// `[register + displacement]`
+    Address(Register, i64),
 }
 
+/// The alternate form (`{:#}`) is the AT&T spelling.
 impl fmt::Display for Register {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let name = match self {
             Register::R0 => "rax",
+            Register::R0B => "al",
             Register::R1 => "rdi",
             Register::R5 => "rbp",
             Register::R6 => "rsp",
+            Register::Allocated(n) => ALLOCATABLE[*n],
         };
-        write!(f, "{}", name)
+        if f.alternate() {
+            write!(f, "%{}", name)
+        } else {
+            write!(f, "{}", name)
+        }
     }
 }
 
+/// The alternate form (`{:#}`) is the AT&T spelling.
 impl fmt::Display for Operand {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let name: String = match self {
-            Operand::Register(r) => r.to_string(),
-            Operand::Address(r) => format!("[{}]", r),
-            Operand::Num(n) => n.to_string(),
+        let name: String = match (self, f.alternate()) {
+            (Operand::Register(r), false) => r.to_string(),
+            (Operand::Register(r), true) => format!("{:#}", r),
+            (Operand::Address(r, 0), false) => format!("[{}]", r),
+            (Operand::Address(r, 0), true) => format!("({:#})", r),
+            (Operand::Address(r, d), false) if *d < 0 => format!("[{}-{}]", r, -d),
+            (Operand::Address(r, d), false) => format!("[{}+{}]", r, d),
+            (Operand::Address(r, d), true) => format!("{}({:#})", d, r),
+            (Operand::Num(n), false) => n.to_string(),
+            (Operand::Num(n), true) => format!("${}", n),
         };
         write!(f, "{}", name)
     }
 }
 
-pub fn program_prologue() -> String {
-    ".intel_syntax noprefix\n".to_owned()
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Mnemonic {
+    Mov,
+    Movabs,
+    Movzb,
+    Push,
+    Pop,
+    Add,
+    Sub,
+    Imul,
+    Idiv,
+    Cmp,
+}
+
+impl Mnemonic {
+    fn name(&self) -> &'static str {
+        match self {
+            Mnemonic::Mov => "mov",
+            Mnemonic::Movabs => "movabs",
+            Mnemonic::Movzb => "movzb",
+            Mnemonic::Push => "push",
+            Mnemonic::Pop => "pop",
+            Mnemonic::Add => "add",
+            Mnemonic::Sub => "sub",
+            Mnemonic::Imul => "imul",
+            Mnemonic::Idiv => "idiv",
+            Mnemonic::Cmp => "cmp",
+        }
+    }
 }
 
-pub fn main_func() -> String {
-    ".globl main\nmain:\n".to_owned()
+/// A line of the assembly, written out in either syntax once the peephole pass is done.
+#[derive(Debug, PartialEq, Clone)]
+enum Line {
+    /// written as is, like `.globl main`
+    Directive(String),
+    Label(String),
+    Comment(String),
+    /// the destination first, as in Intel syntax
+    Op(Mnemonic, Vec<Operand>),
+    /// set<cc> al
+    Set(Cond),
+    /// `jmp` without a condition, `j<cc>` with one
+    Jump(Option<Cond>, String),
+    Call(String),
+    Cqo,
+    Ret,
+}
+
+/// The `cc` of `set<cc>` and `j<cc>`, for a signed comparison.
+fn cc(cond: Cond) -> &'static str {
+    match cond {
+        Cond::Eq => "e",
+        Cond::Ne => "ne",
+        Cond::Lt => "l",
+        Cond::Le => "le",
+        Cond::Gt => "g",
+        Cond::Ge => "ge",
+    }
 }
 
-/// push rbp
-/// mov rbp, rsp
-/// sub rsp, #bytes
-pub fn memory_allocate(bytes: usize) -> String {
-    format!(
-        "; memory allocate\n{}{}{}",
-        push(Operand::Register(Register::R5)),
-        mov(
-            Operand::Register(Register::R5),
-            Operand::Register(Register::R6)
-        ),
-        sub(Operand::Register(Register::R6), Operand::Num(bytes))
-    )
+impl Line {
+    fn render(&self, syntax: Syntax) -> String {
+        match self {
+            Line::Directive(text) => text.clone(),
+            Line::Label(label) => format!("{}:\n", label),
+            Line::Comment(text) => format!("# {}\n", text),
+            Line::Op(mnemonic, operands) => inst(syntax, mnemonic.name(), "q", operands),
+            Line::Set(cond) => inst(
+                syntax,
+                &format!("set{}", cc(*cond)),
+                "",
+                &[Operand::Register(Register::R0B)],
+            ),
+            Line::Jump(None, label) => format!("\tjmp {}\n", label),
+            Line::Jump(Some(cond), label) => format!("\tj{} {}\n", cc(*cond), label),
+            Line::Call(callee) => format!("\tcall {}\n", callee),
+            Line::Cqo => match syntax {
+                Syntax::Intel => "\tcqo\n".to_owned(),
+                Syntax::Att => "\tcqto\n".to_owned(),
+            },
+            Line::Ret => "\tret\n".to_owned(),
+        }
+    }
 }
 
-pub fn stmt_epilogue() -> String {
-    format!(
-        "; statement epilogue\n{}",
-        pop(Operand::Register(Register::R0))
-    )
+fn render(syntax: Syntax, lines: &[Line]) -> String {
+    lines.iter().map(|line| line.render(syntax)).collect()
 }
 
-/// mov rsp, rbp
-/// pop rbp
-/// ret
-pub fn program_epilogue() -> String {
-    format!(
-        "; program epilogue\n{}{}{}",
-        mov(
-            Operand::Register(Register::R6),
-            Operand::Register(Register::R5)
-        ),
-        pop(Operand::Register(Register::R5)),
-        ret()
-    )
+/// al is the lowest byte of rax, so the pass asks about full registers only.
+fn full(register: Register) -> Register {
+    match register {
+        Register::R0B => Register::R0,
+        _ => register,
+    }
 }
 
-/// mov rax, rbp
-/// sub rax, offset
-/// push rax
-pub fn gen_val(name: &str, offset: usize) -> String {
-    format!(
-        "; value: {}\n{}{}{}",
-        name,
-        mov(
-            Operand::Register(Register::R0),
-            Operand::Register(Register::R5)
-        ),
-        sub(Operand::Register(Register::R0), Operand::Num(offset)),
-        push(Operand::Register(Register::R0))
-    )
-}
-
-/// pop r0
-/// mov r0, [r0]
-/// push r0
-pub fn pop_val() -> String {
-    format!(
-        "; pop value\n{}{}{}",
-        pop(Operand::Register(Register::R0)),
-        mov(
-            Operand::Register(Register::R0),
-            Operand::Address(Register::R0)
-        ),
-        push(Operand::Register(Register::R0))
-    )
-}
-
-/// pop r1
-/// pop r0
-/// mov [r0], r1
-/// push r1
-pub fn pop_lvar() -> String {
-    format!(
-        ";pop lvar\n{}{}{}{}",
-        pop(Operand::Register(Register::R1)),
-        pop(Operand::Register(Register::R0)),
-        mov(
-            Operand::Address(Register::R0),
-            Operand::Register(Register::R1)
-        ),
-        push(Operand::Register(Register::R1))
-    )
+/// Whether reading `operand` reads `register`, as a value or as an address.
+fn uses(operand: Operand, register: Register) -> bool {
+    match operand {
+        Operand::Register(r) | Operand::Address(r, _) => full(r) == register,
+        Operand::Num(_) => false,
+    }
 }
 
-/// pop r1
-/// pop r0
-pub fn pop_arg() -> String {
-    format!(
-        "; pop arg\n{}{}",
-        pop(Operand::Register(Register::R1)),
-        pop(Operand::Register(Register::R0))
-    )
+/// Whether writing `operand` reads `register` to find the address.
+fn addresses(operand: Operand, register: Register) -> bool {
+    match operand {
+        Operand::Address(r, _) => r == register,
+        _ => false,
+    }
 }
 
-/// push rd
-pub fn push(rd: Operand) -> String {
-    format!("\tpush {}\n", rd)
-}
+impl peephole::Line for Line {
+    type Register = Register;
 
-/// pop rd
-fn pop(rd: Operand) -> String {
-    format!("\tpop {}\n", rd)
-}
+    fn is_comment(&self) -> bool {
+        matches!(self, Line::Comment(_))
+    }
 
-pub fn add_arg() -> String {
-    add(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
-}
+    fn label(&self) -> Option<&str> {
+        match self {
+            Line::Label(label) => Some(label),
+            _ => None,
+        }
+    }
 
-fn add(rd: Operand, rn: Operand) -> String {
-    format!("\tadd {}, {}\n", rd, rn) // rd <- rd + rn
-}
+    fn flow(&self) -> Flow<'_> {
+        match self {
+            Line::Jump(None, label) => Flow::Jump(label),
+            Line::Jump(Some(_), label) => Flow::Branch(label),
+            Line::Ret => Flow::Return,
+            _ => Flow::Next,
+        }
+    }
 
-pub fn sub_arg() -> String {
-    sub(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
-}
+    /// A directive could do anything, so it reads every register. `ret` reads the result and
+    /// what the caller expects preserved.
+    fn reads(&self, register: Register) -> bool {
+        match self {
+            Line::Op(Mnemonic::Mov | Mnemonic::Movabs | Mnemonic::Movzb, operands) => {
+                addresses(operands[0], register) || uses(operands[1], register)
+            }
+            Line::Op(Mnemonic::Pop, operands) => {
+                addresses(operands[0], register) || register == Register::R6
+            }
+            Line::Op(Mnemonic::Push, operands) => {
+                uses(operands[0], register) || register == Register::R6
+            }
+            Line::Op(Mnemonic::Idiv, operands) => {
+                uses(operands[0], register) || register == Register::R0
+            }
+            Line::Op(_, operands) => operands.iter().any(|operand| uses(*operand, register)),
+            Line::Cqo => register == Register::R0,
+            Line::Call(_) => register == Register::R6,
+            Line::Directive(_) => true,
+            Line::Ret => match register {
+                Register::Allocated(n) => n >= CALLER_SAVED,
+                _ => register != Register::R1,
+            },
+            Line::Label(_) | Line::Comment(_) | Line::Set(_) | Line::Jump(..) => false,
+        }
+    }
 
-fn sub(rd: Operand, rn: Operand) -> String {
-    format!("\tsub {}, {}\n", rd, rn) // rd <- rd - rn
+    /// `set<cc> al` writes all of rax as far as the pass is concerned, since only the `movzb` after
+    /// it reads al and nothing reads the rest.
+    fn writes(&self, register: Register) -> bool {
+        match self {
+            Line::Set(_) => register == Register::R0,
+            Line::Op(Mnemonic::Push | Mnemonic::Cmp, _) => false,
+            Line::Op(Mnemonic::Idiv, _) => register == Register::R0,
+            Line::Op(_, operands) => operands[0] == Operand::Register(register),
+            // a call clobbers the caller-saved registers
+            Line::Call(_) => match register {
+                Register::R0 | Register::R1 => true,
+                Register::Allocated(n) => n < CALLER_SAVED,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
 }
 
-pub fn mul_arg() -> String {
-    mul(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
-}
+/// The rules of the peephole pass, tried in order at every line.
+const RULES: [Rule<Line>; 4] = [self_move, reload, fuse_branch, dead_set];
 
-fn mul(rd: Operand, rn: Operand) -> String {
-    format!("\timul {}, {}\n", rd, rn) // rd <- rd x rn
+/// mov rd, rd
+/// does nothing
+fn self_move(code: &[Line], at: usize) -> Option<(usize, Vec<Line>)> {
+    match &code[at] {
+        Line::Op(Mnemonic::Mov, operands) if operands[0] == operands[1] => Some((1, vec![])),
+        _ => None,
+    }
 }
 
-pub fn div_arg() -> String {
-    div(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
+/// mov rd, rn
+/// mov rn, rd
+/// needs no second move
+fn reload(code: &[Line], at: usize) -> Option<(usize, Vec<Line>)> {
+    match (&code[at], code.get(at + 1)?) {
+        (Line::Op(Mnemonic::Mov, first), Line::Op(Mnemonic::Mov, second))
+            if first[0] == second[1] && first[1] == second[0] =>
+        {
+            let (rd, rn) = (first[0], first[1]);
+            let overlap = |operand, other| match other {
+                Operand::Register(r) => addresses(operand, r),
+                _ => false,
+            };
+            if overlap(rd, rn) || overlap(rn, rd) {
+                return None;
+            }
+            Some((2, vec![code[at].clone()]))
+        }
+        _ => None,
+    }
 }
 
-fn div(_: Operand, rn: Operand) -> String {
-    format!("\tcqo\n\tidiv {}\n", rn)
+/// set<cc> al
+/// movzb rd, al
+/// cmp rd, 0
+/// je label
+/// jumps on the flags of the comparison instead, with j<!cc> label, or j<cc> for a jne. Moves
+/// that leave rd alone, like stores of rd and the copies into phis, may come before the cmp.
+fn fuse_branch(code: &[Line], at: usize) -> Option<(usize, Vec<Line>)> {
+    let (cond, rd) = match (&code[at], code.get(at + 1)?) {
+        (Line::Set(cond), Line::Op(Mnemonic::Movzb, operands)) => (*cond, operands[0]),
+        _ => return None,
+    };
+    let moves = code[at + 2..]
+        .iter()
+        .take_while(|line| match line {
+            Line::Op(Mnemonic::Mov, operands) => operands[0] != rd,
+            _ => false,
+        })
+        .count();
+    let end = at + 2 + moves;
+
+    let (cond, label) = match (code.get(end)?, code.get(end + 1)?) {
+        (Line::Op(Mnemonic::Cmp, operands), Line::Jump(Some(jump), label))
+            if operands[..] == [rd, Operand::Num(0)] =>
+        {
+            match jump {
+                Cond::Eq => (cond.inverse(), label),
+                Cond::Ne => (cond, label),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let mut lines = code[at..end].to_vec();
+    lines.push(Line::Jump(Some(cond), label.clone()));
+    Some((end + 2 - at, lines))
+}
+
+/// set<cc> al
+/// movzb rd, al
+/// goes when neither rd nor rax is read after, as a fused branch leaves it
+fn dead_set(code: &[Line], at: usize) -> Option<(usize, Vec<Line>)> {
+    match (&code[at], code.get(at + 1)?) {
+        (Line::Set(_), Line::Op(Mnemonic::Movzb, operands)) => match operands[0] {
+            Operand::Register(rd)
+                if !live(code, at + 2, rd) && !live(code, at + 2, Register::R0) =>
+            {
+                Some((2, vec![]))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
 }
 
-fn mov(rd: Operand, src2: Operand) -> String {
-    format!("\tmov {}, {}\n", rd, src2)
+pub struct X86_64 {
+    pub syntax: Syntax,
+    pub os: Os,
 }
 
-pub fn eq_arg() -> String {
-    eq(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
-}
+impl Backend for X86_64 {
+    fn program_prologue(&self) -> String {
+        program_prologue(self.syntax, self.os)
+    }
 
-fn eq(rd: Operand, rn: Operand) -> String {
-    format!("\tcmp {}, {}\n\tsete al\n\tmovzb {}, al\n", rd, rn, rd)
-}
+    fn registers(&self) -> Registers {
+        Registers {
+            caller_saved: CALLER_SAVED,
+            callee_saved: ALLOCATABLE.len() - CALLER_SAVED,
+        }
+    }
 
-pub fn neq_arg() -> String {
-    neq(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
+    /// Values in the frame go through rax and rdi.
+    fn function(&self, function: &Function, frame: &Frame, opt_level: OptLevel) -> String {
+        let mut lines = vec![Line::Directive(self.os.main_func())];
+        lines.extend(memory_allocate(frame));
+        for (n, block) in function.blocks.iter().enumerate() {
+            lines.push(Line::Label(self.os.local_label("block", n)));
+            for inst in &block.insts {
+                lines.push(Line::Comment(function.inst(inst)));
+                lines.extend(self.inst(frame, inst));
+            }
+            lines.push(Line::Comment(block.terminator.to_string()));
+            lines.extend(self.terminator(frame, &block.terminator, n + 1));
+        }
+        if opt_level != OptLevel::O0 {
+            lines = peephole(lines, &RULES);
+        }
+        render(self.syntax, &lines)
+    }
 }
 
-fn neq(rd: Operand, rn: Operand) -> String {
-    format!("\tcmp {}, {}\n\tsetne al\n\tmovzb {}, al\n", rd, rn, rd)
-}
+impl X86_64 {
+    fn inst(&self, frame: &Frame, inst: &Inst) -> Vec<Line> {
+        let operand = |vreg| operand(frame, vreg);
+        match inst {
+            Inst::Const { dst, value } => match operand(*dst) {
+                Operand::Register(rd) => vec![mov_imm(rd, *value)],
+                slot => vec![
+                    mov_imm(Register::R0, *value),
+                    mov(slot, Operand::Register(Register::R0)),
+                ],
+            },
+            Inst::Binary { op, dst, lhs, rhs } => {
+                binary(*op, operand(*dst), operand(*lhs), operand(*rhs))
+            }
+            Inst::Load { dst, var } => copy(operand(*dst), local(frame.var(*var))),
+            Inst::Store { var, src } => copy(local(frame.var(*var)), operand(*src)),
+            Inst::Call { dst, name } => [
+                vec![gen_func(self.os, name)],
+                copy(operand(*dst), Operand::Register(Register::R0)),
+            ]
+            .concat(),
+            Inst::Copy { dst, src } => copy(operand(*dst), operand(*src)),
+            Inst::Phi { .. } => unreachable!("phis are gone before emission"),
+        }
+    }
 
-pub fn less_arg() -> String {
-    less(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
-}
+    /// `next` is the block laid out right after, which needs no jump.
+    fn terminator(&self, frame: &Frame, terminator: &Terminator, next: BlockId) -> Vec<Line> {
+        match *terminator {
+            Terminator::Return(value) => [
+                copy(Operand::Register(Register::R0), operand(frame, value)),
+                program_epilogue(frame),
+            ]
+            .concat(),
+            Terminator::Jump(block) if block == next => vec![],
+            Terminator::Jump(block) => vec![self.jump(None, block)],
+            Terminator::Branch {
+                cond,
+                then_block,
+                else_block,
+            } => {
+                let jumps = if then_block == next {
+                    vec![self.jump(Some(Cond::Eq), else_block)]
+                } else if else_block == next {
+                    vec![self.jump(Some(Cond::Ne), then_block)]
+                } else {
+                    vec![
+                        self.jump(Some(Cond::Eq), else_block),
+                        self.jump(None, then_block),
+                    ]
+                };
+                let (load, cond) = in_register(operand(frame, cond), Register::R0);
+                [load, vec![cmp(cond, Operand::Num(0))], jumps].concat()
+            }
+        }
+    }
 
-fn less(rd: Operand, rn: Operand) -> String {
-    format!("\tcmp {}, {}\n\tsetl al\n\tmovzb {}, al\n", rd, rn, rd)
+    fn jump(&self, cond: Option<Cond>, block: BlockId) -> Line {
+        Line::Jump(cond, self.os.local_label("block", block))
+    }
 }
 
-pub fn less_or_eq_arg() -> String {
-    less_or_eq(
-        Operand::Register(Register::R0),
-        Operand::Register(Register::R1),
-    )
+fn program_prologue(syntax: Syntax, os: Os) -> String {
+    let syntax = match syntax {
+        Syntax::Intel => ".intel_syntax noprefix\n",
+        Syntax::Att => "",
+    };
+    format!("{}{}", syntax, os.text_section())
+}
+
+/// Intel syntax writes the destination first, AT&T writes it last and spells the operand size
+/// out with a suffix on the mnemonic.
+fn inst(syntax: Syntax, mnemonic: &str, suffix: &str, operands: &[Operand]) -> String {
+    match syntax {
+        Syntax::Intel => format!(
+            "\t{} {}\n",
+            mnemonic,
+            operands
+                .iter()
+                .map(|operand| operand.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Syntax::Att => format!(
+            "\t{}{} {}\n",
+            mnemonic,
+            suffix,
+            operands
+                .iter()
+                .rev()
+                .map(|operand| format!("{:#}", operand))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
 }
 
-fn less_or_eq(rd: Operand, rn: Operand) -> String {
-    format!("\tcmp {}, {}\n\tsetle al\n\tmovzb {}, al\n", rd, rn, rd)
+/// push rbp
+/// mov rbp, rsp
+/// sub rsp, #bytes
+/// and the callee-saved registers in use into their slots
+fn memory_allocate(frame: &Frame) -> Vec<Line> {
+    let mut lines = vec![
+        Line::Comment("memory allocate".to_owned()),
+        push(Operand::Register(Register::R5)),
+        mov(
+            Operand::Register(Register::R5),
+            Operand::Register(Register::R6),
+        ),
+        sub(
+            Operand::Register(Register::R6),
+            Operand::Num(frame.size as i64),
+        ),
+    ];
+    for (register, offset) in frame.saved() {
+        lines.push(mov(
+            local(offset),
+            Operand::Register(Register::Allocated(register)),
+        ));
+    }
+    lines
 }
 
-pub fn gen_ret() -> String {
-    format!(
-        "; return\n{}{}{}{}",
-        pop(Operand::Register(Register::R0)),
+/// the callee-saved registers back from their slots
+/// mov rsp, rbp
+/// pop rbp
+/// ret
+fn program_epilogue(frame: &Frame) -> Vec<Line> {
+    let mut lines = vec![];
+    for (register, offset) in frame.saved() {
+        lines.push(mov(
+            Operand::Register(Register::Allocated(register)),
+            local(offset),
+        ));
+    }
+    lines.extend([
         mov(
             Operand::Register(Register::R6),
-            Operand::Register(Register::R5)
+            Operand::Register(Register::R5),
         ),
         pop(Operand::Register(Register::R5)),
-        ret()
-    )
+        Line::Ret,
+    ]);
+    lines
+}
+
+/// The slot `offset` bytes below rbp.
+fn local(offset: usize) -> Operand {
+    Operand::Address(Register::R5, -(offset as i64))
+}
+
+fn operand(frame: &Frame, vreg: VReg) -> Operand {
+    match frame.location(vreg) {
+        Location::Register(n) => Operand::Register(Register::Allocated(n)),
+        Location::Stack(offset) => local(offset),
+    }
+}
+
+/// rd <- rn, through rax when both are in memory
+fn copy(rd: Operand, rn: Operand) -> Vec<Line> {
+    if rd == rn {
+        return vec![];
+    }
+    match (rd, rn) {
+        (Operand::Address(..), Operand::Address(..)) => vec![
+            mov(Operand::Register(Register::R0), rn),
+            mov(rd, Operand::Register(Register::R0)),
+        ],
+        _ => vec![mov(rd, rn)],
+    }
 }
 
-fn ret() -> String {
-    "\tret\n".to_owned()
+/// A register holding `operand`, loading it into `scratch` if it is in memory.
+fn in_register(operand: Operand, scratch: Register) -> (Vec<Line>, Operand) {
+    match operand {
+        Operand::Address(..) => (
+            vec![mov(Operand::Register(scratch), operand)],
+            Operand::Register(scratch),
+        ),
+        _ => (vec![], operand),
+    }
 }
 
-fn lbegin(n: usize) -> String {
-    format!(".Lbegin{:0width$}:\n", n, width = 3)
+/// `mov` sign-extends a 32-bit immediate; anything wider needs `movabs`.
+fn mov_imm(rd: Register, n: i64) -> Line {
+    let mnemonic = if i32::try_from(n).is_ok() {
+        Mnemonic::Mov
+    } else {
+        Mnemonic::Movabs
+    };
+    Line::Op(mnemonic, vec![Operand::Register(rd), Operand::Num(n)])
+}
+
+/// rd <- lhs op rhs. The two-operand instructions work in rd when it is a register other than
+/// the one rhs is in, and in rax otherwise.
+fn binary(op: BinOp, rd: Operand, lhs: Operand, rhs: Operand) -> Vec<Line> {
+    let r0 = Operand::Register(Register::R0);
+    let work = match rd {
+        Operand::Register(_) if rd != rhs => rd,
+        _ => r0,
+    };
+    let compute = match op {
+        BinOp::Add => add(work, rhs),
+        BinOp::Sub => sub(work, rhs),
+        BinOp::Mul => mul(work, rhs),
+        BinOp::Div => {
+            // Intel syntax has no size for a divisor in memory
+            let (load, rhs) = in_register(rhs, Register::R1);
+            return [copy(r0, lhs), load, div(r0, rhs), copy(rd, r0)].concat();
+        }
+        BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le => {
+            let cond = Cond::new(op).expect("a comparison");
+            let (load, lhs) = in_register(lhs, Register::R0);
+            let rd_work = match rd {
+                Operand::Register(_) => rd,
+                _ => r0,
+            };
+            return [load, set(cond, lhs, rhs, rd_work), copy(rd, rd_work)].concat();
+        }
+    };
+    [copy(work, lhs), vec![compute], copy(rd, work)].concat()
 }
 
-fn lend(n: usize) -> String {
-    format!(".Lend{:0width$}:\n", n, width = 3)
+/// push rd
+fn push(rd: Operand) -> Line {
+    Line::Op(Mnemonic::Push, vec![rd])
 }
 
-fn lelse(n: usize) -> String {
-    format!(".Lelse{:0width$}:\n", n, width = 3)
+/// pop rd
+fn pop(rd: Operand) -> Line {
+    Line::Op(Mnemonic::Pop, vec![rd])
 }
 
-fn je_lend(n: usize) -> String {
-    format!("\tje .Lend{:0width$}\n", n, width = 3)
+fn add(rd: Operand, rn: Operand) -> Line {
+    Line::Op(Mnemonic::Add, vec![rd, rn]) // rd <- rd + rn
 }
 
-fn je_lelse(n: usize) -> String {
-    format!("\tje .Lelse{:0width$}\n", n, width = 3)
+fn sub(rd: Operand, rn: Operand) -> Line {
+    Line::Op(Mnemonic::Sub, vec![rd, rn]) // rd <- rd - rn
 }
 
-fn jmp_lend(n: usize) -> String {
-    format!("\tjmp .Lend{:0width$}\n", n, width = 3)
+fn mul(rd: Operand, rn: Operand) -> Line {
+    Line::Op(Mnemonic::Imul, vec![rd, rn]) // rd <- rd x rn
 }
 
-fn jmp_lbegin(n: usize) -> String {
-    format!("\tjmp .Lbegin{:0width$}\n", n, width = 3)
+fn div(_: Operand, rn: Operand) -> Vec<Line> {
+    vec![Line::Cqo, Line::Op(Mnemonic::Idiv, vec![rn])]
 }
 
-pub fn gen_if(expr: &str, stmt: &str, n: usize) -> String {
-    format!(
-        "{}\tcmp {}, {}\n{}{}{}",
-        expr,
-        Operand::Register(Register::R0),
-        Operand::Num(0),
-        je_lend(n),
-        stmt,
-        lend(n)
-    )
+fn mov(rd: Operand, src2: Operand) -> Line {
+    Line::Op(Mnemonic::Mov, vec![rd, src2])
 }
 
-pub fn gen_if_else(expr: &str, stmt: &str, stmt_else: &str, n: usize) -> String {
-    format!(
-        "{}\tcmp {}, {}\n{}{}{}{}{}{}",
-        expr,
-        Operand::Register(Register::R0),
-        Operand::Num(0),
-        je_lelse(n),
-        stmt,
-        jmp_lend(n),
-        lelse(n),
-        stmt_else,
-        lend(n),
-    )
+/// cmp lhs, rhs
+/// set<cc> al
+/// movzb rd, al
+fn set(cond: Cond, lhs: Operand, rhs: Operand, rd: Operand) -> Vec<Line> {
+    vec![
+        cmp(lhs, rhs),
+        Line::Set(cond),
+        Line::Op(Mnemonic::Movzb, vec![rd, Operand::Register(Register::R0B)]),
+    ]
 }
 
-pub fn gen_while(expr: &str, stmt: &str, n: usize) -> String {
-    format!(
-        "{}{}\tcmp {}, {}\n{}{}{}{}",
-        lbegin(n),
-        expr,
-        Operand::Register(Register::R0),
-        Operand::Num(0),
-        je_lend(n),
-        stmt,
-        jmp_lbegin(n),
-        lend(n),
-    )
+fn cmp(rd: Operand, rn: Operand) -> Line {
+    Line::Op(Mnemonic::Cmp, vec![rd, rn])
 }
 
-pub fn gen_for(init_expr: &str, cond_expr: &str, loop_expr: &str, stmt: &str, n: usize) -> String {
-    format!(
-        "{}{}{}\tcmp {}, {}\n{}{}{}{}{}",
-        init_expr,
-        lbegin(n),
-        cond_expr,
-        Operand::Register(Register::R0),
-        Operand::Num(0),
-        je_lend(n),
-        stmt,
-        loop_expr,
-        jmp_lbegin(n),
-        lend(n)
-    )
+/// ELF code calls functions that may be in a shared library through the PLT.
+fn callee(os: Os, name: &str) -> String {
+    match os {
+        Os::Linux => format!("{}@PLT", name),
+        Os::Darwin => os.symbol(name),
+    }
 }
 
-pub fn gen_func(name: &str) -> String {
-    format!("\tbl _{}\n", name)
+/// The frame keeps rsp 16-byte aligned, as the ABI wants at a call.
+fn gen_func(os: Os, name: &str) -> Line {
+    Line::Call(callee(os, name))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ir::lower;
+    use crate::passes::Pipeline;
+    use crate::{lexer::lexer, parser::parser, variable::variable_analysis};
+
+    fn function(syntax: Syntax, src: &str) -> String {
+        let (tokens, ident_count) = variable_analysis(lexer(src).unwrap()).unwrap();
+        let function = lower(&parser(tokens).unwrap(), ident_count).unwrap();
+        X86_64 {
+            syntax,
+            os: Os::Linux,
+        }
+        .function(
+            &function,
+            &Frame::new(&function, Registers::default()),
+            OptLevel::O0,
+        )
+    }
+
+    #[test]
+    fn syntax_test() {
+        let (r0, r1) = (
+            Operand::Register(Register::R0),
+            Operand::Register(Register::R1),
+        );
+        assert_eq!(
+            "\tmov rax, [rax]\n",
+            render(Syntax::Intel, &[mov(r0, Operand::Address(Register::R0, 0))])
+        );
+        assert_eq!(
+            "\tmovq (%rax), %rax\n",
+            render(Syntax::Att, &[mov(r0, Operand::Address(Register::R0, 0))])
+        );
+        assert_eq!(
+            "\tmov [rbp-16], rax\n",
+            render(Syntax::Intel, &copy(local(16), r0))
+        );
+        assert_eq!(
+            "\tmovq -16(%rbp), %rdi\n",
+            render(Syntax::Att, &copy(r1, local(16)))
+        );
+        assert_eq!(
+            "\tmovabsq $4294967296, %rax\n",
+            render(Syntax::Att, &[mov_imm(Register::R0, 1 << 32)])
+        );
+        assert_eq!(
+            "\tsubq $16, %rsp\n",
+            render(
+                Syntax::Att,
+                &[sub(Operand::Register(Register::R6), Operand::Num(16))]
+            )
+        );
+        assert_eq!(
+            "\tcmpq %rdi, %rax\n\tsete %al\n\tmovzbq %al, %rax\n",
+            render(Syntax::Att, &binary(BinOp::Eq, r0, r0, r1))
+        );
+        assert_eq!(
+            "\tcqto\n\tidivq %rdi\n",
+            render(Syntax::Att, &binary(BinOp::Div, r0, r0, r1))
+        );
+    }
+
+    #[test]
+    fn register_test() {
+        let (rcx, rsi) = (
+            Operand::Register(Register::Allocated(0)),
+            Operand::Register(Register::Allocated(1)),
+        );
+        assert!(copy(rcx, rcx).is_empty());
+        assert_eq!(
+            "\tadd rcx, [rbp-8]\n",
+            render(Syntax::Intel, &binary(BinOp::Add, rcx, rcx, local(8)))
+        );
+        // rcx is the destination and the subtrahend
+        assert_eq!(
+            "\tmov rax, rsi\n\tsub rax, rcx\n\tmov rcx, rax\n",
+            render(Syntax::Intel, &binary(BinOp::Sub, rcx, rsi, rcx))
+        );
+        assert_eq!(
+            "\tmov rax, [rbp-8]\n\tcmp rax, rsi\n\tsetl al\n\tmovzb rcx, al\n",
+            render(Syntax::Intel, &binary(BinOp::Lt, rcx, local(8), rsi))
+        );
+        assert_eq!(
+            "\tmov rax, [rbp-8]\n\tmov rdi, [rbp-16]\n\tcqo\n\tidiv rdi\n\tmov [rbp-8], rax\n",
+            render(
+                Syntax::Intel,
+                &binary(BinOp::Div, local(8), local(8), local(16))
+            )
+        );
+    }
+
+    #[test]
+    fn function_test() {
+        let asm = function(Syntax::Intel, "a = 2; if (a) foo();");
+
+        // a, foo, .last and one slot the virtual registers take turns in
+        assert!(asm.starts_with(
+            ".globl main\nmain:\n# memory allocate\n\tpush rbp\n\tmov rbp, rsp\n\tsub rsp, 32\n"
+        ));
+        assert!(asm.contains("# v1 = 2\n\tmov rax, 2\n\tmov [rbp-32], rax\n"));
+        assert!(asm.contains("\tcmp rax, 0\n\tje .Lblock002\n.Lblock001:\n"));
+        assert!(asm.contains("\tcall foo@PLT\n"));
+        assert!(asm.ends_with("\tmov rsp, rbp\n\tpop rbp\n\tret\n"));
+    }
+
+    /// The code `generator` writes, with the passes, registers and the peephole pass from `-O1`.
+    fn function_at(opt_level: OptLevel, src: &str) -> String {
+        let (tokens, ident_count) = variable_analysis(lexer(src).unwrap()).unwrap();
+        let pipeline = Pipeline::new(opt_level.passes());
+        let function = crate::generator::function(&parser(tokens).unwrap(), ident_count, &pipeline)
+            .unwrap()
+            .0;
+        let backend = X86_64 {
+            syntax: Syntax::Intel,
+            os: Os::Linux,
+        };
+        let registers = match opt_level {
+            OptLevel::O0 => Registers::default(),
+            _ => backend.registers(),
+        };
+        backend.function(&function, &Frame::new(&function, registers), opt_level)
+    }
 
     #[test]
-    fn lbegin_test() {
-        assert_eq!(".Lbegin001", lbegin(1));
-        assert_eq!(".Lbegin010", lbegin(10));
-        assert_eq!(".Lbegin100", lbegin(100));
+    fn callee_saved_test() {
+        let asm = function_at(OptLevel::O1, "a = x(); a - y();");
+
+        // the result of x() lives across the call to y() in rbx, which main has to preserve
+        assert!(asm.contains("\tsub rsp, 48\n\tmov [rbp-40], rbx\n"));
+        assert!(asm.contains("\tcall x@PLT\n\tmov rbx, rax\n"));
+        assert!(asm.ends_with("\tmov rbx, [rbp-40]\n\tmov rsp, rbp\n\tpop rbp\n\tret\n"));
+    }
+
+    #[test]
+    fn peephole_test() {
+        let (rax, rcx) = (
+            Operand::Register(Register::R0),
+            Operand::Register(Register::Allocated(0)),
+        );
+        let lines = vec![
+            mov(rcx, rcx),
+            Line::Comment("stored".to_owned()),
+            mov(local(16), rax),
+            mov(rax, local(16)),
+            Line::Ret,
+        ];
+        assert_eq!(
+            "# stored\n\tmov [rbp-16], rax\n\tret\n",
+            render(Syntax::Intel, &peephole(lines, &RULES))
+        );
+
+        // the comparison only decides the branch
+        let src = "a = x(); b = y(); if (a < b) 1;";
+        let asm = function_at(OptLevel::O1, src);
+        assert!(asm.contains("\tcmp rbx, rcx\n"));
+        assert!(asm.contains("\tjge .Lblock002\n"));
+        assert!(!asm.contains("setl"));
+        // and in a loop, where the compare on the back edge sets al again
+        let src = "s = 0; n = x(); for (i = 0; i < n; i = i + 1) if (i < 3) s = s + i; s;";
+        let asm = function_at(OptLevel::O1, src);
+        assert!(asm.contains("\tjge .Lblock"));
+        assert!(!asm.contains("set"));
+        // -O0 leaves the code as it is
+        let asm = function_at(OptLevel::O0, "a = x(); b = y(); if (a < b) 1;");
+        assert!(asm.contains("\tsetl al\n\tmovzb rax, al\n"));
+        assert!(asm.contains("\tcmp rax, 0\n\tje .Lblock002\n"));
     }
 }
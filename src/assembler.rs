@@ -0,0 +1,508 @@
+use crate::elf::{Object, Relocation, RelocationKind, Symbol};
+use std::collections::BTreeMap;
+
+pub type AssembleError = String;
+
+/// Encodes the Intel syntax x86_64 assembly the backend writes.
+///
+/// Only the instructions and directives the backend uses are understood. Jumps to `.L` labels are
+/// resolved here; every other symbol a jump, call or `[rip + symbol]` refers to is left to the
+/// linker as a relocation.
+pub fn assemble(asm: &str) -> Result<Object, AssembleError> {
+    let mut assembler = Assembler::default();
+    for (n, line) in asm.lines().enumerate() {
+        assembler
+            .line(line)
+            .map_err(|e| format!("line {}: {}", n + 1, e))?;
+    }
+    assembler.finish()
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Size {
+    Byte,
+    Quad,
+}
+
+#[derive(Debug, PartialEq)]
+enum Operand {
+    Register(u8, Size),
+    /// `[reg + displacement]`
+    Memory(u8, i32),
+    /// `[rip + symbol]`
+    RipRelative(String),
+    Immediate(i64),
+    Symbol(String),
+}
+
+const REGISTERS: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+
+const BYTE_REGISTERS: [&str; 4] = ["al", "cl", "dl", "bl"];
+
+fn register(s: &str) -> Option<u8> {
+    REGISTERS.iter().position(|r| *r == s).map(|n| n as u8)
+}
+
+fn operand(s: &str) -> Result<Operand, AssembleError> {
+    if let Some(n) = register(s) {
+        return Ok(Operand::Register(n, Size::Quad));
+    }
+    if let Some(n) = BYTE_REGISTERS.iter().position(|r| *r == s) {
+        return Ok(Operand::Register(n as u8, Size::Byte));
+    }
+    if let Some(inner) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        let inner = inner.trim();
+        if let Some(n) = register(inner) {
+            return Ok(Operand::Memory(n, 0));
+        }
+        if let Some(symbol) = inner.strip_prefix("rip").map(str::trim) {
+            if let Some(symbol) = symbol.strip_prefix('+') {
+                return Ok(Operand::RipRelative(symbol.trim().to_owned()));
+            }
+        }
+        if let Some(n) = inner.find(['+', '-']) {
+            let displacement = inner[n + 1..].trim().parse::<i32>();
+            if let (Some(base), Ok(displacement)) = (register(inner[..n].trim()), displacement) {
+                let sign = if inner[n..].starts_with('-') { -1 } else { 1 };
+                return Ok(Operand::Memory(base, sign * displacement));
+            }
+        }
+        return Err(format!("unsupported memory operand '{}'", s));
+    }
+    if let Ok(n) = s.parse::<i64>() {
+        return Ok(Operand::Immediate(n));
+    }
+    // a call through the PLT gets an R_X86_64_PLT32 relocation either way
+    let symbol = s.strip_suffix("@PLT").unwrap_or(s);
+    if symbol
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+    {
+        return Ok(Operand::Symbol(symbol.to_owned()));
+    }
+    Err(format!("invalid operand '{}'", s))
+}
+
+/// The `/digit` of the `83`/`81` group that takes an immediate.
+fn group_digit(mnemonic: &str) -> Option<u8> {
+    match mnemonic {
+        "add" => Some(0),
+        "and" => Some(4),
+        "sub" => Some(5),
+        "cmp" => Some(7),
+        _ => None,
+    }
+}
+
+/// The `r/m64, r64` opcode.
+fn rm_opcode(mnemonic: &str) -> Option<u8> {
+    match mnemonic {
+        "add" => Some(0x01),
+        "sub" => Some(0x29),
+        "cmp" => Some(0x39),
+        "mov" => Some(0x89),
+        _ => None,
+    }
+}
+
+/// The `r64, r/m64` opcode.
+fn load_opcode(mnemonic: &str) -> Option<u8> {
+    match mnemonic {
+        "add" => Some(0x03),
+        "sub" => Some(0x2b),
+        "cmp" => Some(0x3b),
+        _ => None,
+    }
+}
+
+fn setcc_opcode(mnemonic: &str) -> Option<u8> {
+    match mnemonic {
+        "sete" => Some(0x94),
+        "setne" => Some(0x95),
+        "setl" => Some(0x9c),
+        "setle" => Some(0x9e),
+        _ => None,
+    }
+}
+
+/// The second byte of the `0f 8x` near `j<cc>`.
+fn jcc_opcode(mnemonic: &str) -> Option<u8> {
+    match mnemonic {
+        "je" => Some(0x84),
+        "jne" => Some(0x85),
+        "jl" => Some(0x8c),
+        "jge" => Some(0x8d),
+        "jle" => Some(0x8e),
+        "jg" => Some(0x8f),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Assembler {
+    text: Vec<u8>,
+    labels: BTreeMap<String, usize>,
+    globals: Vec<String>,
+    /// rel32 fields that point at a `.L` label, resolved once every label is known
+    fixups: Vec<(usize, String)>,
+    relocations: Vec<Relocation>,
+}
+
+impl Assembler {
+    fn line(&mut self, line: &str) -> Result<(), AssembleError> {
+        let line = match line.find([';', '#']) {
+            Some(n) => &line[..n],
+            None => line,
+        }
+        .trim();
+
+        if line.is_empty() {
+            return Ok(());
+        }
+        if let Some(label) = line.strip_suffix(':') {
+            if self
+                .labels
+                .insert(label.to_owned(), self.text.len())
+                .is_some()
+            {
+                return Err(format!("label {} is defined twice", label));
+            }
+            return Ok(());
+        }
+        if line.starts_with('.') {
+            return self.directive(line);
+        }
+
+        let (mnemonic, operands) = match line.split_once(char::is_whitespace) {
+            Some((mnemonic, operands)) => (
+                mnemonic,
+                operands
+                    .split(',')
+                    .map(|s| operand(s.trim()))
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => (line, vec![]),
+        };
+        self.instruction(mnemonic, &operands)
+            .ok_or(format!("unsupported instruction '{}'", line))
+    }
+
+    fn directive(&mut self, line: &str) -> Result<(), AssembleError> {
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [".intel_syntax", "noprefix"] | [".text"] => Ok(()),
+            // `write_elf` always adds this section
+            [".section", ".note.GNU-stack,\"\",%progbits"] => Ok(()),
+            [".globl", name] => {
+                self.globals.push(name.to_string());
+                Ok(())
+            }
+            _ => Err(format!("unsupported directive '{}'", line)),
+        }
+    }
+
+    /// REX.W with the extension bits of `reg` and `rm`.
+    fn rex_w(&mut self, reg: u8, rm: u8) {
+        self.text.push(0x48 | ((reg >> 3) << 2) | (rm >> 3));
+    }
+
+    /// ModRM for a register `reg` and a register or `[register + displacement]` `rm`, with the
+    /// shortest displacement that fits.
+    fn modrm(&mut self, reg: u8, rm: &Operand) {
+        match *rm {
+            Operand::Register(rm, _) => self.text.push(0xc0 | ((reg & 7) << 3) | (rm & 7)),
+            Operand::Memory(base, displacement) => {
+                // rbp and r13 without a displacement would mean rip
+                let mode = match i8::try_from(displacement) {
+                    Ok(0) if base & 7 != 5 => 0x00,
+                    Ok(_) => 0x40,
+                    Err(_) => 0x80,
+                };
+                self.text.push(mode | ((reg & 7) << 3) | (base & 7));
+                // rsp and r12 need a SIB byte
+                if base & 7 == 4 {
+                    self.text.push(0x24);
+                }
+                match mode {
+                    0x40 => self.text.push(displacement as u8),
+                    0x80 => self.text.extend(displacement.to_le_bytes()),
+                    _ => {}
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn rm_base(rm: &Operand) -> u8 {
+        match *rm {
+            Operand::Register(n, _) | Operand::Memory(n, _) => n,
+            _ => 0,
+        }
+    }
+
+    /// `opcode` with REX.W and ModRM.
+    fn rm(&mut self, opcode: &[u8], reg: u8, rm: &Operand) {
+        self.rex_w(reg, Self::rm_base(rm));
+        self.text.extend_from_slice(opcode);
+        self.modrm(reg, rm);
+    }
+
+    /// A rel32 field at the end of the instruction just started.
+    fn rel32(&mut self, symbol: &str, kind: RelocationKind) {
+        let offset = self.text.len();
+        if symbol.starts_with(".L") {
+            self.fixups.push((offset, symbol.to_owned()));
+        } else {
+            self.relocations.push(Relocation {
+                offset: offset as u64,
+                symbol: symbol.to_owned(),
+                kind,
+                addend: -4,
+            });
+        }
+        self.text.extend([0; 4]);
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operands: &[Operand]) -> Option<()> {
+        use Operand::*;
+        use Size::*;
+
+        match (mnemonic, operands) {
+            ("push" | "pop", [Register(r, Quad)]) => {
+                if *r >= 8 {
+                    self.text.push(0x41);
+                }
+                let base = if mnemonic == "push" { 0x50 } else { 0x58 };
+                self.text.push(base + (r & 7));
+            }
+            ("push", [Immediate(n)]) => match (i8::try_from(*n), i32::try_from(*n)) {
+                (Ok(n), _) => self.text.extend([0x6a, n as u8]),
+                (_, Ok(n)) => {
+                    self.text.push(0x68);
+                    self.text.extend(n.to_le_bytes());
+                }
+                _ => return None,
+            },
+            ("mov", [Register(d, Quad), rm @ Memory(..)]) => self.rm(&[0x8b], *d, rm),
+            ("mov", [rd @ Register(_, Quad), Immediate(n)]) if i32::try_from(*n).is_ok() => {
+                self.rm(&[0xc7], 0, rd);
+                self.text.extend((*n as i32).to_le_bytes());
+            }
+            ("mov" | "movabs", [Register(d, Quad), Immediate(n)]) => {
+                self.rex_w(0, *d);
+                self.text.push(0xb8 + (d & 7));
+                self.text.extend(n.to_le_bytes());
+            }
+            (
+                "add" | "sub" | "cmp" | "mov",
+                [rm @ (Register(_, Quad) | Memory(..)), Register(s, Quad)],
+            ) => self.rm(&[rm_opcode(mnemonic)?], *s, rm),
+            ("add" | "sub" | "cmp", [Register(d, Quad), rm @ Memory(..)]) => {
+                self.rm(&[load_opcode(mnemonic)?], *d, rm)
+            }
+            ("add" | "and" | "sub" | "cmp", [rm @ Register(_, Quad), Immediate(n)]) => {
+                let digit = group_digit(mnemonic)?;
+                match (i8::try_from(*n), i32::try_from(*n)) {
+                    (Ok(n), _) => {
+                        self.rm(&[0x83], digit, rm);
+                        self.text.push(n as u8);
+                    }
+                    (_, Ok(n)) => {
+                        self.rm(&[0x81], digit, rm);
+                        self.text.extend(n.to_le_bytes());
+                    }
+                    _ => return None,
+                }
+            }
+            ("imul", [Register(d, Quad), rm @ (Register(_, Quad) | Memory(..))]) => {
+                self.rm(&[0x0f, 0xaf], *d, rm)
+            }
+            ("cqo", []) => self.text.extend([0x48, 0x99]),
+            ("idiv", [rm @ (Register(_, Quad) | Memory(..))]) => self.rm(&[0xf7], 7, rm),
+            (_, [rm @ Register(_, Byte)]) => {
+                self.text.extend([0x0f, setcc_opcode(mnemonic)?]);
+                self.modrm(0, rm);
+            }
+            ("movzb" | "movzx", [Register(d, Quad), rm @ Register(_, Byte)]) => {
+                self.rm(&[0x0f, 0xb6], *d, rm)
+            }
+            ("lea", [Register(d, Quad), RipRelative(symbol)]) => {
+                self.rex_w(*d, 0);
+                self.text.extend([0x8d, ((d & 7) << 3) | 5]);
+                self.rel32(symbol, RelocationKind::Pc32);
+            }
+            ("ret", []) => self.text.push(0xc3),
+            ("jmp", [Symbol(symbol)]) => {
+                self.text.push(0xe9);
+                self.rel32(symbol, RelocationKind::Plt32);
+            }
+            ("call", [Symbol(symbol)]) => {
+                self.text.push(0xe8);
+                self.rel32(symbol, RelocationKind::Plt32);
+            }
+            (_, [Symbol(symbol)]) => {
+                self.text.extend([0x0f, jcc_opcode(mnemonic)?]);
+                self.rel32(symbol, RelocationKind::Plt32);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn finish(mut self) -> Result<Object, AssembleError> {
+        for (offset, label) in &self.fixups {
+            let target = match self.labels.get(label) {
+                Some(target) => *target,
+                None => return Err(format!("undefined label {}", label)),
+            };
+            let rel = target as i64 - (*offset as i64 + 4);
+            self.text[*offset..*offset + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+
+        let mut symbols: Vec<Symbol> = self
+            .labels
+            .iter()
+            .filter(|(name, _)| !name.starts_with(".L"))
+            .map(|(name, offset)| Symbol {
+                name: name.clone(),
+                global: self.globals.contains(name),
+                value: Some(*offset as u64),
+            })
+            .collect();
+        let undefined = self
+            .globals
+            .iter()
+            .chain(self.relocations.iter().map(|r| &r.symbol));
+        for name in undefined {
+            if !symbols.iter().any(|s| s.name == *name) {
+                symbols.push(Symbol {
+                    name: name.clone(),
+                    global: true,
+                    value: None,
+                });
+            }
+        }
+
+        Ok(Object {
+            text: self.text,
+            symbols,
+            relocations: self.relocations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(asm: &str) -> Vec<u8> {
+        assemble(asm).unwrap().text
+    }
+
+    #[test]
+    fn encode_test() {
+        let cases: [(&str, &[u8]); 31] = [
+            ("push rbp", &[0x55]),
+            ("push r12", &[0x41, 0x54]),
+            ("pop rdi", &[0x5f]),
+            ("push 5", &[0x6a, 0x05]),
+            ("push 300", &[0x68, 0x2c, 0x01, 0x00, 0x00]),
+            ("mov rbp, rsp", &[0x48, 0x89, 0xe5]),
+            ("mov rax, [rax]", &[0x48, 0x8b, 0x00]),
+            ("mov [rax], rdi", &[0x48, 0x89, 0x38]),
+            ("mov rax, [rbp]", &[0x48, 0x8b, 0x45, 0x00]),
+            ("mov rax, [rsp]", &[0x48, 0x8b, 0x04, 0x24]),
+            ("mov r9, [r13]", &[0x4d, 0x8b, 0x4d, 0x00]),
+            ("mov rax, [rbp-8]", &[0x48, 0x8b, 0x45, 0xf8]),
+            (
+                "mov [rbp - 1024], rax",
+                &[0x48, 0x89, 0x85, 0x00, 0xfc, 0xff, 0xff],
+            ),
+            ("mov rdi, [rsp+16]", &[0x48, 0x8b, 0x7c, 0x24, 0x10]),
+            ("mov rax, -1", &[0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff]),
+            (
+                "movabs rdi, 4294967296",
+                &[0x48, 0xbf, 0, 0, 0, 0, 1, 0, 0, 0],
+            ),
+            ("mov rax, 4294967296", &[0x48, 0xb8, 0, 0, 0, 0, 1, 0, 0, 0]),
+            ("sub rsp, 16", &[0x48, 0x83, 0xec, 0x10]),
+            ("and rsp, -16", &[0x48, 0x83, 0xe4, 0xf0]),
+            ("sub rsp, 1000", &[0x48, 0x81, 0xec, 0xe8, 0x03, 0x00, 0x00]),
+            ("mov r15, rsi", &[0x49, 0x89, 0xf7]),
+            ("add rcx, [rbp-16]", &[0x48, 0x03, 0x4d, 0xf0]),
+            ("cmp r8, [rbp-8]", &[0x4c, 0x3b, 0x45, 0xf8]),
+            ("imul rax, rdi", &[0x48, 0x0f, 0xaf, 0xc7]),
+            ("imul rbx, r12", &[0x49, 0x0f, 0xaf, 0xdc]),
+            ("cqo", &[0x48, 0x99]),
+            ("idiv rdi", &[0x48, 0xf7, 0xff]),
+            ("cmp rax, 0", &[0x48, 0x83, 0xf8, 0x00]),
+            ("setle al", &[0x0f, 0x9e, 0xc0]),
+            ("movzb rax, al", &[0x48, 0x0f, 0xb6, 0xc0]),
+            ("ret", &[0xc3]),
+        ];
+
+        for (asm, bytes) in cases {
+            assert_eq!(bytes, text(asm), "{}", asm);
+        }
+    }
+
+    #[test]
+    fn label_test() {
+        assert_eq!(
+            vec![0xe9, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x84, 0xfa, 0xff, 0xff, 0xff],
+            text("; comment\n\tjmp .L1\n.L1:\n\tje .L1\n")
+        );
+        assert_eq!(
+            vec![0x0f, 0x85, 0xfa, 0xff, 0xff, 0xff],
+            text(".L1:\n\tjne .L1\n")
+        );
+        assert_eq!(
+            vec![0x0f, 0x8d, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x8e, 0xfa, 0xff, 0xff, 0xff],
+            text("\tjge .L1\n.L1:\n\tjle .L1\n")
+        );
+        assert_eq!(Err("undefined label .L2".to_owned()), assemble("jmp .L2"));
+        assert_eq!(
+            Err("line 2: unsupported instruction 'bl _foo'".to_owned()),
+            assemble("ret\nbl _foo")
+        );
+    }
+
+    #[test]
+    fn relocation_test() {
+        let object =
+            assemble(".globl main\nmain:\n\tcall foo@PLT\n\tlea rax, [rip + bar]\n").unwrap();
+
+        assert_eq!(
+            vec![0xe8, 0, 0, 0, 0, 0x48, 0x8d, 0x05, 0, 0, 0, 0],
+            object.text
+        );
+        assert_eq!(
+            vec![
+                Relocation {
+                    offset: 1,
+                    symbol: "foo".to_owned(),
+                    kind: RelocationKind::Plt32,
+                    addend: -4,
+                },
+                Relocation {
+                    offset: 8,
+                    symbol: "bar".to_owned(),
+                    kind: RelocationKind::Pc32,
+                    addend: -4,
+                },
+            ],
+            object.relocations
+        );
+        assert_eq!(
+            vec!["main", "foo", "bar"],
+            object
+                .symbols
+                .iter()
+                .map(|s| s.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert!(object.symbols[0].global && object.symbols[0].value == Some(0));
+    }
+}
@@ -0,0 +1,548 @@
+use crate::generator::GenerateError;
+use crate::tree::*;
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"IOCB";
+const VERSION: u8 = 1;
+
+/// The instructions of a stack machine that works like the assembly the backends generate:
+/// variables are read through an address pushed on the stack, and `pop`/`ret` move the top of
+/// the stack into the result register.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Op {
+    Push(i64),
+    /// pushes the address of the `n`th variable slot
+    Addr(u32),
+    /// replaces the address on top of the stack with the value it points to
+    Load,
+    /// stores the value on top of the stack to the address below it, leaving the value
+    Store,
+    /// pops the result of an expression statement into the result register
+    Pop,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Jump(u32),
+    /// pops a condition and jumps when it is zero
+    JumpIfZero(u32),
+    /// calls the `n`th function of `Program::functions` and pushes its result
+    Call(u32),
+    /// pops the return value into the result register and stops
+    Ret,
+}
+
+impl Op {
+    fn opcode(&self) -> u8 {
+        match self {
+            Op::Push(_) => 0x01,
+            Op::Addr(_) => 0x02,
+            Op::Load => 0x03,
+            Op::Store => 0x04,
+            Op::Pop => 0x05,
+            Op::Add => 0x10,
+            Op::Sub => 0x11,
+            Op::Mul => 0x12,
+            Op::Div => 0x13,
+            Op::Eq => 0x14,
+            Op::Ne => 0x15,
+            Op::Lt => 0x16,
+            Op::Le => 0x17,
+            Op::Jump(_) => 0x20,
+            Op::JumpIfZero(_) => 0x21,
+            Op::Call(_) => 0x22,
+            Op::Ret => 0x23,
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Push(n) => write!(f, "push {}", n),
+            Op::Addr(n) => write!(f, "addr {}", n),
+            Op::Load => write!(f, "load"),
+            Op::Store => write!(f, "store"),
+            Op::Pop => write!(f, "pop"),
+            Op::Add => write!(f, "add"),
+            Op::Sub => write!(f, "sub"),
+            Op::Mul => write!(f, "mul"),
+            Op::Div => write!(f, "div"),
+            Op::Eq => write!(f, "eq"),
+            Op::Ne => write!(f, "ne"),
+            Op::Lt => write!(f, "lt"),
+            Op::Le => write!(f, "le"),
+            Op::Jump(n) => write!(f, "jump {:04}", n),
+            Op::JumpIfZero(n) => write!(f, "jz {:04}", n),
+            Op::Call(n) => write!(f, "call {}", n),
+            Op::Ret => write!(f, "ret"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct Program {
+    /// how many variable slots `main` needs
+    pub frame_size: u32,
+    /// the external functions `Op::Call` refers to
+    pub functions: Vec<String>,
+    pub code: Vec<Op>,
+}
+
+pub type BytecodeError = String;
+
+/// Compiles the program to bytecode. Running off the end of `code` returns the result register,
+/// the value of the last expression statement.
+pub fn bytecode(trees: &[Tree], ident_count: usize) -> Result<Program, GenerateError> {
+    let mut program = Program {
+        frame_size: ident_count as u32,
+        ..Program::default()
+    };
+    for tree in trees {
+        program.stmt(tree)?;
+    }
+    Ok(program)
+}
+
+impl Program {
+    fn here(&self) -> u32 {
+        self.code.len() as u32
+    }
+
+    /// Patches the jump at `at` to land on the next instruction.
+    fn patch(&mut self, at: usize) {
+        let here = self.here();
+        match &mut self.code[at] {
+            Op::Jump(n) | Op::JumpIfZero(n) => *n = here,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Emits a jump over what follows when `cond` is zero, and returns where to patch it. A
+    /// missing condition, like in `for (;;)`, is always true.
+    fn jump_unless(&mut self, cond: &Tree) -> Result<Option<usize>, GenerateError> {
+        if *cond == Tree::None {
+            return Ok(None);
+        }
+        self.expr(cond)?;
+        self.code.push(Op::JumpIfZero(0));
+        Ok(Some(self.code.len() - 1))
+    }
+
+    fn stmt(&mut self, tree: &Tree) -> Result<(), GenerateError> {
+        match tree {
+            Tree::None => {}
+            Tree::Return(expr) => {
+                self.expr(expr)?;
+                self.code.push(Op::Ret);
+            }
+            Tree::If(cond, stmt) => {
+                let end = self.jump_unless(cond)?;
+                self.stmt(stmt)?;
+                if let Some(end) = end {
+                    self.patch(end);
+                }
+            }
+            Tree::IfElse(cond, stmt, else_stmt) => {
+                let otherwise = self.jump_unless(cond)?;
+                self.stmt(stmt)?;
+                self.code.push(Op::Jump(0));
+                let end = self.code.len() - 1;
+                if let Some(otherwise) = otherwise {
+                    self.patch(otherwise);
+                }
+                self.stmt(else_stmt)?;
+                self.patch(end);
+            }
+            Tree::While(cond, stmt) => {
+                let begin = self.here();
+                let end = self.jump_unless(cond)?;
+                self.stmt(stmt)?;
+                self.code.push(Op::Jump(begin));
+                if let Some(end) = end {
+                    self.patch(end);
+                }
+            }
+            Tree::For(init, cond, step, stmt) => {
+                self.stmt(init)?;
+                let begin = self.here();
+                let end = self.jump_unless(cond)?;
+                self.stmt(stmt)?;
+                self.stmt(step)?;
+                self.code.push(Op::Jump(begin));
+                if let Some(end) = end {
+                    self.patch(end);
+                }
+            }
+            Tree::Block(trees) => {
+                for tree in trees {
+                    self.stmt(tree)?;
+                }
+            }
+            expr => {
+                self.expr(expr)?;
+                self.code.push(Op::Pop);
+            }
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, tree: &Tree) -> Result<(), GenerateError> {
+        match tree {
+            Tree::Int(n) => self.code.push(Op::Push(*n as i64)),
+            Tree::Val { offset, .. } => {
+                self.code.push(Op::Addr(slot(*offset)));
+                self.code.push(Op::Load);
+            }
+            Tree::Func { name } => {
+                let n = match self.functions.iter().position(|f| f == name) {
+                    Some(n) => n,
+                    None => {
+                        self.functions.push(name.clone());
+                        self.functions.len() - 1
+                    }
+                };
+                self.code.push(Op::Call(n as u32));
+            }
+            Tree::Node(NodeKind::Assign, lhs, rhs) => {
+                match **lhs {
+                    Tree::Val { offset, .. } => self.code.push(Op::Addr(slot(offset))),
+                    _ => {
+                        return Err(
+                            "The left-hand side value of the assignment is not a variable"
+                                .to_owned(),
+                        )
+                    }
+                }
+                self.expr(rhs)?;
+                self.code.push(Op::Store);
+            }
+            Tree::Node(kind, lhs, rhs) => {
+                self.expr(lhs)?;
+                self.expr(rhs)?;
+                self.code.push(match kind {
+                    NodeKind::Add => Op::Add,
+                    NodeKind::Sub => Op::Sub,
+                    NodeKind::Mul => Op::Mul,
+                    NodeKind::Div => Op::Div,
+                    NodeKind::Equality => Op::Eq,
+                    NodeKind::Nonequality => Op::Ne,
+                    NodeKind::Less => Op::Lt,
+                    NodeKind::LessOrEqual => Op::Le,
+                    NodeKind::Assign => unreachable!(),
+                });
+            }
+            _ => return Err("unexpected statement in expression".to_owned()),
+        }
+        Ok(())
+    }
+}
+
+/// Variables live at `[rbp - offset]` in 8 byte slots starting at offset 8.
+fn slot(offset: usize) -> u32 {
+    (offset / 8 - 1) as u32
+}
+
+/// Writes the `.iocb` file format: the magic, a version byte, then the frame size, the function
+/// names and the instructions. Numbers are LEB128 encoded, so most instructions take two bytes.
+pub fn serialize(program: &Program) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    uleb128(&mut out, program.frame_size as u64);
+    uleb128(&mut out, program.functions.len() as u64);
+    for name in &program.functions {
+        uleb128(&mut out, name.len() as u64);
+        out.extend_from_slice(name.as_bytes());
+    }
+    uleb128(&mut out, program.code.len() as u64);
+    for op in &program.code {
+        out.push(op.opcode());
+        match op {
+            Op::Push(n) => sleb128(&mut out, *n),
+            Op::Addr(n) | Op::Jump(n) | Op::JumpIfZero(n) | Op::Call(n) => {
+                uleb128(&mut out, *n as u64)
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+pub fn deserialize(bytes: &[u8]) -> Result<Program, BytecodeError> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if !bytes.starts_with(MAGIC) {
+        return Err("not an iocb file".to_owned());
+    }
+    reader.pos = MAGIC.len();
+    match reader.byte()? {
+        VERSION => {}
+        version => return Err(format!("unsupported iocb version {}", version)),
+    }
+
+    let frame_size = reader.u32()?;
+    let mut functions = vec![];
+    for _ in 0..reader.uleb128()? {
+        let len = reader.uleb128()? as usize;
+        let name = reader.take(len)?;
+        match String::from_utf8(name.to_vec()) {
+            Ok(name) => functions.push(name),
+            Err(_) => return Err("function name is not UTF-8".to_owned()),
+        }
+    }
+
+    let len = reader.uleb128()?;
+    let mut code = vec![];
+    for _ in 0..len {
+        let op = match reader.byte()? {
+            0x01 => Op::Push(reader.sleb128()?),
+            0x02 => Op::Addr(reader.u32()?),
+            0x03 => Op::Load,
+            0x04 => Op::Store,
+            0x05 => Op::Pop,
+            0x10 => Op::Add,
+            0x11 => Op::Sub,
+            0x12 => Op::Mul,
+            0x13 => Op::Div,
+            0x14 => Op::Eq,
+            0x15 => Op::Ne,
+            0x16 => Op::Lt,
+            0x17 => Op::Le,
+            0x20 => Op::Jump(reader.u32()?),
+            0x21 => Op::JumpIfZero(reader.u32()?),
+            0x22 => Op::Call(reader.u32()?),
+            0x23 => Op::Ret,
+            opcode => return Err(format!("unknown opcode {:#04x}", opcode)),
+        };
+        code.push(op);
+    }
+    if reader.pos != bytes.len() {
+        return Err("trailing bytes after the code".to_owned());
+    }
+
+    // Every slot belongs to a name the code uses at least once, so a larger frame is corrupt and
+    // would only have the VM allocate it.
+    if frame_size as u64 > len {
+        return Err(format!(
+            "frame of {} slots is larger than the code",
+            frame_size
+        ));
+    }
+    // Checked once here so the VM can index without bounds errors of its own.
+    for op in &code {
+        match *op {
+            Op::Jump(n) | Op::JumpIfZero(n) if n as u64 > len => {
+                return Err(format!("jump target {} is out of range", n))
+            }
+            Op::Addr(n) if n >= frame_size => {
+                return Err(format!("variable slot {} is out of range", n))
+            }
+            Op::Call(n) if n as usize >= functions.len() => {
+                return Err(format!("function {} is out of range", n))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Program {
+        frame_size,
+        functions,
+        code,
+    })
+}
+
+/// Lists the instructions one per line, prefixed by their index that jumps refer to.
+pub fn disassemble(program: &Program) -> String {
+    let mut out = format!("frame {}\n", program.frame_size);
+    for (n, name) in program.functions.iter().enumerate() {
+        out.push_str(&format!("function {} {}\n", n, name));
+    }
+    for (n, op) in program.code.iter().enumerate() {
+        out.push_str(&format!("{:04}  {}", n, op));
+        if let Op::Call(f) = op {
+            out.push_str(&format!("  ; {}", program.functions[*f as usize]));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn uleb128(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb128(out: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], BytecodeError> {
+        let end = self.pos.checked_add(len);
+        match end.and_then(|end| self.bytes.get(self.pos..end)) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => Err("truncated iocb file".to_owned()),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn uleb128(&mut self) -> Result<u64, BytecodeError> {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err("LEB128 number is too long".to_owned())
+    }
+
+    fn sleb128(&mut self) -> Result<i64, BytecodeError> {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as i64) << shift;
+            if byte & 0x80 == 0 {
+                if shift < 57 && byte & 0x40 != 0 {
+                    n |= -1 << (shift + 7);
+                }
+                return Ok(n);
+            }
+        }
+        Err("LEB128 number is too long".to_owned())
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        match u32::try_from(self.uleb128()?) {
+            Ok(n) => Ok(n),
+            Err(_) => Err("operand does not fit in 32 bits".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::lexer, parser::parser, variable::variable_analysis};
+
+    fn compile(src: &str) -> Program {
+        let (tokens, ident_count) = variable_analysis(lexer(src).unwrap()).unwrap();
+        bytecode(&parser(tokens).unwrap(), ident_count).unwrap()
+    }
+
+    #[test]
+    fn bytecode_test() {
+        assert_eq!(
+            vec![
+                Op::Addr(0),
+                Op::Push(3),
+                Op::Store,
+                Op::Pop,
+                Op::Addr(0),
+                Op::Load,
+                Op::Call(0),
+                Op::Lt,
+                Op::JumpIfZero(11),
+                Op::Push(1),
+                Op::Ret,
+            ],
+            compile("a = 3; if (a < foo()) return 1;").code
+        );
+        assert_eq!(
+            vec![Op::Push(1), Op::Pop, Op::Jump(0)],
+            compile("for (;;) 1;").code
+        );
+    }
+
+    #[test]
+    fn serialize_test() {
+        let program = compile("a = -200; while (a < 70000) a = a * 2; foo(); return a;");
+        let bytes = serialize(&program);
+
+        // `foo` takes a frame slot too, like in the native backends
+        assert_eq!(b"IOCB\x01\x02\x01\x03foo", &bytes[..11]);
+        assert_eq!(Ok(program), deserialize(&bytes));
+    }
+
+    #[test]
+    fn deserialize_error_test() {
+        assert_eq!(Err("not an iocb file".to_owned()), deserialize(b"\x7fELF"));
+        assert_eq!(
+            Err("unsupported iocb version 2".to_owned()),
+            deserialize(b"IOCB\x02")
+        );
+        assert_eq!(
+            Err("truncated iocb file".to_owned()),
+            deserialize(b"IOCB\x01\x00\x00\x01\x01")
+        );
+        assert_eq!(
+            Err("unknown opcode 0xff".to_owned()),
+            deserialize(b"IOCB\x01\x00\x00\x01\xff")
+        );
+        assert_eq!(
+            Err("jump target 5 is out of range".to_owned()),
+            deserialize(b"IOCB\x01\x00\x00\x01\x20\x05")
+        );
+        assert_eq!(
+            Err("variable slot 0 is out of range".to_owned()),
+            deserialize(b"IOCB\x01\x00\x00\x01\x02\x00")
+        );
+        assert_eq!(
+            Err("frame of 4294967295 slots is larger than the code".to_owned()),
+            deserialize(b"IOCB\x01\xff\xff\xff\xff\x0f\x00\x00")
+        );
+        assert_eq!(
+            Err("truncated iocb file".to_owned()),
+            deserialize(b"IOCB\x01\x00\x01\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01")
+        );
+    }
+
+    #[test]
+    fn disassemble_test() {
+        assert_eq!(
+            "frame 2
+function 0 foo
+0000  addr 0
+0001  call 0  ; foo
+0002  store
+0003  pop
+0004  addr 0
+0005  load
+0006  jz 0009
+0007  push 7
+0008  ret
+",
+            disassemble(&compile("a = foo(); if (a) return 7;"))
+        );
+    }
+}
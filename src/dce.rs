@@ -0,0 +1,267 @@
+use crate::ir::{BinOp, Function, Inst, Terminator};
+use crate::ssa::{remove_unreachable, reverse_postorder};
+use crate::warning::Warning;
+
+/// Drops the blocks control never reaches and the stores to variables that no load reads after,
+/// warning about the statements and variables of the program that go. Runs before SSA form,
+/// where stores still name their variables.
+pub fn dead_stores(function: &mut Function) -> Vec<Warning> {
+    let mut warnings = unreachable(function);
+    let blocks = function.blocks.len();
+    let vars = function.vars.len();
+
+    // the variables read in a block before it writes them, and the ones it writes
+    let mut uses = vec![vec![false; vars]; blocks];
+    let mut defs = vec![vec![false; vars]; blocks];
+    for (n, block) in function.blocks.iter().enumerate() {
+        for inst in &block.insts {
+            match *inst {
+                Inst::Load { var, .. } if !defs[n][var] => uses[n][var] = true,
+                Inst::Store { var, .. } => defs[n][var] = true,
+                _ => {}
+            }
+        }
+    }
+
+    // the variables live coming out of each block, backwards to a fixed point
+    let mut live_out = vec![vec![false; vars]; blocks];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for n in (0..blocks).rev() {
+            for successor in function.blocks[n].terminator.successors() {
+                for var in 0..vars {
+                    let live_in =
+                        uses[successor][var] || (live_out[successor][var] && !defs[successor][var]);
+                    if live_in && !live_out[n][var] {
+                        live_out[n][var] = true;
+                        changed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    // `lower` starts every variable at the zero it defines first; those stores aren't the user's
+    let zero = match function.blocks[0].insts.first() {
+        Some(Inst::Const { dst, value: 0 }) => Some(*dst),
+        _ => None,
+    };
+    let last = vars - 1;
+    for (n, block) in function.blocks.iter_mut().enumerate() {
+        let mut live = live_out[n].clone();
+        let mut dead = vec![false; block.insts.len()];
+        for (i, inst) in block.insts.iter().enumerate().rev() {
+            match *inst {
+                Inst::Load { var, .. } => live[var] = true,
+                Inst::Store { var, .. } if live[var] => live[var] = false,
+                Inst::Store { var, src } => {
+                    dead[i] = true;
+                    let warning = Warning::DeadStore(function.vars[var].clone());
+                    if var != last && Some(src) != zero && !warnings.contains(&warning) {
+                        warnings.push(warning);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut dead = dead.into_iter();
+        block
+            .insts
+            .retain(|_| !dead.next().expect("one for every instruction"));
+    }
+    warnings
+}
+
+/// Turns branches on constants into jumps, then drops the blocks that cuts off and the
+/// instructions whose values nothing uses, warning about the statements that go. Needs SSA form
+/// after folding, where a condition worked out from constants is itself a constant.
+pub fn dead_code(function: &mut Function) -> Vec<Warning> {
+    let mut constants = vec![None; function.vregs];
+    for inst in function.blocks.iter().flat_map(|block| &block.insts) {
+        if let Inst::Const { dst, value } = *inst {
+            constants[dst] = Some(value);
+        }
+    }
+
+    for n in 0..function.blocks.len() {
+        let (taken, dropped) = match function.blocks[n].terminator {
+            Terminator::Branch {
+                cond,
+                then_block,
+                else_block,
+            } => match constants[cond] {
+                Some(0) => (else_block, then_block),
+                Some(_) => (then_block, else_block),
+                None => continue,
+            },
+            _ => continue,
+        };
+        function.blocks[n].terminator = Terminator::Jump(taken);
+        if taken != dropped {
+            for inst in &mut function.blocks[dropped].insts {
+                if let Inst::Phi { args, .. } = inst {
+                    args.retain(|(predecessor, _)| *predecessor != n);
+                }
+            }
+        }
+    }
+
+    let warnings = unreachable(function);
+    remove_unused(function);
+    warnings
+}
+
+/// Removes the unreachable blocks, returning the statements they held.
+fn unreachable(function: &mut Function) -> Vec<Warning> {
+    let mut reachable = vec![false; function.blocks.len()];
+    for block in reverse_postorder(function) {
+        reachable[block] = true;
+    }
+    let warnings = function
+        .blocks
+        .iter()
+        .zip(reachable)
+        .filter(|(_, reachable)| !reachable)
+        .flat_map(|(block, _)| block.stmts.iter().cloned().map(Warning::Unreachable))
+        .collect();
+    remove_unreachable(function);
+    warnings
+}
+
+/// Removes the instructions whose values nothing needs. Calls stay for what they do, and so do
+/// divisions, which may trap.
+fn remove_unused(function: &mut Function) {
+    let mut definitions = vec![None; function.vregs];
+    let mut used = vec![false; function.vregs];
+    let mut work = vec![];
+    for (n, block) in function.blocks.iter().enumerate() {
+        for (i, inst) in block.insts.iter().enumerate() {
+            if let Some(dst) = inst.dst() {
+                definitions[dst] = Some((n, i));
+            }
+            if has_effect(inst) {
+                work.extend(inst.operands());
+                work.extend(inst.dst());
+            }
+        }
+        work.extend(block.terminator.operands());
+    }
+
+    while let Some(vreg) = work.pop() {
+        if used[vreg] {
+            continue;
+        }
+        used[vreg] = true;
+        if let Some((n, i)) = definitions[vreg] {
+            work.extend(function.blocks[n].insts[i].operands());
+        }
+    }
+
+    for block in &mut function.blocks {
+        block
+            .insts
+            .retain(|inst| has_effect(inst) || inst.dst().is_some_and(|dst| used[dst]));
+    }
+}
+
+fn has_effect(inst: &Inst) -> bool {
+    matches!(
+        inst,
+        Inst::Call { .. } | Inst::Store { .. } | Inst::Binary { op: BinOp::Div, .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fold::fold;
+    use crate::ir::{assert_matches_interpreter, lower_source};
+    use crate::ssa::to_ssa;
+
+    fn eliminate_in(function: &mut Function) -> Vec<Warning> {
+        let mut warnings = dead_stores(function);
+        to_ssa(function);
+        fold(function);
+        warnings.extend(dead_code(function));
+        warnings
+    }
+
+    fn eliminate(src: &str) -> (Function, Vec<String>) {
+        let mut function = lower_source(src);
+        let warnings = eliminate_in(&mut function);
+        (function, warnings.iter().map(ToString::to_string).collect())
+    }
+
+    fn calls(function: &Function) -> usize {
+        function
+            .blocks
+            .iter()
+            .flat_map(|block| &block.insts)
+            .filter(|inst| matches!(inst, Inst::Call { .. }))
+            .count()
+    }
+
+    #[test]
+    fn unreachable_test() {
+        let (function, warnings) = eliminate("return 1; x(); 2;");
+        assert_eq!(0, calls(&function));
+        assert_eq!(
+            vec!["unreachable statement `x();`", "unreachable statement `2;`"],
+            warnings
+        );
+
+        let (function, warnings) = eliminate("if (0) x(); else y(); 3;");
+        assert_eq!(1, calls(&function));
+        assert_eq!(vec!["unreachable statement `x();`"], warnings);
+        assert!(function
+            .blocks
+            .iter()
+            .all(|block| !matches!(block.terminator, Terminator::Branch { .. })));
+
+        let (_, warnings) = eliminate("while (1) x(); return 4;");
+        assert_eq!(vec!["unreachable statement `return 4;`"], warnings);
+
+        let (function, warnings) = eliminate("a = x(); if (a) return 1; return 2;");
+        assert_eq!(
+            2,
+            function
+                .blocks
+                .iter()
+                .filter(|block| matches!(block.terminator, Terminator::Return(_)))
+                .count()
+        );
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn dead_store_test() {
+        let (function, warnings) = eliminate("a = x(); a = 2; b = a * 3; b + 0;");
+        assert_eq!(1, calls(&function));
+        assert_eq!(vec!["value assigned to `a` is never read"], warnings);
+
+        let (_, warnings) = eliminate("s = 0; for (i = 0; i < 3; i = i + 1) s = s + i; s;");
+        assert!(warnings.is_empty(), "{:?}", warnings);
+
+        let (_, warnings) = eliminate("a = 1; b = 2; a = b; a = b; a;");
+        assert_eq!(vec!["value assigned to `a` is never read"], warnings);
+    }
+
+    #[test]
+    fn semantics_test() {
+        let cases = [
+            "a = 1; if (0) a = 2; a;",
+            "a = 3; b = 4; a = b; while (0) b = 9; a + b;",
+            "i = 0; while (1) { i = i + 1; if (i == 5) return i * 2; } 7;",
+            "if (1) return 6; else return 7;",
+            "a = 1; b = a; a = 2; b = 3; a + 1;",
+            "x = 10 / 0; 4;",
+        ];
+
+        for src in cases {
+            assert_matches_interpreter(src, |function| {
+                eliminate_in(function);
+            });
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use crate::ir::{Function, Terminator};
+use crate::tree::*;
+
+pub fn ast_to_dot(trees: &[Tree]) -> String {
+    let mut graph = Graph::default();
+    let root = graph.node("Program");
+    for (i, tree) in trees.iter().enumerate() {
+        let child = graph.tree(tree);
+        graph.edge(root, child, &i.to_string());
+    }
+    format!("digraph ast {{\n\tnode [shape=box];\n{}}}\n", graph.body)
+}
+
+/// Draws the blocks of the function `ir::lower` builds, with their instructions as `--dump-ir`
+/// prints them.
+pub fn cfg_to_dot(function: &Function) -> String {
+    let name = &function.name;
+    let mut body = format!(
+        "\tsubgraph cluster_{} {{\n\t\tlabel=\"{}\";\n",
+        name,
+        escape(name)
+    );
+    for (i, block) in function.blocks.iter().enumerate() {
+        let mut label = format!("b{}\\l", i);
+        for inst in &block.insts {
+            label.push_str(&format!("{}\\l", escape(&function.inst(inst))));
+        }
+        if let Terminator::Return(_) = block.terminator {
+            label.push_str(&format!("{}\\l", block.terminator));
+        }
+        body.push_str(&format!("\t\t{}_{} [label=\"{}\"];\n", name, i, label));
+    }
+    for (i, block) in function.blocks.iter().enumerate() {
+        match block.terminator {
+            Terminator::Return(_) => {}
+            Terminator::Jump(n) => {
+                body.push_str(&format!("\t\t{}_{} -> {}_{};\n", name, i, name, n))
+            }
+            Terminator::Branch {
+                cond,
+                then_block,
+                else_block,
+            } => {
+                body.push_str(&format!(
+                    "\t\t{}_{} -> {}_{} [label=\"v{}\"];\n",
+                    name, i, name, then_block, cond
+                ));
+                body.push_str(&format!(
+                    "\t\t{}_{} -> {}_{} [label=\"!v{}\"];\n",
+                    name, i, name, else_block, cond
+                ));
+            }
+        }
+    }
+    body.push_str("\t}\n");
+    format!("digraph cfg {{\n\tnode [shape=box];\n{}}}\n", body)
+}
+
+#[derive(Default)]
+struct Graph {
+    body: String,
+    count: usize,
+}
+
+impl Graph {
+    fn node(&mut self, label: &str) -> usize {
+        let id = self.count;
+        self.count += 1;
+        self.body
+            .push_str(&format!("\tn{} [label=\"{}\"];\n", id, escape(label)));
+        id
+    }
+
+    fn edge(&mut self, from: usize, to: usize, label: &str) {
+        self.body.push_str(&format!(
+            "\tn{} -> n{} [label=\"{}\"];\n",
+            from,
+            to,
+            escape(label)
+        ));
+    }
+
+    fn children(&mut self, label: &str, children: &[(&str, &Tree)]) -> usize {
+        let id = self.node(label);
+        for (edge, child) in children {
+            let child = self.tree(child);
+            self.edge(id, child, edge);
+        }
+        id
+    }
+
+    fn tree(&mut self, tree: &Tree) -> usize {
+        match tree {
+            Tree::None => self.node("None"),
+            Tree::Int(n) => self.node(&format!("Int {}", n)),
+            Tree::Val { name, offset } => self.node(&format!("Val {} (offset {})", name, offset)),
+            Tree::Func { name } => self.node(&format!("Func {}", name)),
+            Tree::Return(expr) => self.children("Return", &[("expr", expr)]),
+            Tree::If(expr, stmt) => self.children("If", &[("cond", expr), ("then", stmt)]),
+            Tree::IfElse(expr, stmt, else_stmt) => self.children(
+                "IfElse",
+                &[("cond", expr), ("then", stmt), ("else", else_stmt)],
+            ),
+            Tree::While(expr, stmt) => self.children("While", &[("cond", expr), ("body", stmt)]),
+            Tree::For(init_expr, cond_expr, loop_expr, stmt) => self.children(
+                "For",
+                &[
+                    ("init", init_expr),
+                    ("cond", cond_expr),
+                    ("loop", loop_expr),
+                    ("body", stmt),
+                ],
+            ),
+            Tree::Block(stmts) => {
+                let id = self.node("Block");
+                for (i, stmt) in stmts.iter().enumerate() {
+                    let child = self.tree(stmt);
+                    self.edge(id, child, &i.to_string());
+                }
+                id
+            }
+            Tree::Node(kind, lhs, rhs) => {
+                self.children(&format!("{:?}", kind), &[("lhs", lhs), ("rhs", rhs)])
+            }
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::lower_source;
+
+    #[test]
+    fn ast_to_dot_test() {
+        let trees = vec![Tree::new_tree(
+            NodeKind::Assign,
+            Tree::new_val("a", 8),
+            Tree::new_int(1),
+        )];
+
+        assert_eq!(
+            "digraph ast {
+\tnode [shape=box];
+\tn0 [label=\"Program\"];
+\tn1 [label=\"Assign\"];
+\tn2 [label=\"Val a (offset 8)\"];
+\tn1 -> n2 [label=\"lhs\"];
+\tn3 [label=\"Int 1\"];
+\tn1 -> n3 [label=\"rhs\"];
+\tn0 -> n1 [label=\"0\"];
+}
+",
+            ast_to_dot(&trees)
+        );
+    }
+
+    #[test]
+    fn cfg_to_dot_test() {
+        assert_eq!(
+            "digraph cfg {
+\tnode [shape=box];
+\tsubgraph cluster_main {
+\t\tlabel=\"main\";
+\t\tmain_0 [label=\"b0\\lv0 = 0\\lstore a, v0\\lstore .last, v0\\lv1 = load a\\lv2 = 2\\lv3 = lt v1, v2\\l\"];
+\t\tmain_1 [label=\"b1\\lv4 = 1\\lstore .last, v4\\l\"];
+\t\tmain_2 [label=\"b2\\lv5 = load .last\\lret v5\\l\"];
+\t\tmain_0 -> main_1 [label=\"v3\"];
+\t\tmain_0 -> main_2 [label=\"!v3\"];
+\t\tmain_1 -> main_2;
+\t}
+}
+",
+            cfg_to_dot(&lower_source("if (a < 2) 1;"))
+        );
+    }
+}
@@ -0,0 +1,306 @@
+/// The contents of a relocatable object with a single `.text` section.
+#[derive(Debug, PartialEq, Default)]
+pub struct Object {
+    pub text: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub global: bool,
+    /// offset in `.text`, `None` when the symbol is defined in another object
+    pub value: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RelocationKind {
+    /// R_X86_64_PC32, for data references relative to rip
+    Pc32,
+    /// R_X86_64_PLT32, for calls and jumps to other objects
+    Plt32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Relocation {
+    /// offset in `.text` of the 32-bit field to patch
+    pub offset: u64,
+    pub symbol: String,
+    pub kind: RelocationKind,
+    pub addend: i64,
+}
+
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_SECTION: u8 = 3;
+
+/// Section header indices, in the order the headers are written.
+const TEXT: u32 = 1;
+// .rela.text is 2
+const SYMTAB: u32 = 3;
+const STRTAB: u32 = 4;
+const SHSTRTAB: u32 = 5;
+const SECTION_COUNT: u16 = 7;
+
+/// A string table and the offset of every name added to it.
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> StringTable {
+        StringTable { bytes: vec![0] }
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(s.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+/// Writes `object` as an x86_64 ELF64 relocatable (`ET_REL`) file.
+pub fn write_elf(object: &Object) -> Vec<u8> {
+    let mut shstrtab = StringTable::new();
+    let mut strtab = StringTable::new();
+
+    // Locals have to come before globals, and `.symtab`'s info is the index of the first global.
+    let mut symbols: Vec<&Symbol> = object.symbols.iter().filter(|s| !s.global).collect();
+    let first_global = symbols.len() + 2;
+    symbols.extend(object.symbols.iter().filter(|s| s.global));
+
+    let mut symtab = vec![0; SYM_SIZE];
+    symtab.extend(symbol_entry(0, STB_LOCAL, STT_SECTION, TEXT as u16, 0));
+    for symbol in &symbols {
+        let bind = if symbol.global { STB_GLOBAL } else { STB_LOCAL };
+        let (shndx, value) = match symbol.value {
+            Some(value) => (TEXT as u16, value),
+            None => (0, 0),
+        };
+        let name = strtab.add(&symbol.name);
+        symtab.extend(symbol_entry(name, bind, STT_NOTYPE, shndx, value));
+    }
+
+    let mut rela = vec![];
+    for relocation in &object.relocations {
+        let index = symbols
+            .iter()
+            .position(|s| s.name == relocation.symbol)
+            .map_or(0, |n| n + 2) as u64;
+        let kind: u64 = match relocation.kind {
+            RelocationKind::Pc32 => 2,
+            RelocationKind::Plt32 => 4,
+        };
+        rela.extend(relocation.offset.to_le_bytes());
+        rela.extend(((index << 32) | kind).to_le_bytes());
+        rela.extend(relocation.addend.to_le_bytes());
+    }
+
+    let mut out = vec![0; EHDR_SIZE];
+    let mut headers = vec![];
+    let mut section = |out: &mut Vec<u8>, name: &str, kind, flags, data: &[u8], align: usize| {
+        while !out.len().is_multiple_of(align) {
+            out.push(0);
+        }
+        let header = SectionHeader {
+            name: shstrtab.add(name),
+            kind,
+            flags,
+            offset: out.len(),
+            size: data.len(),
+            link: 0,
+            info: 0,
+            align: align as u64,
+            entsize: 0,
+        };
+        out.extend_from_slice(data);
+        header
+    };
+
+    headers.push(section(
+        &mut out,
+        ".text",
+        SHT_PROGBITS,
+        SHF_ALLOC | SHF_EXECINSTR,
+        &object.text,
+        16,
+    ));
+    headers.push(SectionHeader {
+        link: SYMTAB,
+        info: TEXT,
+        entsize: RELA_SIZE as u64,
+        ..section(&mut out, ".rela.text", SHT_RELA, SHF_INFO_LINK, &rela, 8)
+    });
+    headers.push(SectionHeader {
+        link: STRTAB,
+        info: first_global as u32,
+        entsize: SYM_SIZE as u64,
+        ..section(&mut out, ".symtab", SHT_SYMTAB, 0, &symtab, 8)
+    });
+    headers.push(section(
+        &mut out,
+        ".strtab",
+        SHT_STRTAB,
+        0,
+        &strtab.bytes,
+        1,
+    ));
+    // marks the stack as non-executable
+    headers.push(section(
+        &mut out,
+        ".note.GNU-stack",
+        SHT_PROGBITS,
+        0,
+        &[],
+        1,
+    ));
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    let shstrtab_offset = out.len();
+    out.extend_from_slice(&shstrtab.bytes);
+    headers.insert(
+        SHSTRTAB as usize - 1,
+        SectionHeader {
+            name: shstrtab_name,
+            kind: SHT_STRTAB,
+            flags: 0,
+            offset: shstrtab_offset,
+            size: shstrtab.bytes.len(),
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        },
+    );
+
+    while !out.len().is_multiple_of(8) {
+        out.push(0);
+    }
+    let shoff = out.len();
+    out.extend([0; SHDR_SIZE]);
+    for header in &headers {
+        out.extend(header.name.to_le_bytes());
+        out.extend(header.kind.to_le_bytes());
+        out.extend(header.flags.to_le_bytes());
+        out.extend(0u64.to_le_bytes());
+        out.extend((header.offset as u64).to_le_bytes());
+        out.extend((header.size as u64).to_le_bytes());
+        out.extend(header.link.to_le_bytes());
+        out.extend(header.info.to_le_bytes());
+        out.extend(header.align.to_le_bytes());
+        out.extend(header.entsize.to_le_bytes());
+    }
+
+    let mut ehdr = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    ehdr.extend(1u16.to_le_bytes()); // ET_REL
+    ehdr.extend(62u16.to_le_bytes()); // EM_X86_64
+    ehdr.extend(1u32.to_le_bytes());
+    ehdr.extend(0u64.to_le_bytes()); // entry
+    ehdr.extend(0u64.to_le_bytes()); // program headers
+    ehdr.extend((shoff as u64).to_le_bytes());
+    ehdr.extend(0u32.to_le_bytes());
+    ehdr.extend((EHDR_SIZE as u16).to_le_bytes());
+    ehdr.extend(0u16.to_le_bytes());
+    ehdr.extend(0u16.to_le_bytes());
+    ehdr.extend((SHDR_SIZE as u16).to_le_bytes());
+    ehdr.extend(SECTION_COUNT.to_le_bytes());
+    ehdr.extend((SHSTRTAB as u16).to_le_bytes());
+    out[..EHDR_SIZE].copy_from_slice(&ehdr);
+
+    out
+}
+
+fn symbol_entry(name: u32, bind: u8, kind: u8, shndx: u16, value: u64) -> Vec<u8> {
+    let mut entry = vec![];
+    entry.extend(name.to_le_bytes());
+    entry.push((bind << 4) | kind);
+    entry.push(0);
+    entry.extend(shndx.to_le_bytes());
+    entry.extend(value.to_le_bytes());
+    entry.extend(0u64.to_le_bytes());
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn write_elf_test() {
+        let object = Object {
+            text: vec![0xe8, 0, 0, 0, 0, 0xc3],
+            symbols: vec![
+                Symbol {
+                    name: "main".to_owned(),
+                    global: true,
+                    value: Some(0),
+                },
+                Symbol {
+                    name: "foo".to_owned(),
+                    global: true,
+                    value: None,
+                },
+            ],
+            relocations: vec![Relocation {
+                offset: 1,
+                symbol: "foo".to_owned(),
+                kind: RelocationKind::Plt32,
+                addend: -4,
+            }],
+        };
+        let elf = write_elf(&object);
+
+        assert_eq!(b"\x7fELF", &elf[..4]);
+        assert_eq!(1, u16_at(&elf, 16));
+        assert_eq!(62, u16_at(&elf, 18));
+        assert_eq!(SECTION_COUNT, u16_at(&elf, 60));
+
+        let shoff = u64_at(&elf, 40) as usize;
+        let text = shoff + SHDR_SIZE * TEXT as usize;
+        let text_offset = u64_at(&elf, text + 24) as usize;
+        assert_eq!(object.text, elf[text_offset..text_offset + 6]);
+
+        let rela = shoff + SHDR_SIZE * 2;
+        let rela_offset = u64_at(&elf, rela + 24) as usize;
+        assert_eq!(1, u64_at(&elf, rela_offset));
+        // foo is the fourth entry: null, .text, main, foo
+        assert_eq!((3 << 32) | 4, u64_at(&elf, rela_offset + 8));
+        assert_eq!(-4, u64_at(&elf, rela_offset + 16) as i64);
+    }
+}
@@ -1,9 +1,10 @@
 pub fn invalid_char_error(source: &str, c: char) -> String {
     let source_split: Vec<String> = source.split('\n').map(|s| s.to_string()).collect();
-    let irregular_line = match source_split.clone().into_iter().find(|s| s.contains(c)) {
-        Some(s) => s,
-        None => String::from(""),
-    };
+    let irregular_line = source_split
+        .clone()
+        .into_iter()
+        .find(|s| s.contains(c))
+        .unwrap_or_default();
     let irregular_line_num = source_split
         .into_iter()
         .position(|s| s.contains(c))
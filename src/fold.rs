@@ -0,0 +1,202 @@
+use crate::interp::Value;
+use crate::ir::{BinOp, Function, Inst, VReg};
+use crate::ssa::reverse_postorder;
+
+/// What folding knows about the value of a virtual register.
+#[derive(Clone, Copy)]
+enum Known {
+    Nothing,
+    Const(Value),
+    /// the same value as another register
+    Copy(VReg),
+    /// `0 - vreg`, which is how `-x` is lowered
+    Negation(VReg),
+}
+
+/// Evaluates operations on constants and simplifies algebraic identities like `x * 1`, `x - x`
+/// and `-(-x)`, rewriting them to constants and copies. Needs SSA form, where a register has the
+/// one value its definition gives it.
+///
+/// Arithmetic wraps like it does at run time. Divisions that trap, by zero or of the smallest
+/// value by -1, are left for the program to perform.
+pub fn fold(function: &mut Function) {
+    let mut known = vec![Known::Nothing; function.vregs];
+
+    // definitions come before their uses in reverse postorder, except for phis
+    for block in reverse_postorder(function) {
+        for inst in &mut function.blocks[block].insts {
+            let folded = match *inst {
+                Inst::Const { value, .. } => Known::Const(value),
+                Inst::Copy { src, .. } => resolve(&known, src),
+                Inst::Binary { op, lhs, rhs, .. } => simplify(&known, op, lhs, rhs),
+                _ => Known::Nothing,
+            };
+            let dst = match *inst {
+                Inst::Const { dst, .. } | Inst::Copy { dst, .. } | Inst::Binary { dst, .. } => dst,
+                _ => continue,
+            };
+            match folded {
+                Known::Const(value) => *inst = Inst::Const { dst, value },
+                Known::Copy(src) => *inst = Inst::Copy { dst, src },
+                _ => {}
+            }
+            known[dst] = folded;
+        }
+    }
+}
+
+/// Follows copies to where the value comes from.
+fn resolve(known: &[Known], vreg: VReg) -> Known {
+    match known[vreg] {
+        Known::Nothing | Known::Negation(_) => Known::Copy(vreg),
+        known => known,
+    }
+}
+
+fn simplify(known: &[Known], op: BinOp, lhs: VReg, rhs: VReg) -> Known {
+    let (lhs, rhs) = (resolve(known, lhs), resolve(known, rhs));
+    let same = match (lhs, rhs) {
+        (Known::Copy(l), Known::Copy(r)) => l == r,
+        _ => false,
+    };
+
+    match (op, lhs, rhs) {
+        (BinOp::Div, Known::Const(l), Known::Const(r))
+            if r == 0 || (l == Value::MIN && r == -1) =>
+        {
+            Known::Nothing
+        }
+        (op, Known::Const(l), Known::Const(r)) => match op.eval(l, r) {
+            Ok(value) => Known::Const(value),
+            Err(_) => Known::Nothing,
+        },
+        (BinOp::Add | BinOp::Sub, x, Known::Const(0)) => x,
+        (BinOp::Add, Known::Const(0), x) => x,
+        (BinOp::Mul | BinOp::Div, x, Known::Const(1)) => x,
+        (BinOp::Mul, Known::Const(1), x) => x,
+        (BinOp::Mul, _, Known::Const(0)) | (BinOp::Mul, Known::Const(0), _) => Known::Const(0),
+        (BinOp::Sub, Known::Const(0), Known::Copy(x)) => match known[x] {
+            Known::Negation(y) => resolve(known, y),
+            _ => Known::Negation(x),
+        },
+        (BinOp::Sub | BinOp::Ne | BinOp::Lt, _, _) if same => Known::Const(0),
+        (BinOp::Eq | BinOp::Le, _, _) if same => Known::Const(1),
+        _ => Known::Nothing,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{assert_matches_interpreter, lower_source};
+    use crate::ssa::to_ssa;
+
+    fn fold_ssa(function: &mut Function) {
+        to_ssa(function);
+        fold(function);
+    }
+
+    fn fold_source(src: &str) -> Function {
+        let mut function = lower_source(src);
+        fold_ssa(&mut function);
+        function
+    }
+
+    /// The instruction that computes what the program returns.
+    fn result(function: &Function) -> Inst {
+        let block = function.blocks.last().unwrap();
+        let value = match block.terminator {
+            crate::ir::Terminator::Return(value) => value,
+            _ => panic!("the last block returns"),
+        };
+        let defines = |inst: &&Inst| match inst {
+            Inst::Const { dst, .. } | Inst::Copy { dst, .. } | Inst::Binary { dst, .. } => {
+                *dst == value
+            }
+            _ => false,
+        };
+        function
+            .blocks
+            .iter()
+            .flat_map(|block| &block.insts)
+            .find(defines)
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn constant_test() {
+        assert!(matches!(
+            result(&fold_source("5 + 6 * 7;")),
+            Inst::Const { value: 47, .. }
+        ));
+        assert!(matches!(
+            result(&fold_source("-3 - 4 == 0 - 7;")),
+            Inst::Const { value: 1, .. }
+        ));
+        assert!(matches!(
+            result(&fold_source("return 1 / 0;")),
+            Inst::Binary { op: BinOp::Div, .. }
+        ));
+    }
+
+    #[test]
+    fn identity_test() {
+        let x = |src: &str| {
+            let function = fold_source(src);
+            // `x` is the only call, and so the value everything should come down to
+            let call = function.blocks[0]
+                .insts
+                .iter()
+                .find_map(|inst| match inst {
+                    Inst::Call { dst, .. } => Some(*dst),
+                    _ => None,
+                })
+                .unwrap();
+            (result(&function), call)
+        };
+
+        for src in [
+            "a = x(); a * 1;",
+            "a = x(); 1 * a + 0;",
+            "a = x(); a / 1 - 0;",
+            "a = x(); -(-a);",
+            "a = x(); b = 0 - a; 0 - b;",
+        ] {
+            let (result, call) = x(src);
+            assert!(
+                matches!(result, Inst::Copy { src, .. } if src == call),
+                "{}",
+                src
+            );
+        }
+        for (value, src) in [
+            (0, "a = x(); a - a;"),
+            (0, "a = x(); a * 0;"),
+            (1, "a = x(); a <= a;"),
+            (0, "a = x(); a != a;"),
+        ] {
+            assert!(
+                matches!(x(src).0, Inst::Const { value: v, .. } if v == value),
+                "{}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn semantics_test() {
+        let cases = [
+            "a = 3; b = 5*6 - 8; a + b / 2;",
+            "i = 1; while(i < 3) i = i*2;i;",
+            "x = 0 - 7; x / 2 == 0 - 3;",
+            "a = 5; b = a - a; c = b * 100 + 3 * 1; c + -(-a);",
+            "s = 0; for (i = 0; i < 4; i = i + 1) s = s + i * 1 + 0; s;",
+            "1 - 2 * 3 <= 0 - 5;",
+        ];
+
+        for src in cases {
+            assert_matches_interpreter(src, fold_ssa);
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use crate::token::Token;
+
+const INDENT: &str = "    ";
+const MAX_WIDTH: usize = 80;
+/// Continuation lines of a wrapped statement are indented this many extra levels.
+const CONTINUATION: usize = 2;
+
+pub fn format(tokens: &[Token]) -> String {
+    let mut formatter = Formatter {
+        tokens,
+        out: String::new(),
+        line: Line::default(),
+        line_done: false,
+        continuation: false,
+        at_block_start: true,
+        stack: vec![],
+        paren_depth: 0,
+        header: None,
+        header_open: false,
+        block_if_body: false,
+        prev: None,
+        prev_sign: false,
+        after_comment: false,
+    };
+    formatter.run();
+    formatter.out
+}
+
+fn token_str(token: &Token) -> String {
+    match token {
+        Token::Integer(n) => n.to_string(),
+        Token::Ident(ident) => ident.name.clone(),
+        Token::Variable { name, .. } => name.clone(),
+        Token::Return => "return".to_owned(),
+        Token::If => "if".to_owned(),
+        Token::Else => "else".to_owned(),
+        Token::While => "while".to_owned(),
+        Token::For => "for".to_owned(),
+        Token::Semicolon => ";".to_owned(),
+        Token::Equality => "==".to_owned(),
+        Token::Equal => "=".to_owned(),
+        Token::Noneequality => "!=".to_owned(),
+        Token::LessOrEqual => "<=".to_owned(),
+        Token::Less => "<".to_owned(),
+        Token::GreaterOrEqual => ">=".to_owned(),
+        Token::Greater => ">".to_owned(),
+        Token::Add => "+".to_owned(),
+        Token::Sub => "-".to_owned(),
+        Token::Mul => "*".to_owned(),
+        Token::Div => "/".to_owned(),
+        Token::LParen => "(".to_owned(),
+        Token::RParen => ")".to_owned(),
+        Token::OpenBrace => "{".to_owned(),
+        Token::CloseBrace => "}".to_owned(),
+        Token::Comment(s) => s.clone(),
+        Token::Newline => "\n".to_owned(),
+    }
+}
+
+fn is_binary_operator(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Equality
+            | Token::Equal
+            | Token::Noneequality
+            | Token::LessOrEqual
+            | Token::Less
+            | Token::GreaterOrEqual
+            | Token::Greater
+            | Token::Add
+            | Token::Sub
+            | Token::Mul
+            | Token::Div
+    )
+}
+
+/// `+` and `-` are signs unless they follow something that ends an operand.
+fn is_sign(prev: Option<&Token>, token: &Token) -> bool {
+    matches!(token, Token::Add | Token::Sub)
+        && !matches!(
+            prev,
+            Some(Token::Integer(_) | Token::Ident(_) | Token::Variable { .. } | Token::RParen)
+        )
+}
+
+enum Frame {
+    Block {
+        if_body: bool,
+    },
+    /// the single statement of an `if`/`else`/`while`/`for` written without braces
+    Body {
+        if_body: bool,
+    },
+}
+
+#[derive(Default)]
+struct Line {
+    text: String,
+    indent: usize,
+    /// positions of the space before a binary operator, with their parenthesis depth
+    breaks: Vec<(usize, usize)>,
+    comment: Option<String>,
+}
+
+struct Formatter<'a> {
+    tokens: &'a [Token],
+    out: String,
+    line: Line,
+    line_done: bool,
+    continuation: bool,
+    at_block_start: bool,
+    stack: Vec<Frame>,
+    paren_depth: usize,
+    header: Option<Token>,
+    header_open: bool,
+    block_if_body: bool,
+    prev: Option<Token>,
+    prev_sign: bool,
+    /// the line ends in a block comment written between two tokens
+    after_comment: bool,
+}
+
+impl Formatter<'_> {
+    fn run(&mut self) {
+        let mut newlines = 0;
+        for i in 0..self.tokens.len() {
+            match &self.tokens[i] {
+                Token::Newline => newlines += 1,
+                Token::Comment(comment) => {
+                    self.comment(comment, newlines);
+                    newlines = 0;
+                }
+                token => {
+                    self.token(i, token, newlines);
+                    newlines = 0;
+                }
+            }
+        }
+        self.flush();
+    }
+
+    fn next_token(&self, i: usize) -> Option<&Token> {
+        self.tokens[i + 1..].iter().find(|t| !t.is_trivia())
+    }
+
+    fn flush(&mut self) {
+        if self.line.text.is_empty() && self.line.comment.is_none() {
+            return;
+        }
+        let line = std::mem::take(&mut self.line);
+        let mut start = 0;
+        let mut indent = line.indent;
+        loop {
+            let avail = MAX_WIDTH.saturating_sub(indent * INDENT.len());
+            let rest = &line.text[start..];
+            let wrap = if rest.len() <= avail {
+                None
+            } else {
+                line.breaks
+                    .iter()
+                    .filter(|(pos, _)| *pos > start && *pos - start <= avail)
+                    .min_by_key(|(pos, depth)| (*depth, usize::MAX - *pos))
+            };
+            self.out.push_str(&INDENT.repeat(indent));
+            match wrap {
+                Some((pos, _)) => {
+                    self.out.push_str(&line.text[start..*pos]);
+                    self.out.push('\n');
+                    start = pos + 1;
+                    indent = line.indent + CONTINUATION;
+                }
+                None => {
+                    self.out.push_str(rest);
+                    break;
+                }
+            }
+        }
+        if let Some(comment) = line.comment {
+            if !line.text.is_empty() {
+                self.out.push(' ');
+            }
+            self.out.push_str(&comment);
+        }
+        self.out.push('\n');
+        self.line_done = false;
+    }
+
+    fn blank_line(&mut self, newlines: usize) {
+        if newlines >= 2 && !self.at_block_start {
+            self.out.push('\n');
+        }
+    }
+
+    fn start_line(&mut self) {
+        self.line.indent = self.stack.len();
+        if self.continuation {
+            self.line.indent += CONTINUATION;
+            self.continuation = false;
+        }
+    }
+
+    fn comment(&mut self, comment: &str, newlines: usize) {
+        // a block comment between the tokens of a statement stays between them
+        let inline = newlines == 0
+            && !self.line_done
+            && !self.line.text.is_empty()
+            && comment.starts_with("/*")
+            && !comment.contains('\n');
+        if inline {
+            if self.prev != Some(Token::LParen) {
+                self.line.text.push(' ');
+            }
+            self.line.text.push_str(comment);
+            self.after_comment = true;
+            return;
+        }
+
+        let trailing = newlines == 0 && (self.line_done || !self.line.text.is_empty());
+        if trailing {
+            self.line.comment = Some(comment.to_owned());
+            if !self.line_done {
+                self.continuation = true;
+            }
+            self.flush();
+            return;
+        }
+
+        let mid_statement = !self.line_done && !self.line.text.is_empty();
+        self.flush();
+        self.blank_line(newlines);
+        self.start_line();
+        self.line.comment = Some(comment.to_owned());
+        self.flush();
+        self.continuation = mid_statement;
+        self.at_block_start = false;
+    }
+
+    fn push(&mut self, token: &Token) {
+        if self.line.text.is_empty() {
+            self.start_line();
+        } else if self.needs_space(token)
+            || (self.after_comment && !matches!(token, Token::Semicolon | Token::RParen))
+        {
+            if is_binary_operator(token) && !is_sign(self.prev.as_ref(), token) {
+                self.line
+                    .breaks
+                    .push((self.line.text.len(), self.paren_depth));
+            }
+            self.line.text.push(' ');
+        }
+        self.line.text.push_str(&token_str(token));
+        self.after_comment = false;
+        self.prev_sign = is_sign(self.prev.as_ref(), token);
+        self.prev = Some(token.clone());
+        self.at_block_start = false;
+    }
+
+    fn needs_space(&self, token: &Token) -> bool {
+        let prev = match &self.prev {
+            Some(prev) => prev,
+            None => return false,
+        };
+        if matches!(token, Token::Semicolon | Token::RParen) || *prev == Token::LParen {
+            return false;
+        }
+        if self.prev_sign {
+            return false;
+        }
+        match token {
+            Token::LParen => !matches!(prev, Token::Ident(_) | Token::Variable { .. }),
+            _ => true,
+        }
+    }
+
+    /// Called when a statement ends: closes every braceless body that the statement completed.
+    fn end_statement(&mut self, i: usize) {
+        self.line_done = true;
+        while let Some(Frame::Body { if_body }) = self.stack.last() {
+            let if_body = *if_body;
+            self.stack.pop();
+            if if_body && self.next_token(i) == Some(&Token::Else) {
+                break;
+            }
+        }
+    }
+
+    fn open_body(&mut self, i: usize, if_body: bool) {
+        match self.next_token(i) {
+            Some(Token::OpenBrace) => self.block_if_body = if_body,
+            Some(Token::If) if self.tokens[i] == Token::Else => {}
+            _ => {
+                self.line_done = true;
+                self.stack.push(Frame::Body { if_body });
+            }
+        }
+    }
+
+    fn token(&mut self, i: usize, token: &Token, newlines: usize) {
+        if self.line_done {
+            self.flush();
+        }
+        if self.line.text.is_empty() && *token != Token::CloseBrace {
+            self.blank_line(newlines);
+        }
+
+        match token {
+            Token::OpenBrace => {
+                self.push(token);
+                let if_body = std::mem::take(&mut self.block_if_body);
+                self.stack.push(Frame::Block { if_body });
+                self.line_done = true;
+                self.at_block_start = true;
+            }
+            Token::CloseBrace => {
+                self.flush();
+                let mut if_body = false;
+                while let Some(frame) = self.stack.pop() {
+                    if let Frame::Block { if_body: b } = frame {
+                        if_body = b;
+                        break;
+                    }
+                }
+                self.prev = None;
+                self.push(token);
+                if !(if_body && self.next_token(i) == Some(&Token::Else)) {
+                    self.end_statement(i);
+                }
+            }
+            Token::Semicolon if self.paren_depth == 0 => {
+                self.push(token);
+                self.end_statement(i);
+            }
+            Token::If | Token::While | Token::For if self.paren_depth == 0 => {
+                self.push(token);
+                self.header = Some(token.clone());
+            }
+            Token::Else => {
+                self.push(token);
+                self.open_body(i, false);
+            }
+            Token::LParen => {
+                self.push(token);
+                self.paren_depth += 1;
+                if self.paren_depth == 1 && self.header.is_some() {
+                    self.header_open = true;
+                }
+            }
+            Token::RParen => {
+                self.push(token);
+                self.paren_depth = self.paren_depth.saturating_sub(1);
+                if self.paren_depth == 0 && self.header_open {
+                    self.header_open = false;
+                    let if_body = self.header.take() == Some(Token::If);
+                    self.open_body(i, if_body);
+                }
+            }
+            _ => self.push(token),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lexer_with_trivia;
+
+    fn fmt(src: &str) -> String {
+        format(&lexer_with_trivia(src).unwrap())
+    }
+
+    #[test]
+    fn spacing_test() {
+        assert_eq!("a = -1 + 2 * (b - 3);\n", fmt("a=-1+2*(b-3) ;"));
+        assert_eq!("return -(a) / foo();\n", fmt("return -( a )/foo ( ) ;"));
+        assert_eq!("a = b - -c;\n", fmt("a=b- -c;"));
+    }
+
+    #[test]
+    fn indent_test() {
+        assert_eq!(
+            "if (a < 1) {\n    a = 1;\n} else if (a == 1)\n    return a;\nelse\n    a = 2;\n",
+            fmt("if(a<1){a=1;}else if(a==1)return a;else a=2;")
+        );
+        assert_eq!(
+            "for (i = 0; i < 10; i = i + 1)\n    while (i)\n        i = i - 1;\ni;\n",
+            fmt("for(i=0;i<10;i=i+1) while(i) i=i-1; i;")
+        );
+        assert_eq!(
+            "if (a)\n    if (b)\n        1;\n    else\n        2;\n3;\n",
+            fmt("if(a)if(b)1;else 2;3;")
+        );
+        assert_eq!("{\n    {\n        1;\n    }\n}\n", fmt("{{1;}}"));
+    }
+
+    #[test]
+    fn comment_test() {
+        assert_eq!(
+            "// head\na = 1; // one\n\n/* two */\nb = 2;\n",
+            fmt("// head\na=1;   // one\n\n\n  /* two */\nb=2;")
+        );
+        assert_eq!(
+            "a = 1; // one\n\nb = 2;\n",
+            fmt("a = 1; // one\n\n\nb = 2;")
+        );
+        assert_eq!(
+            "if (a) { // then\n    b;\n}\n",
+            fmt("if (a) { // then\nb;}")
+        );
+        assert_eq!("a = 1 /* x */ + 2;\n", fmt("a = 1 /* x */ + 2;"));
+        assert_eq!("f(/* x */ a) /* y */;\n", fmt("f(/* x */a)/* y */;"));
+    }
+
+    #[test]
+    fn wrap_test() {
+        let src = "result = first_value * second_value + third_value * fourth_value + (fifth_value - sixth_value);";
+
+        assert_eq!(
+            "result = first_value * second_value + third_value * fourth_value\n        + (fifth_value - sixth_value);\n",
+            fmt(src)
+        );
+    }
+
+    #[test]
+    fn idempotent_test() {
+        let src = "num=0;\nfor(i=1;i<=10;i=i+1){ // loop\nnum=num+1;\n\n\nif(i==2){num=num+1;}else{num=num+2;}}\nreturn num;";
+        let once = fmt(src);
+
+        assert_eq!(once, fmt(&once));
+    }
+}
@@ -1,129 +1,97 @@
-use crate::architecture::*;
+use crate::architecture::{Backend, Frame};
+use crate::ir::{lower, Function};
+use crate::passes::{Pass, Pipeline};
+use crate::regalloc::Registers;
 use crate::tree::*;
+use crate::warning::Warning;
 
 pub type GenerateError = String;
 
-pub fn generator(trees: Vec<Tree>, ident_count: usize) -> Result<String, GenerateError> {
-    let mut asm = String::new();
-
-    asm.push_str(&program_prologue());
-    asm.push_str(&main_func());
-    asm.push_str(&memory_allocate(ident_count * 8));
+/// How hard code generation works, from `-O`.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OptLevel {
+    /// every value lives in the frame and the IR goes through no passes
+    #[default]
+    O0,
+    /// values live in registers, by linear scan, and the cheap passes run
+    O1,
+    /// as `-O1`, with every pass
+    O2,
+    /// as `-O1`, with the passes that don't make the code bigger
+    Os,
+}
 
-    let mut flow_count = 0;
-    for tree in trees {
-        let (str, count) = generate_assembly(tree, flow_count)?;
-        flow_count = count;
-        asm.push_str(&str);
-        asm.push_str(&stmt_epilogue());
+impl OptLevel {
+    pub fn parse(s: &str) -> Result<OptLevel, &'static str> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            "s" => Ok(OptLevel::Os),
+            _ => Err("unsupported optimization level"),
+        }
     }
 
-    asm.push_str(&program_epilogue());
-
-    Ok(asm)
+    /// The passes the level runs over the IR when `--passes` doesn't say.
+    pub fn passes(&self) -> Vec<Pass> {
+        use Pass::*;
+        match self {
+            OptLevel::O0 => vec![],
+            OptLevel::O1 => vec![DeadStores, TailCalls, Fold, DeadCode],
+            OptLevel::O2 => vec![DeadStores, TailCalls, Fold, Gvn, Loops, Fold, Gvn, DeadCode],
+            OptLevel::Os => vec![DeadStores, TailCalls, Fold, Gvn, DeadCode],
+        }
+    }
 }
 
-fn generate_val(name: &str, offset: usize) -> String {
-    gen_val(name, offset)
+/// What `-O`, `--passes`, `--print-after` and `--time-passes` ask of code generation.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Options {
+    pub opt_level: OptLevel,
+    pub pipeline: Pipeline,
 }
 
-pub fn generate_assembly(tree: Tree, flow_count: usize) -> Result<(String, usize), GenerateError> {
-    match tree {
-        Tree::None => Ok((String::new(), flow_count)),
-        Tree::Int(n) => Ok((push(Operand::Num(n)), flow_count)),
-        Tree::Val { name, offset } => Ok((
-            format!("{}{}", generate_val(&name, offset), pop_val()),
-            flow_count,
-        )),
-        Tree::Return(t) => {
-            let (asm, flow_count) = generate_assembly(*t, flow_count)?;
-            Ok((format!("{}{}", asm, gen_ret()), flow_count))
-        }
-        Tree::If(expr, stmt) => {
-            let (expr, flow_count) = generate_assembly(*expr, flow_count)?;
-            let (stmt, flow_count) = generate_assembly(*stmt, flow_count)?;
-            Ok((
-                gen_if(&expr, &stmt, flow_count + 1).to_string(),
-                flow_count + 1,
-            ))
-        }
-        Tree::IfElse(expr, stmt, stmt_else) => {
-            let (expr, flow_count) = generate_assembly(*expr, flow_count)?;
-            let (stmt, flow_count) = generate_assembly(*stmt, flow_count)?;
-            let (stmt_else, flow_count) = generate_assembly(*stmt_else, flow_count)?;
-            Ok((
-                gen_if_else(&expr, &stmt, &stmt_else, flow_count + 1).to_string(),
-                flow_count + 1,
-            ))
-        }
-        Tree::While(expr, stmt) => {
-            let (expr, flow_count) = generate_assembly(*expr, flow_count)?;
-            let (stmt, flow_count) = generate_assembly(*stmt, flow_count)?;
-            Ok((
-                gen_while(&expr, &stmt, flow_count + 1).to_string(),
-                flow_count + 1,
-            ))
+impl Options {
+    /// The level with the passes it runs.
+    pub fn new(opt_level: OptLevel) -> Options {
+        Options {
+            opt_level,
+            pipeline: Pipeline::new(opt_level.passes()),
         }
-        Tree::For(init_expr, cond_expr, loop_expr, stmt) => {
-            let (init_expr, flow_count) = generate_assembly(*init_expr, flow_count)?;
-            let (cond_expr, flow_count) = generate_assembly(*cond_expr, flow_count)?;
-            let (loop_expr, flow_count) = generate_assembly(*loop_expr, flow_count)?;
-            let (stmt, flow_count) = generate_assembly(*stmt, flow_count)?;
-            Ok((
-                gen_for(&init_expr, &cond_expr, &loop_expr, &stmt, flow_count + 1).to_string(),
-                flow_count + 1,
-            ))
-        }
-        Tree::Block(trees) => {
-            let mut asm = String::new();
-            let mut count = flow_count;
-            for tree in trees {
-                let (str, n) = generate_assembly(tree, count)?;
-                asm = format!("{}{}", asm, str);
-                count = n;
-            }
-            Ok((asm, count))
-        }
-        Tree::Node(kind, lhs, rhs) => {
-            let mut node_str = String::new();
-            if let NodeKind::Assign = kind {
-                let mut str = String::new();
-                if let Tree::Val { name, offset } = *lhs {
-                    str.push_str(&generate_val(&name, offset));
-                } else {
-                    return Err(
-                        "The left-hand side value of the assignment is not a variable".to_owned(),
-                    );
-                }
-                let (asm, flow_count) = generate_assembly(*rhs, flow_count)?;
-                str.push_str(&asm);
-                str.push_str(&pop_lvar());
-                return Ok((str, flow_count));
-            }
-
-            let (asm, flow_count) = generate_assembly(*lhs, flow_count)?;
-            node_str.push_str(&asm);
-            let (asm, flow_count) = generate_assembly(*rhs, flow_count)?;
-            node_str.push_str(&asm);
+    }
+}
 
-            node_str.push_str(&pop_arg());
+/// The IR the backends emit: lowered, through the passes of `pipeline` and out of SSA form, with
+/// warnings about the code the passes removed.
+pub fn function(
+    trees: &[Tree],
+    ident_count: usize,
+    pipeline: &Pipeline,
+) -> Result<(Function, Vec<Warning>), GenerateError> {
+    let mut function = lower(trees, ident_count)?;
+    let warnings = pipeline.run(&mut function);
+    Ok((function, warnings))
+}
 
-            match kind {
-                NodeKind::Equality => node_str.push_str(&eq_arg()),
-                NodeKind::Nonequality => node_str.push_str(&neq_arg()),
-                NodeKind::Less => node_str.push_str(&less_arg()),
-                NodeKind::LessOrEqual => node_str.push_str(&less_or_eq_arg()),
-                NodeKind::Add => node_str.push_str(&add_arg()),
-                NodeKind::Sub => node_str.push_str(&sub_arg()),
-                NodeKind::Mul => node_str.push_str(&mul_arg()),
-                NodeKind::Div => node_str.push_str(&div_arg()),
-                _ => {
-                    return Err("unexpected node".to_owned());
-                }
-            }
-            node_str.push_str(&push(Operand::Register(Register::R0)));
-            Ok((node_str, flow_count))
-        }
-        Tree::Func { name } => Ok((gen_func(&name), flow_count)),
-    }
+pub fn generator(
+    trees: Vec<Tree>,
+    ident_count: usize,
+    backend: &dyn Backend,
+    options: &Options,
+) -> Result<(String, Vec<Warning>), GenerateError> {
+    let (function, warnings) = function(&trees, ident_count, &options.pipeline)?;
+    let registers = match options.opt_level {
+        OptLevel::O0 => Registers::default(),
+        _ => backend.registers(),
+    };
+    let asm = format!(
+        "{}{}",
+        backend.program_prologue(),
+        backend.function(
+            &function,
+            &Frame::new(&function, registers),
+            options.opt_level
+        )
+    );
+    Ok((asm, warnings))
 }
@@ -0,0 +1,207 @@
+use crate::interp::Value;
+use crate::ir::{BinOp, BlockId, Function, Inst, VReg};
+use crate::ssa::dominators;
+use std::collections::HashMap;
+
+/// What an instruction computes, in terms of the value numbers of its operands.
+#[derive(PartialEq, Eq, Hash, Clone)]
+enum Expr {
+    Const(Value),
+    Binary(BinOp, VReg, VReg),
+}
+
+enum Visit {
+    Enter(BlockId),
+    /// forgets the expressions the block made available
+    Exit(Vec<Expr>),
+}
+
+/// Numbers the values of the function so that each expression is computed once where it is
+/// available, by global value numbering over the dominator tree. A copy is replaced by its
+/// source everywhere, and so is an instruction computing what one of its dominators already has,
+/// and a phi whose arguments are all the same value. Needs SSA form, where the same operands
+/// give the same result.
+///
+/// Calls are never the same twice. A division repeated where one has already run can go, since
+/// it would have trapped the first time.
+pub fn gvn(function: &mut Function) {
+    let idom = dominators(function);
+    let mut children = vec![vec![]; function.blocks.len()];
+    for (block, parent) in idom.iter().enumerate().skip(1) {
+        if let Some(parent) = parent {
+            children[*parent].push(block);
+        }
+    }
+
+    // the value number of each register: the register that first computed its value
+    let mut numbers: Vec<VReg> = (0..function.vregs).collect();
+    let mut numbered = vec![false; function.vregs];
+    let mut available: HashMap<Expr, VReg> = HashMap::new();
+    let mut work = vec![Visit::Enter(0)];
+    while let Some(visit) = work.pop() {
+        let block = match visit {
+            Visit::Enter(block) => block,
+            Visit::Exit(made) => {
+                for expr in made {
+                    available.remove(&expr);
+                }
+                continue;
+            }
+        };
+
+        let mut made = vec![];
+        function.blocks[block].insts.retain_mut(|inst| {
+            for operand in inst.operands_mut() {
+                *operand = numbers[*operand];
+            }
+            if let Some(dst) = inst.dst() {
+                numbered[dst] = true;
+            }
+            let (dst, expr) = match *inst {
+                Inst::Copy { dst, src } => {
+                    numbers[dst] = src;
+                    return false;
+                }
+                // the arguments from back edges may not be numbered yet, and then they count as
+                // different
+                Inst::Phi { dst, ref args } => {
+                    let mut values = args.iter().map(|(_, value)| *value).filter(|v| *v != dst);
+                    match values.next() {
+                        Some(first) if numbered[first] && values.all(|value| value == first) => {
+                            numbers[dst] = first;
+                            return false;
+                        }
+                        _ => return true,
+                    }
+                }
+                Inst::Const { dst, value } => (dst, Expr::Const(value)),
+                Inst::Binary { op, dst, lhs, rhs } => {
+                    let commutes = matches!(op, BinOp::Add | BinOp::Mul | BinOp::Eq | BinOp::Ne);
+                    match commutes && rhs < lhs {
+                        true => (dst, Expr::Binary(op, rhs, lhs)),
+                        false => (dst, Expr::Binary(op, lhs, rhs)),
+                    }
+                }
+                _ => return true,
+            };
+            match available.get(&expr) {
+                Some(&number) => {
+                    numbers[dst] = number;
+                    false
+                }
+                None => {
+                    available.insert(expr.clone(), dst);
+                    made.push(expr);
+                    true
+                }
+            }
+        });
+        work.push(Visit::Exit(made));
+        work.extend(children[block].iter().rev().map(|c| Visit::Enter(*c)));
+    }
+
+    // the uses the walk reached before their definitions, along back edges
+    for block in &mut function.blocks {
+        for inst in &mut block.insts {
+            for operand in inst.operands_mut() {
+                *operand = numbers[*operand];
+            }
+        }
+        for operand in block.terminator.operands_mut() {
+            *operand = numbers[*operand];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fold::fold;
+    use crate::ir::{assert_matches_interpreter, lower_source};
+    use crate::ssa::to_ssa;
+
+    fn number(function: &mut Function) {
+        to_ssa(function);
+        fold(function);
+        gvn(function);
+    }
+
+    fn number_source(src: &str) -> Function {
+        let mut function = lower_source(src);
+        number(&mut function);
+        function
+    }
+
+    fn count(function: &Function, matches: fn(&Inst) -> bool) -> usize {
+        function
+            .blocks
+            .iter()
+            .flat_map(|block| &block.insts)
+            .filter(|inst| matches(inst))
+            .count()
+    }
+
+    fn multiplications(src: &str) -> usize {
+        count(&number_source(src), |inst| {
+            matches!(inst, Inst::Binary { op: BinOp::Mul, .. })
+        })
+    }
+
+    #[test]
+    fn common_subexpression_test() {
+        assert_eq!(1, multiplications("a = x(); b = y(); a * b + a * b;"));
+        assert_eq!(1, multiplications("a = x(); b = y(); a * b - b * a;"));
+        assert_eq!(
+            1,
+            multiplications("a = x(); b = y(); c = a * b; if (c) c = a * b + 1; c;")
+        );
+        // through copies to the same values
+        assert_eq!(
+            1,
+            multiplications("a = x(); b = a; c = y(); a * c + b * c;")
+        );
+        // neither branch runs before the other
+        assert_eq!(
+            2,
+            multiplications("a = x(); b = y(); if (a) c = a * b; else c = a * b; c;")
+        );
+
+        let function = number_source("a = x() + x(); b = 3; c = 3; a + b + c;");
+        assert_eq!(
+            2,
+            count(&function, |inst| matches!(inst, Inst::Call { .. }))
+        );
+        assert_eq!(
+            1,
+            count(&function, |inst| matches!(
+                inst,
+                Inst::Const { value: 3, .. }
+            ))
+        );
+    }
+
+    #[test]
+    fn copy_propagation_test() {
+        let function =
+            number_source("a = x(); b = a; c = b; d = c + 1; s = 0; while (s < d) s = s + c; s;");
+        assert_eq!(
+            0,
+            count(&function, |inst| matches!(inst, Inst::Copy { .. }))
+        );
+    }
+
+    #[test]
+    fn semantics_test() {
+        let cases = [
+            "a = 3; b = 4; a * b + a * b;",
+            "a = 3; b = 4; c = a * b; if (c) c = b * a + 1; c;",
+            "s = 0; for (i = 0; i < 5; i = i + 1) s = s + i * i + i * i; s;",
+            "a = 7; b = a; i = 0; while (i < b) { a = a + i; i = i + 1; } a + b;",
+            "a = 1; if (a) b = 2; else b = 2; b * 3 + 2 * 3;",
+        ];
+
+        for src in cases {
+            assert_matches_interpreter(src, number);
+        }
+    }
+}
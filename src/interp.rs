@@ -0,0 +1,218 @@
+use crate::tree::*;
+
+pub type InterpretError = String;
+
+/// Values are 64-bit like the registers the backends compute in, and wrap on overflow.
+pub type Value = i64;
+
+enum Flow {
+    Normal,
+    Return(Value),
+}
+
+/// Evaluates the program and returns the value it leaves in the result register: the operand of
+/// `return`, or the value of the last expression statement when it falls off the end.
+pub fn interpreter(trees: &[Tree], ident_count: usize) -> Result<Value, InterpretError> {
+    Interpreter::new(ident_count).run(trees)
+}
+
+/// The exit status a process returning `value` from `main` reports.
+pub fn exit_status(value: Value) -> i32 {
+    (value & 0xff) as i32
+}
+
+pub struct Interpreter {
+    /// one 8 byte slot per variable, addressed like `[rbp - offset]`
+    frame: Vec<Value>,
+    last: Value,
+}
+
+impl Interpreter {
+    pub fn new(ident_count: usize) -> Interpreter {
+        Interpreter {
+            frame: vec![0; ident_count],
+            last: 0,
+        }
+    }
+
+    pub fn run(&mut self, trees: &[Tree]) -> Result<Value, InterpretError> {
+        for tree in trees {
+            if let Flow::Return(value) = self.stmt(tree)? {
+                return Ok(value);
+            }
+        }
+        Ok(self.last)
+    }
+
+    /// Runs one statement and returns the operand of the `return` it executed, if any.
+    pub fn exec(&mut self, tree: &Tree) -> Result<Option<Value>, InterpretError> {
+        match self.stmt(tree)? {
+            Flow::Return(value) => Ok(Some(value)),
+            Flow::Normal => Ok(None),
+        }
+    }
+
+    /// The value of the last expression statement.
+    pub fn last(&self) -> Value {
+        self.last
+    }
+
+    pub fn load(&self, offset: usize) -> Result<Value, InterpretError> {
+        match self.slot(offset) {
+            Some(n) => Ok(self.frame[n]),
+            None => Err(format!("offset {} is outside of the stack frame", offset)),
+        }
+    }
+
+    pub fn store(&mut self, offset: usize, value: Value) -> Result<(), InterpretError> {
+        match self.slot(offset) {
+            Some(n) => {
+                self.frame[n] = value;
+                Ok(())
+            }
+            None => Err(format!("offset {} is outside of the stack frame", offset)),
+        }
+    }
+
+    fn slot(&self, offset: usize) -> Option<usize> {
+        match offset / 8 {
+            n if offset.is_multiple_of(8) && n >= 1 && n <= self.frame.len() => Some(n - 1),
+            _ => None,
+        }
+    }
+
+    fn cond(&mut self, tree: &Tree) -> Result<bool, InterpretError> {
+        match tree {
+            Tree::None => Ok(true),
+            tree => Ok(self.expr(tree)? != 0),
+        }
+    }
+
+    fn stmt(&mut self, tree: &Tree) -> Result<Flow, InterpretError> {
+        match tree {
+            Tree::None => {}
+            Tree::Return(expr) => return Ok(Flow::Return(self.expr(expr)?)),
+            Tree::If(expr, stmt) => {
+                if self.cond(expr)? {
+                    return self.stmt(stmt);
+                }
+            }
+            Tree::IfElse(expr, stmt, else_stmt) => {
+                return if self.cond(expr)? {
+                    self.stmt(stmt)
+                } else {
+                    self.stmt(else_stmt)
+                };
+            }
+            Tree::While(expr, stmt) => {
+                while self.cond(expr)? {
+                    if let Flow::Return(value) = self.stmt(stmt)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+            }
+            Tree::For(init_expr, cond_expr, loop_expr, stmt) => {
+                self.stmt(init_expr)?;
+                while self.cond(cond_expr)? {
+                    if let Flow::Return(value) = self.stmt(stmt)? {
+                        return Ok(Flow::Return(value));
+                    }
+                    self.stmt(loop_expr)?;
+                }
+            }
+            Tree::Block(trees) => {
+                for tree in trees {
+                    if let Flow::Return(value) = self.stmt(tree)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+            }
+            expr => self.last = self.expr(expr)?,
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn expr(&mut self, tree: &Tree) -> Result<Value, InterpretError> {
+        match tree {
+            Tree::Int(n) => Ok(*n as Value),
+            Tree::Val { offset, .. } => self.load(*offset),
+            Tree::Func { name } => Err(format!("cannot call external function '{}'", name)),
+            Tree::Node(NodeKind::Assign, lhs, rhs) => {
+                let offset = match **lhs {
+                    Tree::Val { offset, .. } => offset,
+                    _ => {
+                        return Err(
+                            "The left-hand side value of the assignment is not a variable"
+                                .to_owned(),
+                        )
+                    }
+                };
+                let value = self.expr(rhs)?;
+                self.store(offset, value)?;
+                Ok(value)
+            }
+            Tree::Node(kind, lhs, rhs) => {
+                let lhs = self.expr(lhs)?;
+                let rhs = self.expr(rhs)?;
+                binary(kind, lhs, rhs)
+            }
+            _ => Err("unexpected statement in expression".to_owned()),
+        }
+    }
+}
+
+pub fn binary(kind: &NodeKind, lhs: Value, rhs: Value) -> Result<Value, InterpretError> {
+    let value = match kind {
+        NodeKind::Equality => (lhs == rhs) as Value,
+        NodeKind::Nonequality => (lhs != rhs) as Value,
+        NodeKind::Less => (lhs < rhs) as Value,
+        NodeKind::LessOrEqual => (lhs <= rhs) as Value,
+        NodeKind::Add => lhs.wrapping_add(rhs),
+        NodeKind::Sub => lhs.wrapping_sub(rhs),
+        NodeKind::Mul => lhs.wrapping_mul(rhs),
+        NodeKind::Div => match rhs {
+            0 => return Err("division by zero".to_owned()),
+            _ => lhs.wrapping_div(rhs),
+        },
+        NodeKind::Assign => return Err("unexpected node".to_owned()),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::lexer, parser::parser, variable::variable_analysis};
+
+    fn run(src: &str) -> Result<i32, InterpretError> {
+        let (tokens, ident_count) = variable_analysis(lexer(src).unwrap()).unwrap();
+        let trees = parser(tokens).unwrap();
+        interpreter(&trees, ident_count).map(exit_status)
+    }
+
+    #[test]
+    fn exit_status_test() {
+        assert_eq!(Ok(246), run("-10;"));
+        assert_eq!(Ok(0), run("256;"));
+    }
+
+    #[test]
+    fn error_test() {
+        assert_eq!(Err("division by zero".to_owned()), run("1 / 0;"));
+        assert_eq!(
+            Err("cannot call external function 'foo'".to_owned()),
+            run("foo();")
+        );
+    }
+
+    #[test]
+    fn frame_test() {
+        let mut interpreter = Interpreter::new(2);
+
+        assert_eq!(Ok(()), interpreter.store(16, 3));
+        assert_eq!(Ok(3), interpreter.load(16));
+        assert_eq!(Ok(0), interpreter.load(8));
+        assert!(interpreter.load(24).is_err());
+        assert!(interpreter.load(0).is_err());
+    }
+}
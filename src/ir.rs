@@ -0,0 +1,634 @@
+use crate::generator::GenerateError;
+use crate::interp::{binary, InterpretError, Value};
+use crate::tree::*;
+use std::fmt;
+
+/// A virtual register. There are as many as the function needs; the backend decides where each
+/// one lives.
+pub type VReg = usize;
+
+/// An index into `Function::blocks`.
+pub type BlockId = usize;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+}
+
+impl BinOp {
+    fn new(kind: &NodeKind) -> Result<BinOp, GenerateError> {
+        match kind {
+            NodeKind::Add => Ok(BinOp::Add),
+            NodeKind::Sub => Ok(BinOp::Sub),
+            NodeKind::Mul => Ok(BinOp::Mul),
+            NodeKind::Div => Ok(BinOp::Div),
+            NodeKind::Equality => Ok(BinOp::Eq),
+            NodeKind::Nonequality => Ok(BinOp::Ne),
+            NodeKind::Less => Ok(BinOp::Lt),
+            NodeKind::LessOrEqual => Ok(BinOp::Le),
+            NodeKind::Assign => Err("unexpected node".to_owned()),
+        }
+    }
+
+    /// What the operation computes, the same as the interpreter.
+    pub fn eval(&self, lhs: Value, rhs: Value) -> Result<Value, InterpretError> {
+        let kind = match self {
+            BinOp::Add => NodeKind::Add,
+            BinOp::Sub => NodeKind::Sub,
+            BinOp::Mul => NodeKind::Mul,
+            BinOp::Div => NodeKind::Div,
+            BinOp::Eq => NodeKind::Equality,
+            BinOp::Ne => NodeKind::Nonequality,
+            BinOp::Lt => NodeKind::Less,
+            BinOp::Le => NodeKind::LessOrEqual,
+        };
+        binary(&kind, lhs, rhs)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => "div",
+            BinOp::Eq => "eq",
+            BinOp::Ne => "ne",
+            BinOp::Lt => "lt",
+            BinOp::Le => "le",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Inst {
+    Const {
+        dst: VReg,
+        value: i64,
+    },
+    Binary {
+        op: BinOp,
+        dst: VReg,
+        lhs: VReg,
+        rhs: VReg,
+    },
+    /// reads variable `var` from the stack frame
+    Load {
+        dst: VReg,
+        var: usize,
+    },
+    Store {
+        var: usize,
+        src: VReg,
+    },
+    Call {
+        dst: VReg,
+        name: String,
+    },
+    Copy {
+        dst: VReg,
+        src: VReg,
+    },
+    /// Only in SSA form, at the top of a block: the value `dst` takes coming from each
+    /// predecessor.
+    Phi {
+        dst: VReg,
+        args: Vec<(BlockId, VReg)>,
+    },
+}
+
+impl Inst {
+    /// The virtual register the instruction writes.
+    pub fn dst(&self) -> Option<VReg> {
+        match *self {
+            Inst::Const { dst, .. }
+            | Inst::Binary { dst, .. }
+            | Inst::Load { dst, .. }
+            | Inst::Call { dst, .. }
+            | Inst::Copy { dst, .. }
+            | Inst::Phi { dst, .. } => Some(dst),
+            Inst::Store { .. } => None,
+        }
+    }
+
+    /// The virtual registers the instruction reads.
+    pub fn operands(&self) -> Vec<VReg> {
+        match self {
+            Inst::Const { .. } | Inst::Load { .. } | Inst::Call { .. } => vec![],
+            Inst::Binary { lhs, rhs, .. } => vec![*lhs, *rhs],
+            Inst::Store { src, .. } | Inst::Copy { src, .. } => vec![*src],
+            Inst::Phi { args, .. } => args.iter().map(|(_, value)| *value).collect(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut VReg> {
+        match self {
+            Inst::Const { .. } | Inst::Load { .. } | Inst::Call { .. } => vec![],
+            Inst::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Inst::Store { src, .. } | Inst::Copy { src, .. } => vec![src],
+            Inst::Phi { args, .. } => args.iter_mut().map(|(_, value)| value).collect(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Terminator {
+    Return(VReg),
+    Jump(BlockId),
+    /// goes to `then_block` when `cond` is not zero
+    Branch {
+        cond: VReg,
+        then_block: BlockId,
+        else_block: BlockId,
+    },
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match *self {
+            Terminator::Return(_) => vec![],
+            Terminator::Jump(block) => vec![block],
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => vec![then_block, else_block],
+        }
+    }
+
+    pub fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Terminator::Return(_) => vec![],
+            Terminator::Jump(block) => vec![block],
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => vec![then_block, else_block],
+        }
+    }
+
+    pub fn operands(&self) -> Vec<VReg> {
+        match *self {
+            Terminator::Return(value) => vec![value],
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch { cond, .. } => vec![cond],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut VReg> {
+        match self {
+            Terminator::Return(value) => vec![value],
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch { cond, .. } => vec![cond],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+    pub terminator: Terminator,
+    /// The expression statements and `return`s lowered into the block, to point at in warnings.
+    pub stmts: Vec<Tree>,
+}
+
+/// A function in three-address form. Execution starts at the first block.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Function {
+    pub name: String,
+    /// The variables in the stack frame, by name. The last one holds the value of the last
+    /// expression statement, which is what `main` returns when it runs off the end.
+    pub vars: Vec<String>,
+    pub blocks: Vec<Block>,
+    /// how many virtual registers are numbered
+    pub vregs: usize,
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "function {}", self.name)?;
+        for (n, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "b{}:", n)?;
+            for inst in &block.insts {
+                writeln!(f, "  {}", self.inst(inst))?;
+            }
+            writeln!(f, "  {}", block.terminator)?;
+        }
+        Ok(())
+    }
+}
+
+impl Function {
+    pub fn new_vreg(&mut self) -> VReg {
+        self.vregs += 1;
+        self.vregs - 1
+    }
+
+    /// An instruction as `Display` writes it, with variables by name.
+    pub fn inst(&self, inst: &Inst) -> String {
+        match inst {
+            Inst::Const { dst, value } => format!("v{} = {}", dst, value),
+            Inst::Binary { op, dst, lhs, rhs } => {
+                format!("v{} = {} v{}, v{}", dst, op.name(), lhs, rhs)
+            }
+            Inst::Load { dst, var } => format!("v{} = load {}", dst, self.vars[*var]),
+            Inst::Store { var, src } => format!("store {}, v{}", self.vars[*var], src),
+            Inst::Call { dst, name } => format!("v{} = call {}", dst, name),
+            Inst::Copy { dst, src } => format!("v{} = copy v{}", dst, src),
+            Inst::Phi { dst, args } => format!(
+                "v{} = phi {}",
+                dst,
+                args.iter()
+                    .map(|(block, value)| format!("[b{}: v{}]", block, value))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Terminator::Return(value) => write!(f, "ret v{}", value),
+            Terminator::Jump(block) => write!(f, "jump b{}", block),
+            Terminator::Branch {
+                cond,
+                then_block,
+                else_block,
+            } => write!(f, "branch v{}, b{}, b{}", cond, then_block, else_block),
+        }
+    }
+}
+
+/// Lowers the program to the `main` function. Variables keep the slots `variable_analysis` gave
+/// them and start at zero, like in the interpreter.
+pub fn lower(trees: &[Tree], ident_count: usize) -> Result<Function, GenerateError> {
+    let mut vars = vec![String::new(); ident_count];
+    // not a valid identifier, so it can't clash with a variable
+    vars.push(".last".to_owned());
+    let mut builder = Builder {
+        vars,
+        blocks: vec![(vec![], None, vec![])],
+        current: 0,
+        vregs: 0,
+    };
+
+    let zero = builder.constant(0);
+    for tree in trees {
+        builder.stmt(tree)?;
+    }
+    let result = builder.load(ident_count);
+    builder.terminate(Terminator::Return(result));
+
+    // function names take a slot too, but only the variables need clearing
+    let stores = (0..=ident_count)
+        .filter(|var| !builder.vars[*var].is_empty())
+        .map(|var| Inst::Store { var, src: zero });
+    builder.blocks[0].0.splice(1..1, stores.collect::<Vec<_>>());
+
+    Ok(Function {
+        name: "main".to_owned(),
+        vars: builder.vars,
+        blocks: builder
+            .blocks
+            .into_iter()
+            .map(|(insts, terminator, stmts)| Block {
+                insts,
+                terminator: terminator.expect("every block ends up terminated"),
+                stmts,
+            })
+            .collect(),
+        vregs: builder.vregs,
+    })
+}
+
+struct Builder {
+    vars: Vec<String>,
+    blocks: Vec<(Vec<Inst>, Option<Terminator>, Vec<Tree>)>,
+    current: BlockId,
+    vregs: usize,
+}
+
+impl Builder {
+    fn vreg(&mut self) -> VReg {
+        self.vregs += 1;
+        self.vregs - 1
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push((vec![], None, vec![]));
+        self.blocks.len() - 1
+    }
+
+    fn push(&mut self, inst: Inst) {
+        self.blocks[self.current].0.push(inst);
+    }
+
+    fn terminate(&mut self, terminator: Terminator) {
+        self.blocks[self.current].1 = Some(terminator);
+    }
+
+    fn constant(&mut self, value: i64) -> VReg {
+        let dst = self.vreg();
+        self.push(Inst::Const { dst, value });
+        dst
+    }
+
+    fn load(&mut self, var: usize) -> VReg {
+        let dst = self.vreg();
+        self.push(Inst::Load { dst, var });
+        dst
+    }
+
+    /// Ends the current block with a branch to `then_block` when `cond` is true. A missing
+    /// condition, like in `for (;;)`, is always true.
+    fn branch(
+        &mut self,
+        cond: &Tree,
+        then_block: BlockId,
+        else_block: BlockId,
+    ) -> Result<(), GenerateError> {
+        if *cond == Tree::None {
+            self.terminate(Terminator::Jump(then_block));
+            return Ok(());
+        }
+        let cond = self.expr(cond)?;
+        self.terminate(Terminator::Branch {
+            cond,
+            then_block,
+            else_block,
+        });
+        Ok(())
+    }
+
+    fn stmt(&mut self, tree: &Tree) -> Result<(), GenerateError> {
+        match tree {
+            Tree::None => {}
+            Tree::Return(expr) => {
+                self.blocks[self.current].2.push(tree.clone());
+                let value = self.expr(expr)?;
+                self.terminate(Terminator::Return(value));
+                // whatever follows is unreachable, but still needs a block to go in
+                self.current = self.new_block();
+            }
+            Tree::If(cond, stmt) => {
+                let then_block = self.new_block();
+                let end_block = self.new_block();
+                self.branch(cond, then_block, end_block)?;
+                self.current = then_block;
+                self.stmt(stmt)?;
+                self.terminate(Terminator::Jump(end_block));
+                self.current = end_block;
+            }
+            Tree::IfElse(cond, stmt, else_stmt) => {
+                let then_block = self.new_block();
+                let else_block = self.new_block();
+                let end_block = self.new_block();
+                self.branch(cond, then_block, else_block)?;
+                self.current = then_block;
+                self.stmt(stmt)?;
+                self.terminate(Terminator::Jump(end_block));
+                self.current = else_block;
+                self.stmt(else_stmt)?;
+                self.terminate(Terminator::Jump(end_block));
+                self.current = end_block;
+            }
+            Tree::While(cond, stmt) => {
+                let begin_block = self.new_block();
+                let body_block = self.new_block();
+                let end_block = self.new_block();
+                self.terminate(Terminator::Jump(begin_block));
+                self.current = begin_block;
+                self.branch(cond, body_block, end_block)?;
+                self.current = body_block;
+                self.stmt(stmt)?;
+                self.terminate(Terminator::Jump(begin_block));
+                self.current = end_block;
+            }
+            Tree::For(init, cond, step, stmt) => {
+                self.stmt(init)?;
+                let begin_block = self.new_block();
+                let body_block = self.new_block();
+                let end_block = self.new_block();
+                self.terminate(Terminator::Jump(begin_block));
+                self.current = begin_block;
+                self.branch(cond, body_block, end_block)?;
+                self.current = body_block;
+                self.stmt(stmt)?;
+                self.stmt(step)?;
+                self.terminate(Terminator::Jump(begin_block));
+                self.current = end_block;
+            }
+            Tree::Block(trees) => {
+                for tree in trees {
+                    self.stmt(tree)?;
+                }
+            }
+            expr => {
+                self.blocks[self.current].2.push(tree.clone());
+                let src = self.expr(expr)?;
+                let var = self.vars.len() - 1;
+                self.push(Inst::Store { var, src });
+            }
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, tree: &Tree) -> Result<VReg, GenerateError> {
+        match tree {
+            Tree::Int(n) => Ok(self.constant(*n as i64)),
+            Tree::Val { name, offset } => {
+                let var = self.var(name, *offset);
+                Ok(self.load(var))
+            }
+            Tree::Func { name } => {
+                let dst = self.vreg();
+                self.push(Inst::Call {
+                    dst,
+                    name: name.clone(),
+                });
+                Ok(dst)
+            }
+            Tree::Node(NodeKind::Assign, lhs, rhs) => {
+                let var = match &**lhs {
+                    Tree::Val { name, offset } => self.var(name, *offset),
+                    _ => {
+                        return Err(
+                            "The left-hand side value of the assignment is not a variable"
+                                .to_owned(),
+                        )
+                    }
+                };
+                let src = self.expr(rhs)?;
+                self.push(Inst::Store { var, src });
+                Ok(src)
+            }
+            Tree::Node(kind, lhs, rhs) => {
+                let op = BinOp::new(kind)?;
+                let lhs = self.expr(lhs)?;
+                let rhs = self.expr(rhs)?;
+                let dst = self.vreg();
+                self.push(Inst::Binary { op, dst, lhs, rhs });
+                Ok(dst)
+            }
+            _ => Err("unexpected statement in expression".to_owned()),
+        }
+    }
+
+    /// The slot of a variable at `[rbp - offset]`, remembering its name for printing.
+    fn var(&mut self, name: &str, offset: usize) -> usize {
+        let var = offset / 8 - 1;
+        self.vars[var] = name.to_owned();
+        var
+    }
+}
+
+/// Runs the function like `interpreter` runs the trees, so the IR can be checked against it.
+#[cfg(test)]
+pub fn run(function: &Function) -> Result<Value, InterpretError> {
+    let mut vars = vec![0; function.vars.len()];
+    let mut vregs = vec![0; function.vregs];
+    let (mut block, mut previous) = (0, 0);
+    loop {
+        // phis all read the values from before the block
+        let mut phis = vec![];
+        for inst in &function.blocks[block].insts {
+            if let Inst::Phi { dst, args } = inst {
+                match args.iter().find(|(b, _)| *b == previous) {
+                    Some((_, value)) => phis.push((*dst, vregs[*value])),
+                    None => return Err(format!("no phi argument for b{}", previous)),
+                }
+            }
+        }
+        for (dst, value) in phis {
+            vregs[dst] = value;
+        }
+
+        for inst in &function.blocks[block].insts {
+            match inst {
+                Inst::Const { dst, value } => vregs[*dst] = *value,
+                Inst::Binary { op, dst, lhs, rhs } => {
+                    vregs[*dst] = op.eval(vregs[*lhs], vregs[*rhs])?;
+                }
+                Inst::Load { dst, var } => vregs[*dst] = vars[*var],
+                Inst::Store { var, src } => vars[*var] = vregs[*src],
+                Inst::Call { name, .. } => return Err(format!("cannot call {}", name)),
+                Inst::Copy { dst, src } => vregs[*dst] = vregs[*src],
+                Inst::Phi { .. } => {}
+            }
+        }
+        previous = block;
+        block = match function.blocks[block].terminator {
+            Terminator::Return(value) => return Ok(vregs[value]),
+            Terminator::Jump(next) => next,
+            Terminator::Branch {
+                cond,
+                then_block,
+                else_block,
+            } => match vregs[cond] {
+                0 => else_block,
+                _ => then_block,
+            },
+        };
+    }
+}
+
+/// Lowers a program, for the tests.
+#[cfg(test)]
+pub(crate) fn lower_source(src: &str) -> Function {
+    use crate::{lexer::lexer, parser::parser, variable::variable_analysis};
+    let (tokens, ident_count) = variable_analysis(lexer(src).unwrap()).unwrap();
+    lower(&parser(tokens).unwrap(), ident_count).unwrap()
+}
+
+/// Asserts that a program, lowered and put through `transform`, exits as the interpreter says.
+#[cfg(test)]
+pub(crate) fn assert_matches_interpreter(src: &str, transform: impl Fn(&mut Function)) {
+    use crate::interp::{exit_status, interpreter};
+    use crate::{lexer::lexer, parser::parser, variable::variable_analysis};
+    let (tokens, ident_count) = variable_analysis(lexer(src).unwrap()).unwrap();
+    let expected = interpreter(&parser(tokens).unwrap(), ident_count).map(exit_status);
+    let mut function = lower_source(src);
+    transform(&mut function);
+    assert_eq!(expected, run(&function).map(exit_status), "{}", src);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lower_test() {
+        assert_eq!(
+            "function main
+b0:
+  v0 = 0
+  store a, v0
+  store .last, v0
+  v1 = 3
+  store a, v1
+  store .last, v1
+  jump b1
+b1:
+  v2 = load a
+  v3 = 10
+  v4 = lt v2, v3
+  branch v4, b2, b3
+b2:
+  v5 = load a
+  v6 = call foo
+  v7 = add v5, v6
+  store a, v7
+  store .last, v7
+  jump b1
+b3:
+  v8 = load a
+  ret v8
+b4:
+  v9 = load .last
+  ret v9
+",
+            lower_source("a = 3; while (a < 10) a = a + foo(); return a;").to_string()
+        );
+    }
+
+    #[test]
+    fn for_test() {
+        let function = lower_source("for (;;) if (1) 2; else 3;");
+
+        assert_eq!(Terminator::Jump(2), function.blocks[1].terminator);
+        assert_eq!(
+            Terminator::Branch {
+                cond: 1,
+                then_block: 4,
+                else_block: 5
+            },
+            function.blocks[2].terminator
+        );
+        assert_eq!(Terminator::Jump(1), function.blocks[6].terminator);
+    }
+
+    #[test]
+    fn semantics_test() {
+        let cases = [
+            "a = 3; b = 5*6 - 8; a + b / 2;",
+            "if(3 < 2)return 5; else return 4;",
+            "i = 1; while(i < 3) i = i*2;i;",
+            "for(a = 0; a < 10; a = a + 1)a + 1;a;",
+            "{{{{{{{{{{0;1;2;3;10;}}}}}}}}}}",
+            "x = 0; if (x) y = 5; y;",
+        ];
+
+        for src in cases {
+            assert_matches_interpreter(src, |_| {});
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use crate::elf::Object;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+use crate::interp::Value;
+
+pub type JitError = String;
+
+/// The libc functions a JIT compiled program may call. They all take no arguments, which is all
+/// the language can pass.
+pub const LIBC_FUNCTIONS: [&str; 5] = ["clock", "getchar", "getpid", "getppid", "rand"];
+
+/// Lays out `object` for running in place: the text, then a stub for every function it calls
+/// that it doesn't define, jumping to the address `resolve` returns. Calls reach the stubs with
+/// a rel32, so the functions themselves may be anywhere in the address space.
+///
+/// Returns the code and the offset of `main` in it.
+pub fn link(
+    object: &Object,
+    resolve: impl Fn(&str) -> Result<u64, JitError>,
+) -> Result<(Vec<u8>, usize), JitError> {
+    let mut code = object.text.clone();
+    let mut stubs: Vec<(&str, usize)> = vec![];
+
+    for relocation in &object.relocations {
+        let name = relocation.symbol.as_str();
+        let defined = object
+            .symbols
+            .iter()
+            .find(|s| s.name == name)
+            .and_then(|s| s.value);
+        let target = match defined {
+            Some(value) => value as usize,
+            None => match stubs.iter().find(|(stub, _)| *stub == name) {
+                Some((_, offset)) => *offset,
+                None => {
+                    let offset = code.len();
+                    // jmp [rip], followed by the address it reads
+                    code.extend([0xff, 0x25, 0, 0, 0, 0]);
+                    code.extend(resolve(name)?.to_le_bytes());
+                    stubs.push((name, offset));
+                    offset
+                }
+            },
+        };
+
+        // PC32 and PLT32 both patch in S + A - P
+        let offset = relocation.offset as usize;
+        let value = target as i64 + relocation.addend - offset as i64;
+        code[offset..offset + 4].copy_from_slice(&(value as i32).to_le_bytes());
+    }
+
+    match object.symbols.iter().find(|s| s.name == "main") {
+        Some(main) => match main.value {
+            Some(value) => Ok((code, value as usize)),
+            None => Err("main is not defined".to_owned()),
+        },
+        None => Err("main is not defined".to_owned()),
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod sys {
+    use std::ffi::{c_char, c_int, c_long, c_void};
+
+    pub const PROT_READ: c_int = 1;
+    pub const PROT_WRITE: c_int = 2;
+    pub const PROT_EXEC: c_int = 4;
+    pub const MAP_PRIVATE: c_int = 0x02;
+    pub const MAP_ANONYMOUS: c_int = 0x20;
+    pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+    /// glibc's `RTLD_DEFAULT`, which searches every loaded object
+    pub const RTLD_DEFAULT: *mut c_void = std::ptr::null_mut();
+
+    extern "C" {
+        pub fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: c_long,
+        ) -> *mut c_void;
+        pub fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+        pub fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn resolve(name: &str) -> Result<u64, JitError> {
+    if !LIBC_FUNCTIONS.contains(&name) {
+        return Err(format!(
+            "'{}' is not one of the functions --jit can call: {}",
+            name,
+            LIBC_FUNCTIONS.join(", ")
+        ));
+    }
+    let symbol = std::ffi::CString::new(name).unwrap();
+    // SAFETY: `symbol` is a NUL terminated string that outlives the call.
+    match unsafe { sys::dlsym(sys::RTLD_DEFAULT, symbol.as_ptr()) } {
+        address if address.is_null() => Err(format!("cannot find '{}' in libc", name)),
+        address => Ok(address as u64),
+    }
+}
+
+/// Copies the linked code into an executable page, calls `main` and returns what it returns.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub fn jit(object: &Object) -> Result<Value, JitError> {
+    let (code, main) = link(object, resolve)?;
+    let len = code.len().div_ceil(4096) * 4096;
+
+    // SAFETY: the mapping is private to this call and unmapped before returning. `main` follows
+    // the System V calling convention since the backend writes it to be called from crt0.
+    unsafe {
+        let page = sys::mmap(
+            std::ptr::null_mut(),
+            len,
+            sys::PROT_READ | sys::PROT_WRITE,
+            sys::MAP_PRIVATE | sys::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if page == sys::MAP_FAILED {
+            return Err("cannot map memory for the code".to_owned());
+        }
+        std::ptr::copy_nonoverlapping(code.as_ptr(), page as *mut u8, code.len());
+        // never writable and executable at the same time
+        if sys::mprotect(page, len, sys::PROT_READ | sys::PROT_EXEC) != 0 {
+            sys::munmap(page, len);
+            return Err("cannot make the code executable".to_owned());
+        }
+
+        let entry: extern "C" fn() -> Value = std::mem::transmute((page as *const u8).add(main));
+        let value = entry();
+        sys::munmap(page, len);
+        Ok(value)
+    }
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+pub fn jit(_object: &Object) -> Result<crate::interp::Value, JitError> {
+    Err("--jit is only supported on x86_64-linux".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::{Relocation, RelocationKind, Symbol};
+
+    #[test]
+    fn link_test() {
+        let call = |offset| Relocation {
+            offset,
+            symbol: "foo".to_owned(),
+            kind: RelocationKind::Plt32,
+            addend: -4,
+        };
+        let object = Object {
+            // call foo; call foo; ret
+            text: vec![0xe8, 0, 0, 0, 0, 0xe8, 0, 0, 0, 0, 0xc3],
+            symbols: vec![
+                Symbol {
+                    name: "main".to_owned(),
+                    global: true,
+                    value: Some(0),
+                },
+                Symbol {
+                    name: "foo".to_owned(),
+                    global: true,
+                    value: None,
+                },
+            ],
+            relocations: vec![call(1), call(6)],
+        };
+
+        let (code, main) = link(&object, |_| Ok(0x1122334455667788)).unwrap();
+        assert_eq!(0, main);
+        // both calls land on the one stub at 11
+        assert_eq!([0xe8, 6, 0, 0, 0, 0xe8, 1, 0, 0, 0, 0xc3], code[..11]);
+        assert_eq!(
+            [0xff, 0x25, 0, 0, 0, 0, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11],
+            code[11..]
+        );
+
+        assert_eq!(
+            Err("not found".to_owned()),
+            link(&object, |_| Err("not found".to_owned()))
+        );
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn jit_test() {
+        use crate::architecture::{x86_64::Syntax, Target};
+        use crate::assembler::assemble;
+        use crate::generator::{generator, OptLevel, Options};
+        use crate::{lexer::lexer, parser::parser, variable::variable_analysis};
+
+        let run_at = |src: &str, opt_level| {
+            let (tokens, ident_count) = variable_analysis(lexer(src).unwrap()).unwrap();
+            let backend = Target::X86_64Linux.backend(Syntax::Intel).unwrap();
+            let trees = parser(tokens).unwrap();
+            let asm = generator(trees, ident_count, &*backend, &Options::new(opt_level))
+                .unwrap()
+                .0;
+            jit(&assemble(&asm).unwrap())
+        };
+        let run = |src: &str| run_at(src, OptLevel::O0);
+
+        assert_eq!(
+            Ok(55),
+            run("s = 0; for (i = 1; i <= 10; i = i + 1) s = s + i; s;")
+        );
+        assert_eq!(Ok(std::process::id() as Value), run("return getpid();"));
+        assert_eq!(
+            Err(
+                "'foo' is not one of the functions --jit can call: clock, getchar, getpid, \
+                 getppid, rand"
+                    .to_owned()
+            ),
+            run("foo();")
+        );
+
+        // enough values live across calls and loops to spill some of them
+        let src = "a = 1; b = 2; c = 3; d = 4; e = 5; f = 6; g = 7; h = 8; r = rand(); \
+                   for (i = 0; i < 10; i = i + 1) { a = a + b; b = b + c; c = c + d; \
+                   d = d + e; e = e + f; f = f + g; g = g + h; h = h + i; } \
+                   (a + b + c + d + e + f + g + h) / 7 + r - r;";
+        assert_eq!(run(src), run_at(src, OptLevel::O1));
+        assert_eq!(
+            Ok(55),
+            run_at(
+                "s = 0; for (i = 1; i <= 10; i = i + 1) s = s + i; s;",
+                OptLevel::O1
+            )
+        );
+    }
+}
@@ -1,41 +1,106 @@
 use crate::token::{Ident, Int, Token, TokenError};
 
 pub fn lexer(s: &str) -> Result<Vec<Token>, TokenError> {
-    let go = |tokenize: fn(&str) -> Result<(Token, usize), TokenError>| {
-        if let Ok((token, size)) = tokenize(s) {
-            match lexer(&s[size..]) {
-                Ok(v) => Ok([vec![token], v].concat()),
-                Err(e) => Err(e),
-            }
-        } else {
-            Err(TokenError::TokenizeError)
+    Lexer::new(s).collect()
+}
+
+/// Like `lexer`, but keeps comments and line breaks as `Token::Comment` and `Token::Newline`.
+pub fn lexer_with_trivia(s: &str) -> Result<Vec<Token>, TokenError> {
+    Lexer::with_trivia(s).collect()
+}
+
+/// The tokens of a source, read one at a time from a byte offset into it. Each token is looked at
+/// once, so lexing takes time linear in the length of the source. After an error there are no
+/// more tokens.
+pub struct Lexer<'a> {
+    source: &'a str,
+    offset: usize,
+    trivia: bool,
+    /// line breaks read past in whitespace but not yielded yet
+    newlines: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str) -> Lexer<'a> {
+        Lexer {
+            source,
+            offset: 0,
+            trivia: false,
+            newlines: 0,
         }
-    };
+    }
 
-    if s.is_empty() {
-        Ok(vec![])
-    } else if expect_whitespace(s) {
-        lexer(&s[count_whitespace(s)..])
-    } else if expect_int(s) {
-        go(tokenize_int)
-    } else if expect_return(s) {
-        go(tokenize_return)
-    } else if expect_if(s) {
-        go(tokenize_if)
-    } else if expect_else(s) {
-        go(tokenize_else)
-    } else if expect_while(s) {
-        go(tokenize_while)
-    } else if expect_for(s) {
-        go(tokenize_for)
-    } else if expect_ident(s) {
-        go(tokenize_ident)
-    } else if !expect_operators(s).is_empty() {
-        go(tokenize_operator)
-    } else if let Some(c) = s.chars().next() {
-        Err(TokenError::InvalidChar(c))
-    } else {
-        Err(TokenError::TokenizeError)
+    pub fn with_trivia(source: &'a str) -> Lexer<'a> {
+        Lexer {
+            trivia: true,
+            ..Lexer::new(source)
+        }
+    }
+
+    fn token(&self, s: &str) -> Result<(Token, usize), TokenError> {
+        let tokenize = if expect_comment(s) {
+            tokenize_comment
+        } else if expect_int(s) {
+            tokenize_int
+        } else if expect_return(s) {
+            tokenize_return
+        } else if expect_if(s) {
+            tokenize_if
+        } else if expect_else(s) {
+            tokenize_else
+        } else if expect_while(s) {
+            tokenize_while
+        } else if expect_for(s) {
+            tokenize_for
+        } else if expect_ident(s) {
+            tokenize_ident
+        } else if !expect_operators(s).is_empty() {
+            tokenize_operator
+        } else {
+            let c = s.chars().next().expect("only called before the end");
+            return Err(TokenError::InvalidChar(c));
+        };
+        tokenize(s).map_err(|_| TokenError::TokenizeError)
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Result<Token, TokenError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.newlines > 0 {
+                self.newlines -= 1;
+                return Some(Ok(Token::Newline));
+            }
+
+            let s = &self.source[self.offset..];
+            if s.is_empty() {
+                return None;
+            }
+            if expect_whitespace(s) {
+                let size = count_whitespace(s);
+                if self.trivia {
+                    self.newlines = s.as_bytes()[..size].iter().filter(|b| **b == b'\n').count();
+                }
+                self.offset += size;
+                continue;
+            }
+
+            match self.token(s) {
+                Ok((token, size)) => {
+                    self.offset += size;
+                    if token.is_trivia() && !self.trivia {
+                        continue;
+                    }
+                    return Some(Ok(token));
+                }
+                Err(e) => {
+                    self.offset = self.source.len();
+                    return Some(Err(e));
+                }
+            }
+        }
     }
 }
 
@@ -82,6 +147,18 @@ fn tokenize_operator(s: &str) -> Result<(Token, usize), TokenError> {
     }
 }
 
+fn tokenize_comment(s: &str) -> Result<(Token, usize), TokenError> {
+    let size = if expect_str(s, "//") {
+        s.find('\n').unwrap_or(s.len())
+    } else {
+        match s[2..].find("*/") {
+            Some(n) => n + 4,
+            None => return Err(TokenError::TokenizeError),
+        }
+    };
+    Ok((Token::Comment(s[..size].trim_end().to_owned()), size))
+}
+
 fn tokenize_return(_: &str) -> Result<(Token, usize), TokenError> {
     Ok((Token::Return, 6))
 }
@@ -114,18 +191,15 @@ fn count_whitespace(s: &str) -> usize {
     count(s, |c| c.is_whitespace())
 }
 
+/// The length in bytes of the run of characters at the start of `s` that satisfy `pred`.
 fn count(s: &str, pred: fn(char) -> bool) -> usize {
-    fn go(n: usize, s: &str, pred: fn(char) -> bool) -> usize {
-        match s.chars().next() {
-            Some(c) if pred(c) => go(n + 1, &s[1..], pred),
-            _ => n,
-        }
-    }
-    go(0, s, pred)
+    s.char_indices()
+        .find(|(_, c)| !pred(*c))
+        .map_or(s.len(), |(n, _)| n)
 }
 
 fn expect_str(s: &str, expect: &str) -> bool {
-    s.len() >= expect.len() && &s[..expect.len()] == expect
+    s.starts_with(expect)
 }
 
 fn expect_int(s: &str) -> bool {
@@ -136,18 +210,20 @@ fn expect_ident(s: &str) -> bool {
     count_ident(s) > 0
 }
 
-fn expect_operators(s: &str) -> &'static str {
-    let ops = vec![
-        ";", "==", "=", "!=", "<=", "<", ">=", ">", "+", "-", "*", "/", "(", ")", "{", "}",
-    ];
+/// Longer operators come before the ones they start with.
+const OPERATORS: [&str; 16] = [
+    ";", "==", "=", "!=", "<=", "<", ">=", ">", "+", "-", "*", "/", "(", ")", "{", "}",
+];
 
-    for op in ops {
-        if expect_str(s, op) {
-            return op;
-        }
-    }
+fn expect_operators(s: &str) -> &'static str {
+    OPERATORS
+        .into_iter()
+        .find(|op| expect_str(s, op))
+        .unwrap_or("")
+}
 
-    ""
+fn expect_comment(s: &str) -> bool {
+    expect_str(s, "//") || expect_str(s, "/*")
 }
 
 fn expect_whitespace(s: &str) -> bool {
@@ -235,6 +311,23 @@ mod tests {
         assert_eq!(Err(TokenError::TokenizeError), tokenize_operator(s3));
     }
 
+    #[test]
+    fn tokenize_comment_test() {
+        let s1 = "// abc\n1";
+        let s2 = "/* a\nb */1";
+        let s3 = "/* abc";
+
+        assert_eq!(
+            Ok((Token::Comment("// abc".to_owned()), 6)),
+            tokenize_comment(s1)
+        );
+        assert_eq!(
+            Ok((Token::Comment("/* a\nb */".to_owned()), 9)),
+            tokenize_comment(s2)
+        );
+        assert_eq!(Err(TokenError::TokenizeError), tokenize_comment(s3));
+    }
+
     #[test]
     fn count_int_test() {
         let s1 = "123abc";
@@ -471,4 +564,112 @@ mod tests {
         );
         assert_eq!(Err(TokenError::InvalidChar('$')), lexer(query2));
     }
+
+    #[test]
+    fn comment_test() {
+        let query = "1 / 2; // half\n/* done */";
+
+        assert_eq!(
+            Ok(vec![
+                Token::Integer(1),
+                Token::Div,
+                Token::Integer(2),
+                Token::Semicolon
+            ]),
+            lexer(query)
+        );
+        assert_eq!(
+            Ok(vec![
+                Token::Integer(1),
+                Token::Div,
+                Token::Integer(2),
+                Token::Semicolon,
+                Token::Comment("// half".to_owned()),
+                Token::Newline,
+                Token::Comment("/* done */".to_owned()),
+            ]),
+            lexer_with_trivia(query)
+        );
+    }
+
+    #[test]
+    fn lazy_test() {
+        let mut tokens = Lexer::new("a = 1; $ b");
+
+        assert_eq!(
+            Some(Ok(Token::Ident(Ident {
+                name: "a".to_owned()
+            }))),
+            tokens.next()
+        );
+        assert_eq!(
+            vec![
+                Ok(Token::Equal),
+                Ok(Token::Integer(1)),
+                Ok(Token::Semicolon),
+                Err(TokenError::InvalidChar('$'))
+            ],
+            tokens.by_ref().collect::<Vec<_>>()
+        );
+        assert_eq!(None, tokens.next());
+        assert_eq!(
+            vec![Ok(Token::Integer(1)), Err(TokenError::TokenizeError)],
+            Lexer::new("1 /* open").collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn unicode_test() {
+        assert_eq!(
+            Ok(vec![
+                Token::Ident(Ident {
+                    name: "café".to_owned()
+                }),
+                Token::Equal,
+                Token::Integer(1),
+                Token::Semicolon,
+                Token::Comment("// 続き".to_owned()),
+                Token::Newline,
+            ]),
+            lexer_with_trivia("café = 1; // 続き\n")
+        );
+        assert_eq!(Err(TokenError::InvalidChar('→')), lexer("a → b"));
+    }
+
+    /// A program of about `bytes` bytes and how many tokens it has.
+    fn large_input(bytes: usize) -> (String, usize) {
+        let line = "s = s + 12345 * abc; // note\n";
+        let lines = bytes / line.len();
+        let mut source = "s = 0;\n".to_owned();
+        source.push_str(&line.repeat(lines));
+        source.push_str(&"x".repeat(bytes / 4));
+        source.push(';');
+        (source, 4 + lines * 8 + 2)
+    }
+
+    #[test]
+    fn large_input_test() {
+        let (source, count) = large_input(1 << 20);
+        assert_eq!(count, lexer(&source).unwrap().len());
+    }
+
+    /// `cargo test --release lexer_bench -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn lexer_bench() {
+        for megabytes in [1, 4, 16, 64] {
+            let (source, count) = large_input(megabytes << 20);
+            let start = std::time::Instant::now();
+            let tokens = lexer(&source).unwrap();
+            let elapsed = start.elapsed();
+            assert_eq!(count, tokens.len());
+            println!(
+                "{:>3} MB: {:>9} tokens in {:>8.2?} ({:.0} MB/s)",
+                megabytes,
+                count,
+                elapsed,
+                megabytes as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
 }
@@ -1,84 +1,577 @@
+use architecture::{
+    x86_64::{self, Syntax},
+    Backend, Os, Target,
+};
+use assembler::assemble;
+use bytecode::{bytecode, deserialize, disassemble, serialize};
+use dot::{ast_to_dot, cfg_to_dot};
+use elf::write_elf;
 use error::invalid_char_error;
-use generator::generator;
-use lexer::lexer;
+use format::format;
+use generator::{generator, OptLevel, Options};
+use interp::{exit_status, interpreter};
+use ir::lower;
+use jit::jit;
+use lexer::{lexer, lexer_with_trivia};
+use llvm::llvm_ir;
 use parser::parser;
+use passes::{parse_passes, Pass};
+use repl::repl;
 use std::{
     fs::{self, File},
-    io::Write,
+    io::{self, Write},
+};
+use token::{
+    Token, TokenError,
+    TokenError::{InvalidChar, TokenizeError},
 };
-use token::TokenError::{InvalidChar, TokenizeError};
+use tree::Tree;
+use unparse::unparse;
 use variable::variable_analysis;
+use vm::vm;
+use wasm::wat;
 
 mod architecture;
+mod assembler;
+mod bytecode;
+mod dce;
+mod dot;
+mod elf;
 mod error;
+mod fold;
+mod format;
 mod generator;
+mod gvn;
+mod interp;
+mod ir;
+mod jit;
 mod lexer;
+mod llvm;
+mod loops;
 mod parser;
+mod passes;
+mod peephole;
+mod regalloc;
+mod repl;
+mod ssa;
+mod tailcall;
 mod token;
 mod tree;
+mod unparse;
 mod variable;
+mod vm;
+mod warning;
+mod wasm;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DumpFormat {
+    Dot,
+    C,
+}
+
+/// What `Command::Compile` writes to the output file.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Emit {
+    Assembly,
+    /// an ELF relocatable object, from `-c`
+    Object,
+    /// textual LLVM IR, from `--emit-llvm`
+    Llvm,
+    /// a `.iocb` file for `ioc2 run`, from `--emit-bytecode`
+    Bytecode,
+}
+
+pub enum Command {
+    Compile {
+        source_file_path: String,
+        output_file_path: Option<String>,
+        emit: Emit,
+    },
+    Interpret {
+        source_file_path: String,
+    },
+    /// runs the x86_64 machine code in memory
+    Jit {
+        source_file_path: String,
+    },
+    Format {
+        source_file_paths: Vec<String>,
+        check: bool,
+    },
+    Repl,
+    Run {
+        bytecode_file_path: String,
+        /// prints the instructions instead of running them
+        disasm: bool,
+    },
+}
+
+/// What to print to stdout on the way to the output.
+#[derive(Default)]
+struct Dumps {
+    ast: Option<DumpFormat>,
+    cfg: Option<DumpFormat>,
+    ir: bool,
+}
 
 pub struct Config {
-    source_file_path: String,
-    assembly_file_path: String,
+    command: Command,
+    dumps: Dumps,
+    target: Target,
+    syntax: Syntax,
+    options: Options,
 }
 
 impl Config {
     pub fn new(args: &[String]) -> Result<Config, &'static str> {
-        if args.len() < 3 {
+        if args.get(1).is_some_and(|arg| arg == "fmt") {
+            return Config::new_format(&args[2..]);
+        }
+        if args.get(1).is_some_and(|arg| arg == "repl") {
+            return Config::new_repl(&args[2..]);
+        }
+        if args.get(1).is_some_and(|arg| arg == "run") {
+            return Config::new_run(&args[2..]);
+        }
+
+        let mut paths = vec![];
+        let mut dumps = Dumps::default();
+        let mut interpret = false;
+        let mut jit = false;
+        let mut emit = Emit::Assembly;
+        let mut target = Target::host();
+        let mut syntax = Syntax::Intel;
+        let mut opt_level = OptLevel::O0;
+        let mut passes = None;
+        let mut print_after = vec![];
+        let mut time_passes = false;
+
+        for arg in args.iter().skip(1) {
+            if let Some(name) = arg.strip_prefix("--target=") {
+                target = Target::parse(name)?;
+            } else if let Some(name) = arg.strip_prefix("--masm=") {
+                syntax = Syntax::parse(name)?;
+            } else if let Some(level) = arg.strip_prefix("-O") {
+                opt_level = OptLevel::parse(level)?;
+            } else if let Some(list) = arg.strip_prefix("--passes=") {
+                passes = Some(parse_passes(list)?);
+            } else if let Some(name) = arg.strip_prefix("--print-after=") {
+                print_after.push(Pass::parse(name)?);
+            } else if arg == "--time-passes" {
+                time_passes = true;
+            } else if arg == "--interp" {
+                interpret = true;
+            } else if arg == "--jit" {
+                jit = true;
+            } else if arg == "-c" || arg == "--emit-llvm" || arg == "--emit-bytecode" {
+                let next = match arg.as_str() {
+                    "-c" => Emit::Object,
+                    "--emit-llvm" => Emit::Llvm,
+                    _ => Emit::Bytecode,
+                };
+                if emit != Emit::Assembly && emit != next {
+                    return Err("only one of -c, --emit-llvm and --emit-bytecode can be used");
+                }
+                emit = next;
+            } else if let Some(format) = arg.strip_prefix("--dump-ast=") {
+                dumps.ast = Some(parse_dump_format(format)?);
+            } else if let Some(format) = arg.strip_prefix("--dump-cfg=") {
+                dumps.cfg = Some(parse_dump_format(format)?);
+            } else if arg == "--dump-ir" {
+                dumps.ir = true;
+            } else if arg.starts_with("--") {
+                return Err("unknown option");
+            } else {
+                paths.push(arg.clone());
+            }
+        }
+
+        check_syntax(target, syntax)?;
+        if emit == Emit::Object && target != Target::X86_64Linux {
+            return Err("-c is only supported for x86_64-linux");
+        }
+
+        if jit && interpret {
+            return Err("--interp and --jit cannot be used together");
+        }
+        if jit && !cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+            return Err("--jit is only supported on x86_64-linux");
+        }
+
+        let mut options = Options::new(opt_level);
+        if let Some(passes) = passes {
+            options.pipeline.passes = passes;
+        }
+        options.pipeline.print_after = print_after;
+        options.pipeline.time = time_passes;
+
+        let dumps_only = dumps.ast.is_some() || dumps.cfg.is_some() || dumps.ir;
+        if paths.is_empty() || (paths.len() < 2 && !dumps_only && !interpret && !jit) {
             return Err("not enough arguments");
         }
 
-        let input_file_path = args[1].clone();
-        let output_file_path = args[2].clone();
+        if jit {
+            return Ok(Config {
+                command: Command::Jit {
+                    source_file_path: paths[0].clone(),
+                },
+                dumps,
+                target,
+                syntax,
+                options,
+            });
+        }
+
+        if interpret {
+            return Ok(Config {
+                command: Command::Interpret {
+                    source_file_path: paths[0].clone(),
+                },
+                dumps,
+                target,
+                syntax,
+                options,
+            });
+        }
 
         Ok(Config {
-            source_file_path: input_file_path,
-            assembly_file_path: output_file_path,
+            command: Command::Compile {
+                source_file_path: paths[0].clone(),
+                output_file_path: paths.get(1).cloned(),
+                emit,
+            },
+            dumps,
+            target,
+            syntax,
+            options,
         })
     }
+
+    fn new_repl(args: &[String]) -> Result<Config, &'static str> {
+        let mut target = Target::host();
+        let mut syntax = Syntax::Intel;
+
+        for arg in args {
+            if let Some(name) = arg.strip_prefix("--target=") {
+                target = Target::parse(name)?;
+            } else if let Some(name) = arg.strip_prefix("--masm=") {
+                syntax = Syntax::parse(name)?;
+            } else {
+                return Err("unknown option");
+            }
+        }
+        check_syntax(target, syntax)?;
+
+        Ok(Config {
+            command: Command::Repl,
+            dumps: Dumps::default(),
+            target,
+            syntax,
+            options: Options::default(),
+        })
+    }
+
+    fn new_run(args: &[String]) -> Result<Config, &'static str> {
+        let mut paths = vec![];
+        let mut disasm = false;
+
+        for arg in args {
+            if arg == "--disasm" {
+                disasm = true;
+            } else if arg.starts_with("--") {
+                return Err("unknown option");
+            } else {
+                paths.push(arg.clone());
+            }
+        }
+
+        if paths.len() != 1 {
+            return Err("run takes one bytecode file");
+        }
+
+        Ok(Config {
+            command: Command::Run {
+                bytecode_file_path: paths[0].clone(),
+                disasm,
+            },
+            dumps: Dumps::default(),
+            target: Target::host(),
+            syntax: Syntax::Intel,
+            options: Options::default(),
+        })
+    }
+
+    fn new_format(args: &[String]) -> Result<Config, &'static str> {
+        let mut paths = vec![];
+        let mut check = false;
+
+        for arg in args {
+            if arg == "--check" {
+                check = true;
+            } else if arg.starts_with("--") {
+                return Err("unknown option");
+            } else {
+                paths.push(arg.clone());
+            }
+        }
+
+        if paths.is_empty() {
+            return Err("not enough arguments");
+        }
+
+        Ok(Config {
+            command: Command::Format {
+                source_file_paths: paths,
+                check,
+            },
+            dumps: Dumps::default(),
+            target: Target::host(),
+            syntax: Syntax::Intel,
+            options: Options::default(),
+        })
+    }
+}
+
+fn check_syntax(target: Target, syntax: Syntax) -> Result<(), &'static str> {
+    match (target, syntax) {
+        (Target::X86_64Linux | Target::X86_64Darwin, _) | (_, Syntax::Intel) => Ok(()),
+        _ => Err("--masm=att is only supported for x86_64 targets"),
+    }
+}
+
+fn parse_dump_format(format: &str) -> Result<DumpFormat, &'static str> {
+    match format {
+        "dot" => Ok(DumpFormat::Dot),
+        "c" => Ok(DumpFormat::C),
+        _ => Err("unsupported dump format"),
+    }
 }
 
-pub fn run(input: Config) -> Result<(), String> {
-    let contents = match fs::read_to_string(input.source_file_path) {
+/// Returns the exit status of the process.
+pub fn run(input: Config) -> Result<i32, String> {
+    match input.command {
+        Command::Compile {
+            source_file_path,
+            output_file_path,
+            emit,
+        } => compile(
+            &source_file_path,
+            output_file_path,
+            emit,
+            input.dumps,
+            input.target,
+            input.syntax,
+            &input.options,
+        )
+        .map(|_| 0),
+        Command::Interpret { source_file_path } => interpret(&source_file_path),
+        Command::Jit { source_file_path } => jit_compile(&source_file_path, &input.options),
+        Command::Format {
+            source_file_paths,
+            check,
+        } => format_files(&source_file_paths, check).map(|_| 0),
+        Command::Repl => match input.target.backend(input.syntax) {
+            Some(backend) => repl(io::stdin().lock(), &mut io::stdout(), &*backend).map(|_| 0),
+            None => Err("the repl does not support wasm32".to_owned()),
+        },
+        Command::Run {
+            bytecode_file_path,
+            disasm,
+        } => run_bytecode(&bytecode_file_path, disasm),
+    }
+}
+
+fn tokenize(
+    contents: &str,
+    lexer: fn(&str) -> Result<Vec<Token>, TokenError>,
+) -> Result<Vec<Token>, String> {
+    match lexer(contents) {
+        Ok(tokens) => Ok(tokens),
+        Err(e) => match e {
+            TokenizeError => Err("tokenize error".to_owned()),
+            InvalidChar(c) => Err(format!(
+                "tokenize error\n{}",
+                invalid_char_error(contents, c)
+            )),
+        },
+    }
+}
+
+fn format_files(source_file_paths: &[String], check: bool) -> Result<(), String> {
+    let mut unformatted = 0;
+
+    for path in source_file_paths {
+        let contents = match fs::read_to_string(path) {
+            Ok(it) => it,
+            Err(err) => return Err(format!("{}: {}", path, err)),
+        };
+
+        let formatted = format(&tokenize(&contents, lexer_with_trivia)?);
+        if tokenize(&formatted, lexer)? != tokenize(&contents, lexer)? {
+            return Err(format!("{}: formatting would change the program", path));
+        }
+        if formatted == contents {
+            continue;
+        }
+
+        if check {
+            println!("{}: not formatted", path);
+            unformatted += 1;
+        } else if let Err(err) = fs::write(path, formatted) {
+            return Err(format!("{}: {}", path, err));
+        }
+    }
+
+    if unformatted > 0 {
+        return Err(format!("{} file(s) would be reformatted", unformatted));
+    }
+
+    Ok(())
+}
+
+fn front_end(source_file_path: &str) -> Result<(Vec<Tree>, usize), String> {
+    let contents = match fs::read_to_string(source_file_path) {
         Ok(it) => it,
         Err(err) => return Err(err.to_string()),
     };
 
-    let tokens = match lexer(&contents) {
-        Ok(tokens) => tokens,
-        Err(e) => {
-            return match e {
-                TokenizeError => Err("tokenize error".to_owned()),
-                InvalidChar(c) => Err(format!(
-                    "tokenize error\n{}",
-                    invalid_char_error(&contents, c)
-                )),
-            }
-        }
-    };
+    let tokens = tokenize(&contents, lexer)?;
 
     let (tokens, ident_count) = match variable_analysis(tokens) {
         Ok((tokens, ident_count)) => (tokens, ident_count),
         Err(e) => return Err(e.to_string()),
     };
 
-    let trees = match parser(tokens) {
-        Ok(trees) => trees,
-        Err(e) => return Err(format!("parse error: {}", e)),
+    match parser(tokens) {
+        Ok(trees) => Ok((trees, ident_count)),
+        Err(e) => Err(format!("parse error: {}", e)),
+    }
+}
+
+fn interpret(source_file_path: &str) -> Result<i32, String> {
+    let (trees, ident_count) = front_end(source_file_path)?;
+
+    match interpreter(&trees, ident_count) {
+        Ok(value) => Ok(exit_status(value)),
+        Err(e) => Err(format!("runtime error: {}", e)),
+    }
+}
+
+fn jit_compile(source_file_path: &str, options: &Options) -> Result<i32, String> {
+    let (trees, ident_count) = front_end(source_file_path)?;
+
+    let backend = x86_64::X86_64 {
+        syntax: Syntax::Intel,
+        os: Os::Linux,
+    };
+    let asm = generate(trees, ident_count, &backend, options)?;
+    let object = match assemble(&asm) {
+        Ok(object) => object,
+        Err(e) => return Err(format!("assemble error: {}", e)),
+    };
+
+    match jit(&object) {
+        Ok(value) => Ok(exit_status(value)),
+        Err(e) => Err(format!("jit error: {}", e)),
+    }
+}
+
+fn run_bytecode(bytecode_file_path: &str, disasm: bool) -> Result<i32, String> {
+    let bytes = match fs::read(bytecode_file_path) {
+        Ok(it) => it,
+        Err(err) => return Err(format!("{}: {}", bytecode_file_path, err)),
     };
 
-    let asm = match generator(trees, ident_count) {
-        Ok(asm) => asm,
-        Err(e) => return Err(format!("generate error: {}", e)),
+    let program = match deserialize(&bytes) {
+        Ok(program) => program,
+        Err(e) => return Err(format!("{}: {}", bytecode_file_path, e)),
     };
 
-    let mut output_file = match File::create(input.assembly_file_path) {
+    if disasm {
+        print!("{}", disassemble(&program));
+        return Ok(0);
+    }
+
+    match vm(&program) {
+        Ok(value) => Ok(exit_status(value)),
+        Err(e) => Err(format!("runtime error: {}", e)),
+    }
+}
+
+fn compile(
+    source_file_path: &str,
+    output_file_path: Option<String>,
+    emit: Emit,
+    dumps: Dumps,
+    target: Target,
+    syntax: Syntax,
+    options: &Options,
+) -> Result<(), String> {
+    let (trees, ident_count) = front_end(source_file_path)?;
+
+    match dumps.ast {
+        Some(DumpFormat::Dot) => print!("{}", ast_to_dot(&trees)),
+        Some(DumpFormat::C) => print!("{}", unparse(&trees)),
+        None => {}
+    }
+
+    match dumps.cfg {
+        Some(DumpFormat::Dot) => match lower(&trees, ident_count) {
+            Ok(function) => print!("{}", cfg_to_dot(&function)),
+            Err(e) => return Err(format!("generate error: {}", e)),
+        },
+        Some(DumpFormat::C) => return Err("--dump-cfg does not support the c format".to_owned()),
+        None => {}
+    }
+
+    if dumps.ir {
+        match lower(&trees, ident_count) {
+            Ok(function) => print!("{}", function),
+            Err(e) => return Err(format!("generate error: {}", e)),
+        }
+    }
+
+    let output_file_path = match output_file_path {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let output = match emit {
+        Emit::Assembly => match target.backend(syntax) {
+            Some(backend) => generate(trees, ident_count, &*backend, options)?,
+            None => match wat(&trees) {
+                Ok(wat) => wat,
+                Err(e) => return Err(format!("generate error: {}", e)),
+            },
+        }
+        .into_bytes(),
+        // the assembler only reads Intel syntax
+        Emit::Object => {
+            let backend = target
+                .backend(Syntax::Intel)
+                .expect("-c is only accepted for x86_64-linux");
+            let asm = generate(trees, ident_count, &*backend, options)?;
+            match assemble(&asm) {
+                Ok(object) => write_elf(&object),
+                Err(e) => return Err(format!("assemble error: {}", e)),
+            }
+        }
+        Emit::Llvm => match llvm_ir(&trees, target) {
+            Ok(ir) => ir.into_bytes(),
+            Err(e) => return Err(format!("generate error: {}", e)),
+        },
+        Emit::Bytecode => match bytecode(&trees, ident_count) {
+            Ok(program) => serialize(&program),
+            Err(e) => return Err(format!("generate error: {}", e)),
+        },
+    };
+
+    let mut output_file = match File::create(output_file_path) {
         Ok(it) => it,
         Err(err) => return Err(err.to_string()),
     };
 
-    match write!(output_file, "{}", asm) {
+    match output_file.write_all(&output) {
         Ok(it) => it,
         Err(err) => return Err(err.to_string()),
     };
@@ -89,3 +582,20 @@ pub fn run(input: Config) -> Result<(), String> {
 
     Ok(())
 }
+
+fn generate(
+    trees: Vec<Tree>,
+    ident_count: usize,
+    backend: &dyn Backend,
+    options: &Options,
+) -> Result<String, String> {
+    match generator(trees, ident_count, backend, options) {
+        Ok((asm, warnings)) => {
+            for warning in warnings {
+                eprintln!("warning: {}", warning);
+            }
+            Ok(asm)
+        }
+        Err(e) => Err(format!("generate error: {}", e)),
+    }
+}
@@ -0,0 +1,302 @@
+use crate::architecture::Target;
+use crate::generator::GenerateError;
+use crate::tree::*;
+use std::collections::BTreeSet;
+
+/// Lowers the program to textual LLVM IR, for `llc`/`opt` to compile.
+///
+/// Every variable gets an `alloca` in the entry block, as `%var.<name>`, and the value of the last
+/// expression statement is kept in `%last`, which no variable can be called, so falling off the
+/// end of `main` returns it like the other backends do.
+pub fn llvm_ir(trees: &[Tree], target: Target) -> Result<String, GenerateError> {
+    let mut function = Function::default();
+    for tree in trees {
+        function.stmt(tree)?;
+    }
+    let last = function.temp();
+    function.inst(&format!("{} = load i64, ptr %last", last));
+    function.ret(&last);
+
+    let mut ir = format!("target triple = \"{}\"\n\n", triple(target));
+    for callee in &function.callees {
+        ir.push_str(&format!("declare i64 @{}()\n\n", callee));
+    }
+
+    ir.push_str("define i32 @main() {\nentry:\n");
+    for slot in ["%last".to_owned()]
+        .into_iter()
+        .chain(variables(trees).iter().map(|name| format!("%var.{}", name)))
+    {
+        ir.push_str(&format!("  {} = alloca i64\n", slot));
+        ir.push_str(&format!("  store i64 0, ptr {}\n", slot));
+    }
+    ir.push_str(&function.body);
+    ir.push_str("}\n");
+
+    Ok(ir)
+}
+
+fn triple(target: Target) -> &'static str {
+    match target {
+        Target::X86_64Linux => "x86_64-pc-linux-gnu",
+        Target::X86_64Darwin => "x86_64-apple-macosx",
+        Target::Aarch64Linux => "aarch64-unknown-linux-gnu",
+        Target::Aarch64Darwin => "arm64-apple-macosx",
+        Target::Riscv64Linux => "riscv64-unknown-linux-gnu",
+        Target::Wasm32Wasi => "wasm32-unknown-wasi",
+    }
+}
+
+#[derive(Default)]
+struct Function {
+    body: String,
+    temps: usize,
+    labels: usize,
+    callees: BTreeSet<String>,
+}
+
+impl Function {
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("%t{}", self.temps)
+    }
+
+    fn label(&mut self, name: &str) -> String {
+        self.labels += 1;
+        format!("{}{}", name, self.labels)
+    }
+
+    fn inst(&mut self, inst: &str) {
+        self.body.push_str("  ");
+        self.body.push_str(inst);
+        self.body.push('\n');
+    }
+
+    fn block(&mut self, label: &str) {
+        self.body.push_str(label);
+        self.body.push_str(":\n");
+    }
+
+    fn br(&mut self, label: &str) {
+        self.inst(&format!("br label %{}", label));
+    }
+
+    fn load(&mut self, name: &str) -> String {
+        let t = self.temp();
+        self.inst(&format!("{} = load i64, ptr %var.{}", t, name));
+        t
+    }
+
+    fn ret(&mut self, value: &str) {
+        let t = self.temp();
+        self.inst(&format!("{} = trunc i64 {} to i32", t, value));
+        self.inst(&format!("ret i32 {}", t));
+    }
+
+    /// Branches to `then` when `cond` is non-zero. A missing condition, like in `for (;;)`, is
+    /// always true.
+    fn branch(&mut self, cond: &Tree, then: &str, otherwise: &str) -> Result<(), GenerateError> {
+        if *cond == Tree::None {
+            self.br(then);
+            return Ok(());
+        }
+        let value = self.expr(cond)?;
+        let t = self.temp();
+        self.inst(&format!("{} = icmp ne i64 {}, 0", t, value));
+        self.inst(&format!(
+            "br i1 {}, label %{}, label %{}",
+            t, then, otherwise
+        ));
+        Ok(())
+    }
+
+    fn stmt(&mut self, tree: &Tree) -> Result<(), GenerateError> {
+        match tree {
+            Tree::None => {}
+            Tree::Return(expr) => {
+                let value = self.expr(expr)?;
+                self.ret(&value);
+                // whatever follows a return is unreachable but still needs a block
+                let dead = self.label("dead");
+                self.block(&dead);
+            }
+            Tree::If(cond, stmt) => {
+                let (then, end) = (self.label("then"), self.label("end"));
+                self.branch(cond, &then, &end)?;
+                self.block(&then);
+                self.stmt(stmt)?;
+                self.br(&end);
+                self.block(&end);
+            }
+            Tree::IfElse(cond, stmt, else_stmt) => {
+                let (then, otherwise, end) =
+                    (self.label("then"), self.label("else"), self.label("end"));
+                self.branch(cond, &then, &otherwise)?;
+                self.block(&then);
+                self.stmt(stmt)?;
+                self.br(&end);
+                self.block(&otherwise);
+                self.stmt(else_stmt)?;
+                self.br(&end);
+                self.block(&end);
+            }
+            Tree::While(cond, stmt) => {
+                let (begin, body, end) =
+                    (self.label("begin"), self.label("body"), self.label("end"));
+                self.br(&begin);
+                self.block(&begin);
+                self.branch(cond, &body, &end)?;
+                self.block(&body);
+                self.stmt(stmt)?;
+                self.br(&begin);
+                self.block(&end);
+            }
+            Tree::For(init, cond, step, stmt) => {
+                self.stmt(init)?;
+                let (begin, body, end) =
+                    (self.label("begin"), self.label("body"), self.label("end"));
+                self.br(&begin);
+                self.block(&begin);
+                self.branch(cond, &body, &end)?;
+                self.block(&body);
+                self.stmt(stmt)?;
+                self.stmt(step)?;
+                self.br(&begin);
+                self.block(&end);
+            }
+            Tree::Block(trees) => {
+                for tree in trees {
+                    self.stmt(tree)?;
+                }
+            }
+            expr => {
+                let value = self.expr(expr)?;
+                self.inst(&format!("store i64 {}, ptr %last", value));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the operand holding the value: a constant or a temporary.
+    fn expr(&mut self, tree: &Tree) -> Result<String, GenerateError> {
+        match tree {
+            Tree::Int(n) => Ok((*n as i64).to_string()),
+            Tree::Val { name, .. } => Ok(self.load(name)),
+            // `main` is the function being defined, which returns an i32
+            Tree::Func { name } if name == "main" => {
+                let (t, sext) = (self.temp(), self.temp());
+                self.inst(&format!("{} = call i32 @main()", t));
+                self.inst(&format!("{} = sext i32 {} to i64", sext, t));
+                Ok(sext)
+            }
+            Tree::Func { name } => {
+                self.callees.insert(name.clone());
+                let t = self.temp();
+                self.inst(&format!("{} = call i64 @{}()", t, name));
+                Ok(t)
+            }
+            Tree::Node(NodeKind::Assign, lhs, rhs) => {
+                let name = match &**lhs {
+                    Tree::Val { name, .. } => name,
+                    _ => {
+                        return Err(
+                            "The left-hand side value of the assignment is not a variable"
+                                .to_owned(),
+                        )
+                    }
+                };
+                let value = self.expr(rhs)?;
+                self.inst(&format!("store i64 {}, ptr %var.{}", value, name));
+                Ok(value)
+            }
+            Tree::Node(kind, lhs, rhs) => {
+                let lhs = self.expr(lhs)?;
+                let rhs = self.expr(rhs)?;
+                let t = self.temp();
+                let op = match kind {
+                    NodeKind::Add => "add",
+                    NodeKind::Sub => "sub",
+                    NodeKind::Mul => "mul",
+                    NodeKind::Div => "sdiv",
+                    NodeKind::Equality => "icmp eq",
+                    NodeKind::Nonequality => "icmp ne",
+                    NodeKind::Less => "icmp slt",
+                    NodeKind::LessOrEqual => "icmp sle",
+                    NodeKind::Assign => unreachable!(),
+                };
+                self.inst(&format!("{} = {} i64 {}, {}", t, op, lhs, rhs));
+                if op.starts_with("icmp") {
+                    let zext = self.temp();
+                    self.inst(&format!("{} = zext i1 {} to i64", zext, t));
+                    return Ok(zext);
+                }
+                Ok(t)
+            }
+            _ => Err("unexpected statement in expression".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::lexer, parser::parser, variable::variable_analysis};
+
+    fn ir(src: &str) -> String {
+        let (tokens, _) = variable_analysis(lexer(src).unwrap()).unwrap();
+        llvm_ir(&parser(tokens).unwrap(), Target::X86_64Linux).unwrap()
+    }
+
+    #[test]
+    fn expr_test() {
+        assert_eq!(
+            "target triple = \"x86_64-pc-linux-gnu\"
+
+declare i64 @foo()
+
+define i32 @main() {
+entry:
+  %last = alloca i64
+  store i64 0, ptr %last
+  %var.a = alloca i64
+  store i64 0, ptr %var.a
+  store i64 3, ptr %var.a
+  store i64 3, ptr %last
+  %t1 = load i64, ptr %var.a
+  %t2 = call i64 @foo()
+  %t3 = icmp slt i64 %t1, %t2
+  %t4 = zext i1 %t3 to i64
+  store i64 %t4, ptr %last
+  %t5 = load i64, ptr %last
+  %t6 = trunc i64 %t5 to i32
+  ret i32 %t6
+}
+",
+            ir("a = 3; a < foo();")
+        );
+    }
+
+    #[test]
+    fn control_flow_test() {
+        let ir = ir("for (i = 0; i < 3; i = i + 1) if (i == 2) return i; else ; return 0;");
+
+        assert!(ir.contains("  br label %begin1\nbegin1:\n"));
+        assert!(ir.contains("  br i1 %t4, label %body2, label %end3\nbody2:\n"));
+        assert!(ir.contains("  ret i32 %t10\ndead7:\n  br label %end6\n"));
+        assert!(ir.contains("  br label %begin1\nend3:\n"));
+    }
+
+    #[test]
+    fn names_test() {
+        // a variable called `last` has its own slot
+        let last = ir("last = 5; last + 1;");
+        assert_eq!(1, last.matches("  %last = alloca i64\n").count());
+        assert_eq!(1, last.matches("  %var.last = alloca i64\n").count());
+        assert!(last.contains("  store i64 %t2, ptr %last\n"));
+
+        // main calls itself rather than something it declares
+        let recursive = ir("return main();");
+        assert!(!recursive.contains("declare"));
+        assert!(recursive.contains("  %t1 = call i32 @main()\n  %t2 = sext i32 %t1 to i64\n"));
+    }
+}
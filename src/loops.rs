@@ -0,0 +1,552 @@
+use crate::ir::{BinOp, Block, BlockId, Function, Inst, Terminator, VReg};
+use crate::ssa::{dominators, predecessors};
+
+/// A natural loop: the header and the blocks that reach one of its back edges without going
+/// through it.
+struct Loop {
+    header: BlockId,
+    body: Vec<bool>,
+}
+
+/// A basic induction variable: a header phi that goes up or down by the same invariant step on
+/// every way round the loop.
+struct Induction {
+    phi: VReg,
+    /// the value coming in from the preheader
+    init: VReg,
+    step: VReg,
+    op: BinOp,
+    /// `phi op step`, which the back edges pass to the phi
+    next: VReg,
+}
+
+/// Gives every loop a preheader, hoists the computations that give the same value every time
+/// round into it, and turns multiplications of an induction variable by an invariant into a
+/// value stepped by addition. Needs SSA form, where a value defined outside a loop is invariant
+/// inside it.
+///
+/// Only instructions that can't trap move, so hoisting one out of a loop that runs no times is
+/// harmless. Divisions stay.
+pub fn optimize_loops(function: &mut Function) {
+    while let Some(header) = natural_loops(function)
+        .into_iter()
+        .find(|l| needs_preheader(function, l))
+        .map(|l| l.header)
+    {
+        insert_preheader(function, header);
+    }
+
+    // inner loops first, so what they hoist can go on out of the loops around them
+    let mut loops = natural_loops(function);
+    loops.sort_by_key(|l| l.body.iter().filter(|in_loop| **in_loop).count());
+    for l in &loops {
+        if let Some(preheader) = preheader(function, l) {
+            hoist(function, l, preheader);
+        }
+    }
+    for l in &loops {
+        if let Some(preheader) = preheader(function, l) {
+            reduce(function, l, preheader);
+        }
+    }
+}
+
+fn dominates(idom: &[Option<BlockId>], a: BlockId, mut b: BlockId) -> bool {
+    loop {
+        if a == b {
+            return true;
+        }
+        match idom[b] {
+            Some(parent) if parent != b => b = parent,
+            _ => return false,
+        }
+    }
+}
+
+/// The natural loops, one for each header, from the back edges: the edges to a block that
+/// dominates where they come from.
+fn natural_loops(function: &Function) -> Vec<Loop> {
+    let idom = dominators(function);
+    let predecessors = predecessors(function);
+    let mut loops: Vec<Loop> = vec![];
+    for (tail, block) in function.blocks.iter().enumerate() {
+        for header in block.terminator.successors() {
+            if idom[tail].is_none() || !dominates(&idom, header, tail) {
+                continue;
+            }
+            let index = match loops.iter().position(|l| l.header == header) {
+                Some(index) => index,
+                None => {
+                    let mut body = vec![false; function.blocks.len()];
+                    body[header] = true;
+                    loops.push(Loop { header, body });
+                    loops.len() - 1
+                }
+            };
+            let body = &mut loops[index].body;
+            let mut work = vec![tail];
+            while let Some(block) = work.pop() {
+                if !body[block] {
+                    body[block] = true;
+                    work.extend(&predecessors[block]);
+                }
+            }
+        }
+    }
+    loops
+}
+
+/// The one block outside the loop that leads into it, if it goes nowhere else.
+fn preheader(function: &Function, l: &Loop) -> Option<BlockId> {
+    let outside: Vec<_> = predecessors(function)[l.header]
+        .iter()
+        .copied()
+        .filter(|predecessor| !l.body[*predecessor])
+        .collect();
+    match outside[..] {
+        [block] if function.blocks[block].terminator == Terminator::Jump(l.header) => Some(block),
+        _ => None,
+    }
+}
+
+fn needs_preheader(function: &Function, l: &Loop) -> bool {
+    l.header != 0 && preheader(function, l).is_none()
+}
+
+/// Puts an empty block in front of `header` that the edges from outside its loop go through
+/// instead. The phis of the header take what those edges bring from a phi in the new block.
+fn insert_preheader(function: &mut Function, header: BlockId) {
+    let l = natural_loops(function)
+        .into_iter()
+        .find(|l| l.header == header)
+        .expect("the header of a loop");
+    let outside: Vec<_> = predecessors(function)[header]
+        .iter()
+        .copied()
+        .filter(|predecessor| !l.body[*predecessor])
+        .collect();
+
+    // the new block takes the place of the header, which moves down one
+    let renumber = |block: BlockId| if block >= header { block + 1 } else { block };
+    for block in &mut function.blocks {
+        for successor in block.terminator.successors_mut() {
+            *successor = renumber(*successor);
+        }
+        for inst in &mut block.insts {
+            if let Inst::Phi { args, .. } = inst {
+                for (predecessor, _) in args {
+                    *predecessor = renumber(*predecessor);
+                }
+            }
+        }
+    }
+    let outside: Vec<_> = outside.into_iter().map(renumber).collect();
+    for &block in &outside {
+        for successor in function.blocks[block].terminator.successors_mut() {
+            if *successor == header + 1 {
+                *successor = header;
+            }
+        }
+    }
+
+    let mut insts = vec![];
+    for i in 0..function.blocks[header].insts.len() {
+        let dst = match function.blocks[header].insts[i] {
+            Inst::Phi { .. } if outside.len() > 1 => function.new_vreg(),
+            _ => 0,
+        };
+        let args = match &mut function.blocks[header].insts[i] {
+            Inst::Phi { args, .. } => args,
+            _ => continue,
+        };
+        let (entering, mut staying): (Vec<_>, Vec<_>) = args
+            .drain(..)
+            .partition(|(predecessor, _)| outside.contains(predecessor));
+        match entering[..] {
+            [(_, value)] => staying.push((header, value)),
+            _ => {
+                staying.push((header, dst));
+                insts.push(Inst::Phi {
+                    dst,
+                    args: entering,
+                });
+            }
+        }
+        *args = staying;
+    }
+    function.blocks.insert(
+        header,
+        Block {
+            insts,
+            terminator: Terminator::Jump(header + 1),
+            stmts: vec![],
+        },
+    );
+}
+
+/// The registers defined inside the loop.
+fn defined_in(function: &Function, l: &Loop) -> Vec<bool> {
+    let mut defined = vec![false; function.vregs];
+    for (block, _) in function.blocks.iter().zip(&l.body).filter(|(_, b)| **b) {
+        for dst in block.insts.iter().filter_map(Inst::dst) {
+            defined[dst] = true;
+        }
+    }
+    defined
+}
+
+/// Moves the instructions whose operands all come from outside the loop to the end of the
+/// preheader, until there are none left.
+fn hoist(function: &mut Function, l: &Loop, preheader: BlockId) {
+    let mut defined = defined_in(function, l);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in (0..function.blocks.len()).filter(|block| l.body[*block]) {
+            let mut i = 0;
+            while i < function.blocks[block].insts.len() {
+                let inst = &function.blocks[block].insts[i];
+                let movable = match inst {
+                    Inst::Const { .. } | Inst::Copy { .. } => true,
+                    Inst::Binary { op, .. } => *op != BinOp::Div,
+                    _ => false,
+                };
+                if !movable || inst.operands().iter().any(|vreg| defined[*vreg]) {
+                    i += 1;
+                    continue;
+                }
+                let inst = function.blocks[block].insts.remove(i);
+                defined[inst.dst().expect("movable instructions define a register")] = false;
+                function.blocks[preheader].insts.push(inst);
+                changed = true;
+            }
+        }
+    }
+}
+
+fn induction_variables(function: &Function, l: &Loop, preheader: BlockId) -> Vec<Induction> {
+    let defined = defined_in(function, l);
+    let definition = |vreg: VReg| {
+        function
+            .blocks
+            .iter()
+            .flat_map(|block| &block.insts)
+            .find(|inst| inst.dst() == Some(vreg))
+    };
+
+    let mut variables = vec![];
+    for inst in &function.blocks[l.header].insts {
+        let (phi, args) = match inst {
+            Inst::Phi { dst, args } => (*dst, args),
+            _ => continue,
+        };
+        let init = args.iter().find(|(block, _)| *block == preheader);
+        let mut back: Vec<VReg> = args
+            .iter()
+            .filter(|(block, _)| *block != preheader)
+            .map(|(_, value)| *value)
+            .collect();
+        back.dedup();
+        let (init, next) = match (init, &back[..]) {
+            (Some((_, init)), [next]) => (*init, *next),
+            _ => continue,
+        };
+        let (op, step) = match definition(next) {
+            Some(&Inst::Binary { op, lhs, rhs, .. }) => match op {
+                BinOp::Add | BinOp::Sub if lhs == phi => (op, rhs),
+                BinOp::Add if rhs == phi => (op, lhs),
+                _ => continue,
+            },
+            _ => continue,
+        };
+        if !defined[step] {
+            variables.push(Induction {
+                phi,
+                init,
+                step,
+                op,
+                next,
+            });
+        }
+    }
+    variables
+}
+
+/// Replaces `phi * k` for an induction variable and an invariant `k` with a new induction
+/// variable that starts at `init * k` and moves by `step * k`, which wraps to the same values.
+fn reduce(function: &mut Function, l: &Loop, preheader: BlockId) {
+    let variables = induction_variables(function, l, preheader);
+    let defined = defined_in(function, l);
+    // each product made so far, by the induction variable and the factor
+    let mut products: Vec<(VReg, VReg, VReg)> = vec![];
+
+    let mut multiplications = vec![];
+    for (block, _) in function.blocks.iter().zip(&l.body).filter(|(_, b)| **b) {
+        for inst in &block.insts {
+            if let Inst::Binary {
+                op: BinOp::Mul,
+                dst,
+                lhs,
+                rhs,
+            } = *inst
+            {
+                for (v, variable) in variables.iter().enumerate() {
+                    let factor = match variable.phi {
+                        phi if phi == lhs => rhs,
+                        phi if phi == rhs => lhs,
+                        _ => continue,
+                    };
+                    if !defined[factor] {
+                        multiplications.push((dst, v, factor));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    for (dst, variable, factor) in multiplications {
+        let v = &variables[variable];
+
+        let product = match products.iter().find(|p| p.0 == v.phi && p.1 == factor) {
+            Some(&(_, _, product)) => product,
+            None => {
+                let (init, step) = (function.new_vreg(), function.new_vreg());
+                let (product, next) = (function.new_vreg(), function.new_vreg());
+                function.blocks[preheader].insts.extend([
+                    Inst::Binary {
+                        op: BinOp::Mul,
+                        dst: init,
+                        lhs: v.init,
+                        rhs: factor,
+                    },
+                    Inst::Binary {
+                        op: BinOp::Mul,
+                        dst: step,
+                        lhs: v.step,
+                        rhs: factor,
+                    },
+                ]);
+
+                let header = &mut function.blocks[l.header].insts;
+                let args = header
+                    .iter()
+                    .find_map(|inst| match inst {
+                        Inst::Phi { dst, args } if *dst == v.phi => Some(args),
+                        _ => None,
+                    })
+                    .expect("the induction variable is a phi of the header")
+                    .iter()
+                    .map(|&(block, _)| (block, if block == preheader { init } else { next }))
+                    .collect();
+                header.insert(0, Inst::Phi { dst: product, args });
+
+                let (n, at) = function
+                    .blocks
+                    .iter()
+                    .enumerate()
+                    .find_map(|(n, block)| {
+                        let at = block.insts.iter().position(|i| i.dst() == Some(v.next))?;
+                        Some((n, at))
+                    })
+                    .expect("the next value is defined in the loop");
+                function.blocks[n].insts.insert(
+                    at + 1,
+                    Inst::Binary {
+                        op: v.op,
+                        dst: next,
+                        lhs: product,
+                        rhs: step,
+                    },
+                );
+                products.push((v.phi, factor, product));
+                product
+            }
+        };
+
+        let inst = function
+            .blocks
+            .iter_mut()
+            .flat_map(|block| &mut block.insts)
+            .find(|inst| inst.dst() == Some(dst))
+            .expect("the multiplication is still there");
+        *inst = Inst::Copy { dst, src: product };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fold::fold;
+    use crate::ir::{assert_matches_interpreter, lower_source, run};
+    use crate::ssa::to_ssa;
+
+    fn optimize(function: &mut Function) {
+        to_ssa(function);
+        fold(function);
+        optimize_loops(function);
+    }
+
+    fn optimize_source(src: &str) -> Function {
+        let mut function = lower_source(src);
+        optimize(&mut function);
+        function
+    }
+
+    /// The instructions inside some loop.
+    fn in_loops(function: &Function) -> Vec<&Inst> {
+        let loops = natural_loops(function);
+        function
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(n, _)| loops.iter().any(|l| l.body[*n]))
+            .flat_map(|(_, block)| &block.insts)
+            .collect()
+    }
+
+    fn multiplications(insts: &[&Inst]) -> usize {
+        insts
+            .iter()
+            .filter(|inst| matches!(inst, Inst::Binary { op: BinOp::Mul, .. }))
+            .count()
+    }
+
+    #[test]
+    fn hoist_test() {
+        let function = optimize_source(
+            "a = x(); s = 0; i = 0; while (i < 5) { s = s + a * 3; i = i + 1; } s;",
+        );
+        let insts = in_loops(&function);
+        assert_eq!(0, multiplications(&insts));
+        assert!(insts.iter().all(|inst| !matches!(inst, Inst::Const { .. })));
+
+        // a division may trap, so it stays where it is
+        let function = optimize_source("a = x(); s = 0; while (s < 5) s = s + 10 / a; s;");
+        assert!(in_loops(&function)
+            .iter()
+            .any(|inst| matches!(inst, Inst::Binary { op: BinOp::Div, .. })));
+    }
+
+    #[test]
+    fn strength_reduction_test() {
+        let function = optimize_source("s = 0; for (i = 0; i < 10; i = i + 1) s = s + i * 8; s;");
+        let insts = in_loops(&function);
+        assert_eq!(0, multiplications(&insts));
+        // one phi more than the variables `s` and `i` need
+        let phis = insts
+            .iter()
+            .filter(|inst| matches!(inst, Inst::Phi { .. }))
+            .count();
+        assert_eq!(3, phis);
+
+        // `i * i` doesn't go up by a fixed step
+        let function = optimize_source("s = 0; for (i = 0; i < 10; i = i + 1) s = s + i * i; s;");
+        assert_eq!(1, multiplications(&in_loops(&function)));
+    }
+
+    #[test]
+    fn preheader_test() {
+        let function = optimize_source(
+            "s = 0; for (i = 0; i < 3; i = i + 1) for (j = 0; j < 3; j = j + 1) s = s + i * 4 + j; s;",
+        );
+        let loops = natural_loops(&function);
+        assert_eq!(2, loops.len());
+        for l in &loops {
+            assert!(preheader(&function, l).is_some());
+        }
+        // `i * 4` goes out of the inner loop first and is then stepped along by the outer one
+        assert_eq!(0, multiplications(&in_loops(&function)));
+    }
+
+    #[test]
+    fn merged_entry_test() {
+        // b3 counts up to 10 from whichever of b1 and b2 comes first
+        let block = |insts, terminator| Block {
+            insts,
+            terminator,
+            stmts: vec![],
+        };
+        let constant = |dst, value| Inst::Const { dst, value };
+        let mut function = Function {
+            name: "main".to_owned(),
+            vars: vec![],
+            blocks: vec![
+                block(
+                    vec![constant(0, 1), constant(6, 10), constant(7, 1)],
+                    Terminator::Branch {
+                        cond: 0,
+                        then_block: 1,
+                        else_block: 2,
+                    },
+                ),
+                block(vec![constant(1, 4)], Terminator::Jump(3)),
+                block(vec![constant(2, 5)], Terminator::Jump(3)),
+                block(
+                    vec![
+                        Inst::Phi {
+                            dst: 3,
+                            args: vec![(1, 1), (2, 2), (4, 5)],
+                        },
+                        Inst::Binary {
+                            op: BinOp::Lt,
+                            dst: 4,
+                            lhs: 3,
+                            rhs: 6,
+                        },
+                    ],
+                    Terminator::Branch {
+                        cond: 4,
+                        then_block: 4,
+                        else_block: 5,
+                    },
+                ),
+                block(
+                    vec![Inst::Binary {
+                        op: BinOp::Add,
+                        dst: 5,
+                        lhs: 3,
+                        rhs: 7,
+                    }],
+                    Terminator::Jump(3),
+                ),
+                block(vec![], Terminator::Return(3)),
+            ],
+            vregs: 8,
+        };
+        let expected = run(&function);
+        optimize_loops(&mut function);
+
+        assert_eq!(7, function.blocks.len());
+        assert_eq!(Terminator::Jump(4), function.blocks[3].terminator);
+        assert_eq!(
+            vec![Inst::Phi {
+                dst: 8,
+                args: vec![(1, 1), (2, 2)]
+            }],
+            function.blocks[3].insts
+        );
+        assert!(matches!(
+            &function.blocks[4].insts[0],
+            Inst::Phi { args, .. } if *args == vec![(5, 5), (3, 8)]
+        ));
+        assert_eq!(expected, run(&function));
+    }
+
+    #[test]
+    fn semantics_test() {
+        let cases = [
+            "s = 0; for (i = 0; i < 10; i = i + 1) s = s + i * 8; s;",
+            "s = 0; for (i = 10; 0 < i; i = i - 1) s = s + 3 * i - i * 2; s;",
+            "s = 0; i = 0; while (i < 5) { s = s + 2 * 3 + i * i; i = i + 1; } s;",
+            "s = 0; for (i = 0; i < 3; i = i + 1) for (j = 0; j < 3; j = j + 1) s = s + i * 4 + j; s;",
+            "s = 7; for (i = 0; i < 0; i = i + 1) s = i * 5; s;",
+            "s = 1; i = 1; while (i < 100) { i = i + i; s = s + i * 3; } s;",
+            "a = 0; for (i = 0; i < 4; i = i + 1) { b = i * 6; if (b == 12) a = b; } a;",
+        ];
+
+        for src in cases {
+            assert_matches_interpreter(src, optimize);
+        }
+    }
+}
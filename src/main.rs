@@ -10,8 +10,11 @@ fn main() {
         process::exit(1);
     });
 
-    if let Err(e) = ioc2::run(input) {
-        eprintln!("Application error: {}", e);
-        process::exit(1);
+    match ioc2::run(input) {
+        Ok(status) => process::exit(status),
+        Err(e) => {
+            eprintln!("Application error: {}", e);
+            process::exit(1);
+        }
     }
 }
@@ -24,6 +24,7 @@ fn stmt(tokens: Vec<Token>) -> Result<(Tree, Vec<Token>), TreeError> {
             Token::For => parse_for(tokens[1..].to_vec())?,
             Token::Return => parse_return(tokens[1..].to_vec())?,
             Token::CloseBrace => (Tree::None, tokens[1..].to_vec()),
+            Token::Semicolon => (Tree::None, tokens[1..].to_vec()),
             Token::OpenBrace => {
                 fn go(tokens: Vec<Token>) -> Result<(Vec<Tree>, Vec<Token>), TreeError> {
                     if tokens.is_empty() {
@@ -388,24 +389,65 @@ fn closebrace_error() -> TreeError {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use crate::{lexer::lexer, variable::variable_analysis};
 
     use super::*;
 
+    /// The programs the tests below parse, for other modules' tests to reuse.
+    pub(crate) const SOURCES: [&str; 23] = [
+        PARSER,
+        ONE_INT,
+        ADD,
+        SUB,
+        MUL,
+        DIV,
+        PLUS,
+        MINUS,
+        EQUALITY,
+        NONEQUALITY,
+        LESS_OR_EQUAL,
+        LESS,
+        GREATER_OR_EQUAL,
+        GREATER,
+        PAREN,
+        ASSIGN,
+        STATEMENT,
+        EMPTY_STATEMENT,
+        RETURN,
+        IF,
+        IF_ELSE,
+        WHILE,
+        FOR,
+    ];
+
+    const PARSER: &str = "\ncolumn = 5;\nrow = 40;\ncolumn * row;\n";
+    const ONE_INT: &str = "500;";
+    const ADD: &str = "1 + 2;";
+    const SUB: &str = "2 - 1;";
+    const MUL: &str = "4 * 5;";
+    const DIV: &str = "8 / 4;";
+    const PLUS: &str = "+1;";
+    const MINUS: &str = "-1;";
+    const EQUALITY: &str = "1 == 1;";
+    const NONEQUALITY: &str = "1 != 1;";
+    const LESS_OR_EQUAL: &str = "2 <= 1;";
+    const LESS: &str = "2 < 1;";
+    const GREATER_OR_EQUAL: &str = "2 >= 1;";
+    const GREATER: &str = "2 > 1;";
+    const PAREN: &str = "2 * (1 + 2);";
+    const ASSIGN: &str = "a = 123;";
+    const STATEMENT: &str = "1;2;";
+    const EMPTY_STATEMENT: &str = ";1;";
+    const RETURN: &str = "return 0;";
+    const IF: &str = "if(0)return0;";
+    const IF_ELSE: &str = "if(0)return0;else return1;";
+    const WHILE: &str = "while (2 > 1) 1 + 1;";
+    const FOR: &str = "for(;;)0;";
+
     #[test]
     fn parser_test() {
-        let (query, _ident_count) = variable_analysis(
-            lexer(
-                "
-column = 5;
-row = 40;
-column * row;
-",
-            )
-            .unwrap(),
-        )
-        .unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(PARSER).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![
@@ -431,14 +473,14 @@ column * row;
 
     #[test]
     fn one_int_test() {
-        let (query, _ident_count) = variable_analysis(lexer("500;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(ONE_INT).unwrap()).unwrap();
 
         assert_eq!(Ok(vec![Tree::new_int(500)]), parser(query));
     }
 
     #[test]
     fn add_test() {
-        let (query, _ident_count) = variable_analysis(lexer("1 + 2;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(ADD).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_tree(
@@ -452,7 +494,7 @@ column * row;
 
     #[test]
     fn sub_test() {
-        let (query, _ident_count) = variable_analysis(lexer("2 - 1;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(SUB).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_tree(
@@ -466,7 +508,7 @@ column * row;
 
     #[test]
     fn mul_test() {
-        let (query, _ident_count) = variable_analysis(lexer("4 * 5;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(MUL).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_tree(
@@ -480,7 +522,7 @@ column * row;
 
     #[test]
     fn div_test() {
-        let (query, _ident_count) = variable_analysis(lexer("8 / 4;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(DIV).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_tree(
@@ -494,8 +536,8 @@ column * row;
 
     #[test]
     fn unary_test() {
-        let (query1, _ident_count) = variable_analysis(lexer("+1;").unwrap()).unwrap();
-        let (query2, _ident_count) = variable_analysis(lexer("-1;").unwrap()).unwrap();
+        let (query1, _ident_count) = variable_analysis(lexer(PLUS).unwrap()).unwrap();
+        let (query2, _ident_count) = variable_analysis(lexer(MINUS).unwrap()).unwrap();
 
         assert_eq!(Ok(vec![Tree::new_int(1)]), parser(query1));
         assert_eq!(
@@ -510,7 +552,7 @@ column * row;
 
     #[test]
     fn equality_test() {
-        let (query, _ident_count) = variable_analysis(lexer("1 == 1;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(EQUALITY).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_tree(
@@ -524,7 +566,7 @@ column * row;
 
     #[test]
     fn noneequality_test() {
-        let (query, _ident_count) = variable_analysis(lexer("1 != 1;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(NONEQUALITY).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_tree(
@@ -538,7 +580,7 @@ column * row;
 
     #[test]
     fn less_or_equal_test() {
-        let (query, _ident_count) = variable_analysis(lexer("2 <= 1;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(LESS_OR_EQUAL).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_tree(
@@ -552,7 +594,7 @@ column * row;
 
     #[test]
     fn less_test() {
-        let (query, _ident_count) = variable_analysis(lexer("2 < 1;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(LESS).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_tree(
@@ -566,7 +608,7 @@ column * row;
 
     #[test]
     fn greater_or_equal_test() {
-        let (query, _ident_count) = variable_analysis(lexer("2 >= 1;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(GREATER_OR_EQUAL).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_tree(
@@ -580,7 +622,7 @@ column * row;
 
     #[test]
     fn greater_test() {
-        let (query, _ident_count) = variable_analysis(lexer("2 > 1;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(GREATER).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_tree(
@@ -594,7 +636,7 @@ column * row;
 
     #[test]
     fn paren_test() {
-        let (query, _ident_count) = variable_analysis(lexer("2 * (1 + 2);").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(PAREN).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_tree(
@@ -608,7 +650,7 @@ column * row;
 
     #[test]
     fn assign_test() {
-        let (query, _ident_count) = variable_analysis(lexer("a = 123;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(ASSIGN).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_tree(
@@ -622,21 +664,28 @@ column * row;
 
     #[test]
     fn statement_test() {
-        let (query, _ident_count) = variable_analysis(lexer("1;2;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(STATEMENT).unwrap()).unwrap();
 
         assert_eq!(Ok(vec![Tree::new_int(1), Tree::new_int(2)]), parser(query));
     }
 
+    #[test]
+    fn empty_statement_test() {
+        let (query, _ident_count) = variable_analysis(lexer(EMPTY_STATEMENT).unwrap()).unwrap();
+
+        assert_eq!(Ok(vec![Tree::None, Tree::new_int(1)]), parser(query));
+    }
+
     #[test]
     fn return_test() {
-        let (query, _ident_count) = variable_analysis(lexer("return 0;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(RETURN).unwrap()).unwrap();
 
         assert_eq!(Ok(vec![Tree::new_return(Tree::new_int(0))]), parser(query));
     }
 
     #[test]
     fn if_test() {
-        let (query, _ident_count) = variable_analysis(lexer("if(0)return0;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(IF).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_if(
@@ -649,8 +698,7 @@ column * row;
 
     #[test]
     fn if_else_test() {
-        let (query, _ident_count) =
-            variable_analysis(lexer("if(0)return0;else return1;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(IF_ELSE).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_if_else(
@@ -664,8 +712,7 @@ column * row;
 
     #[test]
     fn while_test() {
-        let (query, _ident_count) =
-            variable_analysis(lexer("while (2 > 1) 1 + 1;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(WHILE).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_while(
@@ -678,7 +725,7 @@ column * row;
 
     #[test]
     fn for_test() {
-        let (query, _ident_count) = variable_analysis(lexer("for(;;)0;").unwrap()).unwrap();
+        let (query, _ident_count) = variable_analysis(lexer(FOR).unwrap()).unwrap();
 
         assert_eq!(
             Ok(vec![Tree::new_for(
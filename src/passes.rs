@@ -0,0 +1,231 @@
+use crate::dce::{dead_code, dead_stores};
+use crate::fold::fold;
+use crate::gvn::gvn;
+use crate::ir::Function;
+use crate::loops::optimize_loops;
+use crate::ssa::{out_of_ssa, to_ssa};
+use crate::tailcall::tail_calls;
+use crate::warning::Warning;
+use std::time::{Duration, Instant};
+
+/// A pass over the IR, by the name `--passes` and `--print-after` know it by.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Pass {
+    /// `dse`: removes unreachable blocks and stores nothing reads
+    DeadStores,
+    /// `tailcall`: turns self-recursive tail calls into jumps
+    TailCalls,
+    /// `fold`: evaluates constants and simplifies identities
+    Fold,
+    /// `gvn`: numbers values to drop repeated expressions and copies
+    Gvn,
+    /// `loops`: hoists invariants and strength-reduces induction variables
+    Loops,
+    /// `dce`: removes constant branches, unreachable blocks and unused values
+    DeadCode,
+}
+
+impl Pass {
+    pub fn parse(name: &str) -> Result<Pass, &'static str> {
+        match name {
+            "dse" => Ok(Pass::DeadStores),
+            "tailcall" => Ok(Pass::TailCalls),
+            "fold" => Ok(Pass::Fold),
+            "gvn" => Ok(Pass::Gvn),
+            "loops" => Ok(Pass::Loops),
+            "dce" => Ok(Pass::DeadCode),
+            _ => Err("unknown pass"),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::DeadStores => "dse",
+            Pass::TailCalls => "tailcall",
+            Pass::Fold => "fold",
+            Pass::Gvn => "gvn",
+            Pass::Loops => "loops",
+            Pass::DeadCode => "dce",
+        }
+    }
+
+    /// Whether the pass works on variables, before they are promoted to SSA values.
+    fn before_ssa(&self) -> bool {
+        matches!(self, Pass::DeadStores | Pass::TailCalls)
+    }
+
+    fn run(&self, function: &mut Function) -> Vec<Warning> {
+        match self {
+            Pass::DeadStores => return dead_stores(function),
+            Pass::TailCalls => tail_calls(function),
+            Pass::Fold => fold(function),
+            Pass::Gvn => gvn(function),
+            Pass::Loops => optimize_loops(function),
+            Pass::DeadCode => return dead_code(function),
+        }
+        vec![]
+    }
+}
+
+/// Parses the comma-separated list of `--passes`. The passes on variables have to come first.
+pub fn parse_passes(list: &str) -> Result<Vec<Pass>, &'static str> {
+    let passes = list
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(Pass::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+    if passes
+        .windows(2)
+        .any(|w| !w[0].before_ssa() && w[1].before_ssa())
+    {
+        return Err("dse and tailcall have to come before the other passes");
+    }
+    Ok(passes)
+}
+
+/// The passes to run over the IR and what to report about them.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Pipeline {
+    pub passes: Vec<Pass>,
+    /// the passes after which the IR is printed to stderr, from `--print-after`
+    pub print_after: Vec<Pass>,
+    /// prints how long each step took to stderr, from `--time-passes`
+    pub time: bool,
+}
+
+impl Pipeline {
+    pub fn new(passes: Vec<Pass>) -> Pipeline {
+        Pipeline {
+            passes,
+            ..Pipeline::default()
+        }
+    }
+
+    /// Runs the passes over `function`, promoting the variables to SSA values after the ones that
+    /// work on variables and turning the phis back into copies at the end. Returns what the
+    /// passes warn about.
+    pub fn run(&self, function: &mut Function) -> Vec<Warning> {
+        let mut warnings = vec![];
+        let mut times = vec![];
+        let (before, after) = self
+            .passes
+            .split_at(self.passes.partition_point(Pass::before_ssa));
+        for pass in before {
+            self.pass(*pass, function, &mut warnings, &mut times);
+        }
+        timed(&mut times, "ssa", || to_ssa(function));
+        for pass in after {
+            self.pass(*pass, function, &mut warnings, &mut times);
+        }
+        timed(&mut times, "out-of-ssa", || out_of_ssa(function));
+
+        if self.time {
+            let total: Duration = times.iter().map(|(_, elapsed)| *elapsed).sum();
+            eprintln!("*** pass timing ***");
+            for (name, elapsed) in times {
+                eprintln!("{:>10.3}ms  {}", elapsed.as_secs_f64() * 1000.0, name);
+            }
+            eprintln!("{:>10.3}ms  total", total.as_secs_f64() * 1000.0);
+        }
+        warnings
+    }
+
+    fn pass(
+        &self,
+        pass: Pass,
+        function: &mut Function,
+        warnings: &mut Vec<Warning>,
+        times: &mut Vec<(&'static str, Duration)>,
+    ) {
+        timed(times, pass.name(), || warnings.extend(pass.run(function)));
+        if self.print_after.contains(&pass) {
+            eprint!("*** IR after {} ***\n{}", pass.name(), function);
+        }
+    }
+}
+
+fn timed(times: &mut Vec<(&'static str, Duration)>, name: &'static str, step: impl FnOnce()) {
+    let start = Instant::now();
+    step();
+    times.push((name, start.elapsed()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::OptLevel;
+    use crate::ir::{assert_matches_interpreter, lower_source, Inst};
+
+    fn run_source(pipeline: &Pipeline, src: &str) -> (Function, Vec<Warning>) {
+        let mut function = lower_source(src);
+        let warnings = pipeline.run(&mut function);
+        (function, warnings)
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(
+            Ok(vec![Pass::DeadStores, Pass::Fold, Pass::Gvn, Pass::Fold]),
+            parse_passes("dse,fold,gvn,fold")
+        );
+        assert_eq!(Ok(vec![]), parse_passes(""));
+        assert_eq!(Err("unknown pass"), parse_passes("fold,licm"));
+        assert_eq!(
+            Err("dse and tailcall have to come before the other passes"),
+            parse_passes("fold,tailcall")
+        );
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::Os] {
+            let names: Vec<_> = level.passes().iter().map(Pass::name).collect();
+            assert_eq!(Ok(level.passes()), parse_passes(&names.join(",")));
+        }
+    }
+
+    #[test]
+    fn pipeline_test() {
+        let src = "a = 2 * 3; b = a; return a * b; 4;";
+        let (function, warnings) = run_source(&Pipeline::default(), src);
+        assert!(warnings.is_empty());
+        assert!(function
+            .blocks
+            .iter()
+            .flat_map(|block| &block.insts)
+            .any(|inst| matches!(inst, Inst::Binary { .. })));
+
+        let (function, warnings) = run_source(&Pipeline::new(OptLevel::O1.passes()), src);
+        assert_eq!(
+            vec!["unreachable statement `4;`".to_owned()],
+            warnings.iter().map(ToString::to_string).collect::<Vec<_>>()
+        );
+        assert!(function
+            .blocks
+            .iter()
+            .flat_map(|block| &block.insts)
+            .all(|inst| matches!(inst, Inst::Const { value: 36, .. })));
+    }
+
+    #[test]
+    fn semantics_test() {
+        let cases = [
+            "a = 3; b = 5*6 - 8; a + b / 2;",
+            "s = 0; for (i = 0; i < 10; i = i + 1) s = s + i * 8; s;",
+            "a = 3; b = 4; c = a * b; if (c) c = b * a + 1; c;",
+            "i = 0; while (1) { i = i + 1; if (i == 5) return i * 2; } 7;",
+            "s = 0; for (i = 0; i < 3; i = i + 1) for (j = 0; j < 3; j = j + 1) s = s + i * 4 + j; s;",
+        ];
+        let pipelines = [
+            Pipeline::new(OptLevel::O0.passes()),
+            Pipeline::new(OptLevel::O1.passes()),
+            Pipeline::new(OptLevel::O2.passes()),
+            Pipeline::new(OptLevel::Os.passes()),
+            Pipeline::new(vec![Pass::Loops, Pass::Gvn, Pass::Loops, Pass::Fold]),
+        ];
+
+        for src in cases {
+            for pipeline in &pipelines {
+                assert_matches_interpreter(src, |function| {
+                    pipeline.run(function);
+                });
+            }
+        }
+    }
+}
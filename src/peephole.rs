@@ -0,0 +1,114 @@
+/// A line of assembly as a backend keeps it for the peephole pass.
+pub trait Line: Clone {
+    type Register: Copy;
+
+    /// Comments are skipped over by the rules and stay next to the code they describe.
+    fn is_comment(&self) -> bool;
+    fn label(&self) -> Option<&str>;
+    fn flow(&self) -> Flow<'_>;
+    fn reads(&self, register: Self::Register) -> bool;
+    /// Whether the line overwrites all of `register`.
+    fn writes(&self, register: Self::Register) -> bool;
+}
+
+/// Where execution goes after a line.
+pub enum Flow<'a> {
+    /// on to the next line
+    Next,
+    /// to the label only
+    Jump(&'a str),
+    /// to the label or on to the next line
+    Branch(&'a str),
+    /// out of the function
+    Return,
+}
+
+/// Matches the code from line `at` on. A match gives how many lines to replace and the lines to
+/// put in their place. They are never more, and a rule that keeps the count doesn't match what it
+/// leaves behind, so the pass comes to an end.
+pub type Rule<L> = fn(&[L], usize) -> Option<(usize, Vec<L>)>;
+
+/// Rewrites `lines` with `rules` until none of them matches anywhere.
+pub fn peephole<L: Line>(lines: Vec<L>, rules: &[Rule<L>]) -> Vec<L> {
+    // comments[n] goes before code[n] and the last one at the end
+    let mut code = vec![];
+    let mut comments = vec![vec![]];
+    for line in lines {
+        if line.is_comment() {
+            comments.last_mut().expect("never empty").push(line);
+        } else {
+            code.push(line);
+            comments.push(vec![]);
+        }
+    }
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let mut at = 0;
+        while at < code.len() {
+            let (n, replacement) = match rules.iter().find_map(|rule| rule(&code, at)) {
+                Some(it) => it,
+                None => {
+                    at += 1;
+                    continue;
+                }
+            };
+            debug_assert!(replacement.len() <= n);
+
+            // the comments of the lines past the replacement go before the line after it
+            let mut kept: Vec<Vec<L>> = comments.drain(at..at + n).collect();
+            let mut moved = kept.split_off(replacement.len()).concat();
+            moved.append(&mut comments[at]);
+            comments[at] = moved;
+            comments.splice(at..at, kept);
+            code.splice(at..at + n, replacement);
+            changed = true;
+        }
+    }
+
+    let trailing = comments.pop().expect("never empty");
+    let mut lines = vec![];
+    for (line, comments) in code.into_iter().zip(comments) {
+        lines.extend(comments);
+        lines.push(line);
+    }
+    lines.extend(trailing);
+    lines
+}
+
+/// Whether some path from line `at` reads `register` before writing it. A jump to a label that
+/// isn't in `code` counts as a read.
+pub fn live<L: Line>(code: &[L], at: usize, register: L::Register) -> bool {
+    let mut seen = vec![false; code.len()];
+    let mut paths = vec![at];
+    while let Some(mut n) = paths.pop() {
+        while n < code.len() && !seen[n] {
+            seen[n] = true;
+            let line = &code[n];
+            if line.reads(register) {
+                return true;
+            }
+            if line.writes(register) {
+                break;
+            }
+            let (label, falls_through) = match line.flow() {
+                Flow::Next => (None, true),
+                Flow::Jump(label) => (Some(label), false),
+                Flow::Branch(label) => (Some(label), true),
+                Flow::Return => (None, false),
+            };
+            if let Some(label) = label {
+                match code.iter().position(|line| line.label() == Some(label)) {
+                    Some(target) => paths.push(target),
+                    None => return true,
+                }
+            }
+            if !falls_through {
+                break;
+            }
+            n += 1;
+        }
+    }
+    false
+}
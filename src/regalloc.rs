@@ -0,0 +1,301 @@
+use crate::ir::{Function, Inst, VReg};
+use std::collections::BTreeSet;
+
+/// The registers an ABI leaves to the allocator, numbered with the caller-saved ones first. A
+/// call may clobber those; the callee-saved ones survive calls but the function has to save and
+/// restore the ones it uses.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Registers {
+    pub caller_saved: usize,
+    pub callee_saved: usize,
+}
+
+impl Registers {
+    fn count(&self) -> usize {
+        self.caller_saved + self.callee_saved
+    }
+
+    pub fn is_callee_saved(&self, register: usize) -> bool {
+        register >= self.caller_saved
+    }
+}
+
+/// Where the allocator puts a virtual register for the whole of its life.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Assignment {
+    Register(usize),
+    /// a stack slot, numbered from 0
+    Spill(usize),
+}
+
+pub struct Allocation {
+    /// `None` for virtual registers the function never mentions
+    pub assignments: Vec<Option<Assignment>>,
+    pub spill_slots: usize,
+    /// the callee-saved registers in use, in order
+    pub callee_saved: Vec<usize>,
+}
+
+/// Positions number the instructions and terminators over the blocks in layout order. An
+/// interval runs from the first definition of a virtual register to its last use, stretched over
+/// the blocks it is live through; holes are not tracked.
+#[derive(Debug, PartialEq)]
+struct Interval {
+    vreg: VReg,
+    start: usize,
+    end: usize,
+    /// a call happens while the value is live, so it needs a callee-saved register
+    crosses_call: bool,
+}
+
+/// Linear scan register allocation, after "Linear Scan Register Allocation" of Poletto and
+/// Sarkar. Out of registers, whichever interval ends last is spilled, keeping its value in a
+/// stack slot.
+pub fn allocate(function: &Function, registers: Registers) -> Allocation {
+    let mut assignments = vec![None; function.vregs];
+    let mut registers_of = vec![None; function.vregs];
+    let mut free = vec![true; registers.count()];
+    // the intervals holding a register, with the register
+    let mut active: Vec<(Interval, usize)> = vec![];
+    // the intervals that lost their register or never got one
+    let mut spilled = vec![];
+
+    for interval in intervals(function) {
+        active.retain(|(active, register)| {
+            // a value may take the register of one whose last use defines it
+            let expired = active.end <= interval.start;
+            if expired {
+                free[*register] = true;
+            }
+            !expired
+        });
+
+        let allowed =
+            |register: &usize| !interval.crosses_call || registers.is_callee_saved(*register);
+        if let Some(register) = (0..registers.count()).filter(allowed).find(|r| free[*r]) {
+            free[register] = false;
+            registers_of[interval.vreg] = Some(register);
+            active.push((interval, register));
+            continue;
+        }
+
+        let victim = active
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, register))| allowed(register))
+            .max_by_key(|(_, (active, _))| active.end)
+            .map(|(n, _)| n);
+        match victim {
+            Some(n) if active[n].0.end > interval.end => {
+                let register = active[n].1;
+                let (victim, _) = std::mem::replace(&mut active[n], (interval, register));
+                registers_of[victim.vreg] = None;
+                registers_of[active[n].0.vreg] = Some(register);
+                spilled.push(victim);
+            }
+            _ => spilled.push(interval),
+        }
+    }
+    for (vreg, register) in registers_of.iter().enumerate() {
+        if let Some(register) = register {
+            assignments[vreg] = Some(Assignment::Register(*register));
+        }
+    }
+
+    // spilled intervals share slots the same way registers are shared
+    spilled.sort_by_key(|interval| interval.start);
+    let mut slot_ends: Vec<usize> = vec![];
+    for interval in spilled {
+        let slot = match slot_ends.iter().position(|end| *end <= interval.start) {
+            Some(slot) => slot,
+            None => {
+                slot_ends.push(0);
+                slot_ends.len() - 1
+            }
+        };
+        slot_ends[slot] = interval.end;
+        assignments[interval.vreg] = Some(Assignment::Spill(slot));
+    }
+
+    let callee_saved = (registers.caller_saved..registers.count())
+        .filter(|register| assignments.contains(&Some(Assignment::Register(*register))))
+        .collect();
+    Allocation {
+        assignments,
+        spill_slots: slot_ends.len(),
+        callee_saved,
+    }
+}
+
+/// The virtual registers live into each block.
+fn live_in(function: &Function) -> Vec<BTreeSet<VReg>> {
+    let mut live_in = vec![BTreeSet::new(); function.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (n, block) in function.blocks.iter().enumerate().rev() {
+            let mut live: BTreeSet<VReg> = block
+                .terminator
+                .successors()
+                .iter()
+                .flat_map(|successor| live_in[*successor].iter().copied())
+                .collect();
+            live.extend(block.terminator.operands());
+            for inst in block.insts.iter().rev() {
+                if let Some(dst) = inst.dst() {
+                    live.remove(&dst);
+                }
+                live.extend(inst.operands());
+            }
+            if live != live_in[n] {
+                live_in[n] = live;
+                changed = true;
+            }
+        }
+    }
+    live_in
+}
+
+/// The live intervals of the virtual registers, by start.
+fn intervals(function: &Function) -> Vec<Interval> {
+    let live_in = live_in(function);
+    let mut ranges: Vec<Option<(usize, usize)>> = vec![None; function.vregs];
+    let mut extend = |vreg: VReg, position: usize| {
+        let range = ranges[vreg].get_or_insert((position, position));
+        range.0 = range.0.min(position);
+        range.1 = range.1.max(position);
+    };
+
+    let mut calls = vec![];
+    let mut position = 0;
+    for (n, block) in function.blocks.iter().enumerate() {
+        let start = position;
+        for vreg in &live_in[n] {
+            extend(*vreg, start);
+        }
+        for inst in &block.insts {
+            for vreg in inst.operands() {
+                extend(vreg, position);
+            }
+            if let Some(dst) = inst.dst() {
+                extend(dst, position);
+            }
+            if matches!(inst, Inst::Call { .. }) {
+                calls.push(position);
+            }
+            position += 1;
+        }
+        for vreg in block.terminator.operands() {
+            extend(vreg, position);
+        }
+        // live out of the block is live up to its terminator
+        for successor in block.terminator.successors() {
+            for vreg in &live_in[successor] {
+                extend(*vreg, position);
+            }
+        }
+        position += 1;
+    }
+
+    let mut intervals: Vec<Interval> = ranges
+        .iter()
+        .enumerate()
+        .filter_map(|(vreg, range)| {
+            let (start, end) = (*range)?;
+            Some(Interval {
+                vreg,
+                start,
+                end,
+                crosses_call: calls.iter().any(|call| start < *call && *call < end),
+            })
+        })
+        .collect();
+    intervals.sort_by_key(|interval| interval.start);
+    intervals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::{function, OptLevel};
+    use crate::passes::Pipeline;
+    use crate::{lexer::lexer, parser::parser, variable::variable_analysis};
+
+    fn function_of(src: &str) -> Function {
+        let (tokens, ident_count) = variable_analysis(lexer(src).unwrap()).unwrap();
+        let pipeline = Pipeline::new(OptLevel::O2.passes());
+        function(&parser(tokens).unwrap(), ident_count, &pipeline)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn interval_test() {
+        let function = function_of("s = x(); for (i = 1; i < 10; i = i + 1) s = s + i; s;");
+        let intervals = intervals(&function);
+        let interval = |vreg| intervals.iter().find(|i| i.vreg == vreg).unwrap();
+
+        // b0 to b2 take 6, 5 and 5 positions, so the back edge jumps at 14
+        let carried: Vec<_> = function.blocks[2]
+            .insts
+            .iter()
+            .filter_map(|inst| match inst {
+                Inst::Copy { dst, .. } => Some(*dst),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(2, carried.len());
+        for vreg in carried {
+            assert!(interval(vreg).start < 6, "v{}", vreg);
+            assert_eq!(14, interval(vreg).end, "v{}", vreg);
+        }
+        assert!(intervals.windows(2).all(|w| w[0].start <= w[1].start));
+        assert!(intervals.iter().all(|i| !i.crosses_call));
+    }
+
+    #[test]
+    fn allocate_test() {
+        let function = function_of("a = x(); b = x(); c = x(); d = x(); a + b + c + d;");
+        let all = allocate(&function, Registers::default());
+        assert!(all
+            .assignments
+            .iter()
+            .flatten()
+            .all(|a| matches!(a, Assignment::Spill(_))));
+        assert!(all.callee_saved.is_empty());
+
+        let two = Registers {
+            caller_saved: 0,
+            callee_saved: 2,
+        };
+        let allocation = allocate(&function, two);
+        assert!(allocation.spill_slots > 0);
+        assert!(allocation.spill_slots < all.spill_slots);
+        assert_eq!(vec![0, 1], allocation.callee_saved);
+    }
+
+    #[test]
+    fn call_test() {
+        // `a` is live across the call
+        let function = function_of("a = x(); b = y(); a - b;");
+        let registers = Registers {
+            caller_saved: 4,
+            callee_saved: 1,
+        };
+        let allocation = allocate(&function, registers);
+
+        let crossing: Vec<_> = intervals(&function)
+            .into_iter()
+            .filter(|interval| interval.crosses_call)
+            .collect();
+        assert!(!crossing.is_empty());
+        for interval in crossing {
+            match allocation.assignments[interval.vreg] {
+                Some(Assignment::Register(r)) => assert!(registers.is_callee_saved(r)),
+                Some(Assignment::Spill(_)) => {}
+                None => panic!("v{} is live but unallocated", interval.vreg),
+            }
+        }
+        assert_eq!(vec![4], allocation.callee_saved);
+    }
+}
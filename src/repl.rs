@@ -0,0 +1,281 @@
+use crate::architecture::{Backend, Frame};
+use crate::generator::{function, OptLevel};
+use crate::interp::{Interpreter, Value};
+use crate::lexer::lexer;
+use crate::parser::parser;
+use crate::passes::Pipeline;
+use crate::regalloc::Registers;
+use crate::token::{Token, TokenError};
+use crate::tree::Tree;
+use crate::variable::variable_analysis;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = "... ";
+const HELP: &str = "\
+:tokens  show the tokens of the last input
+:ast     show the syntax trees of the last input
+:asm     show the assembly of the last input
+:vars    show the variables
+:help    show this message
+:quit    exit
+";
+
+pub fn repl(
+    input: impl BufRead,
+    output: &mut impl Write,
+    backend: &dyn Backend,
+) -> Result<(), String> {
+    let mut repl = Repl {
+        variables: BTreeMap::new(),
+        last_input: None,
+    };
+    let mut lines = input.lines();
+
+    loop {
+        write_str(output, PROMPT)?;
+        let mut source = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(err)) => return Err(err.to_string()),
+            None => return Ok(()),
+        };
+
+        let result = match source.trim() {
+            "" => continue,
+            ":quit" | ":q" => return Ok(()),
+            ":help" => Ok(HELP.to_owned()),
+            ":tokens" => repl.last_input().and_then(|s| show_tokens(&s)),
+            ":ast" => repl.last_input().and_then(|s| show_ast(&s)),
+            ":asm" => repl.last_input().and_then(|s| show_asm(&s, backend)),
+            ":vars" => Ok(repl.show_variables()),
+            command if command.starts_with(':') => Err(format!("unknown command {}", command)),
+            _ => {
+                while is_incomplete(&source) {
+                    write_str(output, CONTINUATION_PROMPT)?;
+                    match lines.next() {
+                        Some(Ok(line)) => {
+                            source.push('\n');
+                            source.push_str(&line);
+                        }
+                        Some(Err(err)) => return Err(err.to_string()),
+                        None => break,
+                    }
+                }
+                let source = complete_statement(&source);
+                repl.last_input = Some(source.clone());
+                repl.eval(&source).map(|values| {
+                    values
+                        .iter()
+                        .map(|value| format!("{}\n", value))
+                        .collect::<String>()
+                })
+            }
+        };
+
+        match result {
+            Ok(s) => write_str(output, &s)?,
+            Err(e) => write_str(output, &format!("error: {}\n", e))?,
+        }
+    }
+}
+
+fn write_str(output: &mut impl Write, s: &str) -> Result<(), String> {
+    write!(output, "{}", s)
+        .and_then(|_| output.flush())
+        .map_err(|err| err.to_string())
+}
+
+/// More lines are read while braces are left open.
+fn is_incomplete(source: &str) -> bool {
+    match lexer(source) {
+        Ok(tokens) => {
+            let open = tokens.iter().filter(|t| **t == Token::OpenBrace).count();
+            let close = tokens.iter().filter(|t| **t == Token::CloseBrace).count();
+            open > close
+        }
+        Err(_) => false,
+    }
+}
+
+/// Lets a bare expression like `1 + 2` be typed without the semicolon.
+fn complete_statement(source: &str) -> String {
+    let trimmed = source.trim_end();
+    if trimmed.ends_with(';') || trimmed.ends_with('}') {
+        source.to_owned()
+    } else {
+        format!("{};", trimmed)
+    }
+}
+
+fn lex(source: &str) -> Result<Vec<Token>, String> {
+    lexer(source).map_err(|e| match e {
+        TokenError::TokenizeError => "tokenize error".to_owned(),
+        TokenError::InvalidChar(c) => format!("invalid char '{}'", c),
+    })
+}
+
+struct Parsed {
+    trees: Vec<Tree>,
+    /// name and offset of every variable the input mentions
+    variables: Vec<(String, usize)>,
+    ident_count: usize,
+}
+
+fn front_end(source: &str) -> Result<Parsed, String> {
+    let (tokens, ident_count) = variable_analysis(lex(source)?)?;
+    let mut variables = vec![];
+    for token in &tokens {
+        if let Token::Variable { name, offset } = token {
+            if !variables.iter().any(|(n, _)| n == name) {
+                variables.push((name.clone(), *offset));
+            }
+        }
+    }
+    let trees = parser(tokens).map_err(|e| format!("parse error: {}", e))?;
+    Ok(Parsed {
+        trees,
+        variables,
+        ident_count,
+    })
+}
+
+fn show_tokens(source: &str) -> Result<String, String> {
+    Ok(lex(source)?
+        .iter()
+        .map(|token| format!("{:?}\n", token))
+        .collect())
+}
+
+fn show_ast(source: &str) -> Result<String, String> {
+    let trees = front_end(source)?.trees;
+    Ok(trees.iter().map(|tree| format!("{:?}\n", tree)).collect())
+}
+
+fn show_asm(source: &str, backend: &dyn Backend) -> Result<String, String> {
+    let Parsed {
+        trees, ident_count, ..
+    } = front_end(source)?;
+    let (function, _) = function(&trees, ident_count, &Pipeline::default())?;
+    let frame = Frame::new(&function, Registers::default());
+    Ok(backend.function(&function, &frame, OptLevel::O0))
+}
+
+fn is_expr(tree: &Tree) -> bool {
+    matches!(
+        tree,
+        Tree::Int(_) | Tree::Val { .. } | Tree::Func { .. } | Tree::Node(..)
+    )
+}
+
+struct Repl {
+    variables: BTreeMap<String, Value>,
+    last_input: Option<String>,
+}
+
+impl Repl {
+    fn last_input(&self) -> Result<String, String> {
+        self.last_input.clone().ok_or("no input yet".to_owned())
+    }
+
+    fn show_variables(&self) -> String {
+        self.variables
+            .iter()
+            .map(|(name, value)| format!("{} = {}\n", name, value))
+            .collect()
+    }
+
+    /// Variable offsets are assigned per input, so values are carried over by name.
+    fn eval(&mut self, source: &str) -> Result<Vec<Value>, String> {
+        let Parsed {
+            trees,
+            variables,
+            ident_count,
+        } = front_end(source)?;
+        let mut interpreter = Interpreter::new(ident_count);
+        for (name, offset) in &variables {
+            if let Some(value) = self.variables.get(name) {
+                interpreter.store(*offset, *value)?;
+            }
+        }
+
+        let mut values = vec![];
+        let mut result = Ok(());
+        for tree in &trees {
+            match interpreter.exec(tree) {
+                Ok(Some(value)) => {
+                    values.push(value);
+                    break;
+                }
+                Ok(None) if is_expr(tree) => values.push(interpreter.last()),
+                Ok(None) => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        for (name, offset) in variables {
+            let value = interpreter.load(offset)?;
+            self.variables.insert(name, value);
+        }
+        result.map(|_| values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::architecture::{x86_64::Syntax, Target};
+
+    fn session(input: &str) -> String {
+        let mut output = vec![];
+        repl(
+            input.as_bytes(),
+            &mut output,
+            &*Target::host().backend(Syntax::Intel).unwrap(),
+        )
+        .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn variables_test() {
+        assert_eq!(
+            "> 3\n> 2\n> 5\n12\n> ",
+            session("b = 3;\na = 2\na + b; a * b * 2;\n")
+        );
+    }
+
+    #[test]
+    fn multiline_test() {
+        assert_eq!(
+            "> 1\n> ... ... > 4\n> ",
+            session("i = 1;\nwhile (i < 3) {\ni = i * 2;\n}\ni;\n")
+        );
+    }
+
+    #[test]
+    fn return_test() {
+        assert_eq!("> 1\n> ", session("if (1) return 1; 2;\n"));
+    }
+
+    #[test]
+    fn error_test() {
+        assert_eq!(
+            "> error: division by zero\n> 1\n> ",
+            session("a = 1; 1 / 0;\na;\n")
+        );
+    }
+
+    #[test]
+    fn commands_test() {
+        assert_eq!(
+            "> 2\n> Integer(1)\nAdd\nInteger(1)\nSemicolon\n> Node(Add, Int(1), Int(1))\n> ",
+            session("1 + 1\n:tokens\n:ast\n")
+        );
+        assert_eq!("> error: no input yet\n> ", session(":asm\n"));
+        assert_eq!("> 1\n> a = 1\n> ", session("a = 1;\n:vars\n"));
+    }
+}
@@ -0,0 +1,453 @@
+use crate::ir::{BlockId, Function, Inst, VReg};
+use std::collections::BTreeSet;
+
+pub fn predecessors(function: &Function) -> Vec<Vec<BlockId>> {
+    let mut predecessors = vec![vec![]; function.blocks.len()];
+    for (n, block) in function.blocks.iter().enumerate() {
+        for successor in block.terminator.successors() {
+            if !predecessors[successor].contains(&n) {
+                predecessors[successor].push(n);
+            }
+        }
+    }
+    predecessors
+}
+
+/// The blocks reachable from the entry, in reverse postorder.
+pub fn reverse_postorder(function: &Function) -> Vec<BlockId> {
+    let mut visited = vec![false; function.blocks.len()];
+    let mut postorder = vec![];
+    // a block and the successors it has yet to visit
+    let mut stack = vec![(0, function.blocks[0].terminator.successors())];
+    visited[0] = true;
+    while let Some((block, successors)) = stack.last_mut() {
+        match successors.pop() {
+            Some(successor) if !visited[successor] => {
+                visited[successor] = true;
+                let successors = function.blocks[successor].terminator.successors();
+                stack.push((successor, successors));
+            }
+            Some(_) => {}
+            None => {
+                postorder.push(*block);
+                stack.pop();
+            }
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+/// The immediate dominator of every block, by "A Simple, Fast Dominance Algorithm" of Cooper,
+/// Harvey and Kennedy. The entry is its own immediate dominator and unreachable blocks have
+/// none.
+pub fn dominators(function: &Function) -> Vec<Option<BlockId>> {
+    let order = reverse_postorder(function);
+    let mut rank = vec![usize::MAX; function.blocks.len()];
+    for (n, block) in order.iter().enumerate() {
+        rank[*block] = n;
+    }
+    let predecessors = predecessors(function);
+
+    let mut idom = vec![None; function.blocks.len()];
+    idom[0] = Some(0);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in &order[1..] {
+            let mut new_idom = None;
+            for &predecessor in &predecessors[block] {
+                if idom[predecessor].is_none() {
+                    continue;
+                }
+                new_idom = Some(match new_idom {
+                    None => predecessor,
+                    Some(other) => intersect(&idom, &rank, predecessor, other),
+                });
+            }
+            if idom[block] != new_idom {
+                idom[block] = new_idom;
+                changed = true;
+            }
+        }
+    }
+    idom
+}
+
+/// The nearest common dominator of `a` and `b`.
+fn intersect(idom: &[Option<BlockId>], rank: &[usize], mut a: BlockId, mut b: BlockId) -> BlockId {
+    while a != b {
+        while rank[a] > rank[b] {
+            a = idom[a].expect("reachable blocks have dominators");
+        }
+        while rank[b] > rank[a] {
+            b = idom[b].expect("reachable blocks have dominators");
+        }
+    }
+    a
+}
+
+/// The blocks where the dominance of each block ends, which is where the values it defines
+/// meet others.
+pub fn dominance_frontiers(
+    function: &Function,
+    idom: &[Option<BlockId>],
+) -> Vec<BTreeSet<BlockId>> {
+    let mut frontiers = vec![BTreeSet::new(); function.blocks.len()];
+    for (block, predecessors) in predecessors(function).into_iter().enumerate() {
+        let predecessors: Vec<_> = predecessors
+            .into_iter()
+            .filter(|p| idom[*p].is_some())
+            .collect();
+        if predecessors.len() < 2 {
+            continue;
+        }
+        for mut runner in predecessors {
+            while Some(runner) != idom[block] {
+                frontiers[runner].insert(block);
+                runner = idom[runner].expect("reachable blocks have dominators");
+            }
+        }
+    }
+    frontiers
+}
+
+/// Drops the blocks control never reaches, like the ones after a `return`, and keeps the rest in
+/// order.
+pub fn remove_unreachable(function: &mut Function) {
+    let mut reachable = vec![false; function.blocks.len()];
+    for block in reverse_postorder(function) {
+        reachable[block] = true;
+    }
+    let mut ids = vec![0; function.blocks.len()];
+    let mut next = 0;
+    for (block, id) in ids.iter_mut().enumerate() {
+        if reachable[block] {
+            *id = next;
+            next += 1;
+        }
+    }
+
+    let blocks = std::mem::take(&mut function.blocks);
+    for (n, mut block) in blocks.into_iter().enumerate() {
+        if !reachable[n] {
+            continue;
+        }
+        for successor in block.terminator.successors_mut() {
+            *successor = ids[*successor];
+        }
+        for inst in &mut block.insts {
+            if let Inst::Phi { args, .. } = inst {
+                args.retain(|(predecessor, _)| reachable[*predecessor]);
+                for (predecessor, _) in args {
+                    *predecessor = ids[*predecessor];
+                }
+            }
+        }
+        function.blocks.push(block);
+    }
+}
+
+/// Promotes the variables to SSA values: every `store` becomes a definition, every `load` a use
+/// of the definition that reaches it, and phis merge the definitions where control flow meets.
+/// Unreachable blocks are removed first since nothing dominates them.
+pub fn to_ssa(function: &mut Function) {
+    remove_unreachable(function);
+    let idom = dominators(function);
+    let frontiers = dominance_frontiers(function, &idom);
+    let blocks = function.blocks.len();
+    let vars = function.vars.len();
+
+    // Only variables read in a block before it writes them can need a phi.
+    let mut live_in = vec![false; vars];
+    let mut defsites = vec![vec![]; vars];
+    for (n, block) in function.blocks.iter().enumerate() {
+        let mut written = vec![false; vars];
+        for inst in &block.insts {
+            match *inst {
+                Inst::Load { var, .. } if !written[var] => live_in[var] = true,
+                Inst::Store { var, .. } if !written[var] => {
+                    written[var] = true;
+                    defsites[var].push(n);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // the variable of each phi at the top of each block
+    let mut phi_vars = vec![vec![]; blocks];
+    for var in (0..vars).filter(|var| live_in[*var]) {
+        let mut has_phi = vec![false; blocks];
+        let mut work = defsites[var].clone();
+        while let Some(block) = work.pop() {
+            for &frontier in &frontiers[block] {
+                if !has_phi[frontier] {
+                    has_phi[frontier] = true;
+                    phi_vars[frontier].push(var);
+                    work.push(frontier);
+                }
+            }
+        }
+    }
+    for (block, vars) in phi_vars.iter().enumerate() {
+        let phis: Vec<Inst> = vars
+            .iter()
+            .map(|_| Inst::Phi {
+                dst: function.new_vreg(),
+                args: vec![],
+            })
+            .collect();
+        function.blocks[block].insts.splice(0..0, phis);
+    }
+
+    let mut renamer = Renamer {
+        function,
+        phi_vars,
+        stacks: vec![vec![]; vars],
+        replacements: vec![],
+        undefined: None,
+    };
+    renamer.rename(&idom);
+    if let Some(dst) = renamer.undefined {
+        function.blocks[0]
+            .insts
+            .insert(0, Inst::Const { dst, value: 0 });
+    }
+}
+
+struct Renamer<'a> {
+    function: &'a mut Function,
+    phi_vars: Vec<Vec<usize>>,
+    /// the definitions of each variable on the path from the entry in the dominator tree
+    stacks: Vec<Vec<VReg>>,
+    /// the definition each removed `load` read
+    replacements: Vec<Option<VReg>>,
+    /// what a variable read before any store reads
+    undefined: Option<VReg>,
+}
+
+enum Visit {
+    Enter(BlockId),
+    /// pops the definitions the block pushed
+    Exit(Vec<usize>),
+}
+
+impl Renamer<'_> {
+    /// Walks the dominator tree, so every use is renamed after the definition that reaches it.
+    fn rename(&mut self, idom: &[Option<BlockId>]) {
+        let mut children = vec![vec![]; self.function.blocks.len()];
+        for (block, parent) in idom.iter().enumerate().skip(1) {
+            if let Some(parent) = parent {
+                children[*parent].push(block);
+            }
+        }
+
+        let mut work = vec![Visit::Enter(0)];
+        while let Some(visit) = work.pop() {
+            match visit {
+                Visit::Enter(block) => {
+                    let pushed = self.block(block);
+                    work.push(Visit::Exit(pushed));
+                    work.extend(children[block].iter().rev().map(|c| Visit::Enter(*c)));
+                }
+                Visit::Exit(pushed) => {
+                    for var in pushed {
+                        self.stacks[var].pop();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Renames the block and fills in its part of the phis of its successors. Returns the
+    /// variables it defined.
+    fn block(&mut self, n: BlockId) -> Vec<usize> {
+        let mut pushed = vec![];
+        let insts = std::mem::take(&mut self.function.blocks[n].insts);
+        let mut kept = vec![];
+        for (i, mut inst) in insts.into_iter().enumerate() {
+            match inst {
+                Inst::Phi { dst, .. } if i < self.phi_vars[n].len() => {
+                    let var = self.phi_vars[n][i];
+                    self.stacks[var].push(dst);
+                    pushed.push(var);
+                    kept.push(inst);
+                }
+                Inst::Load { dst, var } => {
+                    let value = self.current(var);
+                    if self.replacements.len() <= dst {
+                        self.replacements.resize(dst + 1, None);
+                    }
+                    self.replacements[dst] = Some(value);
+                }
+                Inst::Store { var, src } => {
+                    let value = self.resolve(src);
+                    self.stacks[var].push(value);
+                    pushed.push(var);
+                }
+                _ => {
+                    for operand in inst.operands_mut() {
+                        *operand = self.resolve(*operand);
+                    }
+                    kept.push(inst);
+                }
+            }
+        }
+        self.function.blocks[n].insts = kept;
+
+        let mut terminator = self.function.blocks[n].terminator.clone();
+        for operand in terminator.operands_mut() {
+            *operand = self.resolve(*operand);
+        }
+        let successors = terminator.successors();
+        self.function.blocks[n].terminator = terminator;
+
+        for successor in successors {
+            for i in 0..self.phi_vars[successor].len() {
+                let value = self.current(self.phi_vars[successor][i]);
+                if let Inst::Phi { args, .. } = &mut self.function.blocks[successor].insts[i] {
+                    if !args.iter().any(|(block, _)| *block == n) {
+                        args.push((n, value));
+                    }
+                }
+            }
+        }
+        pushed
+    }
+
+    fn current(&mut self, var: usize) -> VReg {
+        match self.stacks[var].last() {
+            Some(value) => *value,
+            None => match self.undefined {
+                Some(value) => value,
+                None => {
+                    let value = self.function.new_vreg();
+                    self.undefined = Some(value);
+                    value
+                }
+            },
+        }
+    }
+
+    fn resolve(&self, vreg: VReg) -> VReg {
+        match self.replacements.get(vreg) {
+            Some(Some(value)) => *value,
+            _ => vreg,
+        }
+    }
+}
+
+/// Replaces every phi with a copy from a fresh temporary that each predecessor sets just before
+/// its terminator. Going through temporaries keeps the phis of a block reading the values from
+/// before it, and setting one on a predecessor's other edge is harmless since only the phi's
+/// block reads it.
+pub fn out_of_ssa(function: &mut Function) {
+    for block in 0..function.blocks.len() {
+        let mut i = 0;
+        while let Some(Inst::Phi { dst, args }) = function.blocks[block].insts.get(i) {
+            let (dst, args) = (*dst, args.clone());
+            let temp = function.new_vreg();
+            for (predecessor, value) in args {
+                function.blocks[predecessor].insts.push(Inst::Copy {
+                    dst: temp,
+                    src: value,
+                });
+            }
+            function.blocks[block].insts[i] = Inst::Copy { dst, src: temp };
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{assert_matches_interpreter, lower_source, Block, Terminator};
+
+    /// b0 -> b1, b2; b1 -> b3; b2 -> b3; b3 -> b1, b4
+    fn diamond_loop() -> Function {
+        let block = |terminator| Block {
+            insts: vec![],
+            terminator,
+            stmts: vec![],
+        };
+        let branch = |then_block, else_block| Terminator::Branch {
+            cond: 0,
+            then_block,
+            else_block,
+        };
+        Function {
+            name: "main".to_owned(),
+            vars: vec![],
+            blocks: vec![
+                block(branch(1, 2)),
+                block(Terminator::Jump(3)),
+                block(Terminator::Jump(3)),
+                block(branch(1, 4)),
+                block(Terminator::Return(0)),
+            ],
+            vregs: 1,
+        }
+    }
+
+    #[test]
+    fn dominators_test() {
+        let function = diamond_loop();
+        let idom = dominators(&function);
+
+        assert_eq!(vec![Some(0), Some(0), Some(0), Some(0), Some(3)], idom);
+        assert_eq!(
+            vec![
+                BTreeSet::new(),
+                BTreeSet::from([3]),
+                BTreeSet::from([3]),
+                BTreeSet::from([1]),
+                BTreeSet::new()
+            ],
+            dominance_frontiers(&function, &idom)
+        );
+    }
+
+    #[test]
+    fn to_ssa_test() {
+        let mut function = lower_source("i = 0; while (i < 10) i = i + 1; return i;");
+        to_ssa(&mut function);
+
+        assert!(function.blocks.iter().all(|block| block
+            .insts
+            .iter()
+            .all(|inst| !matches!(inst, Inst::Load { .. } | Inst::Store { .. }))));
+        // the loop header merges i from before the loop and from the body
+        assert!(matches!(
+            &function.blocks[1].insts[0],
+            Inst::Phi { args, .. } if args.iter().map(|(b, _)| *b).collect::<Vec<_>>() == [0, 2]
+        ));
+        // the block after the return is gone
+        assert_eq!(4, function.blocks.len());
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let cases = [
+            "a = 3; b = 5*6 - 8; a + b / 2;",
+            "if(3 < 2)return 5; else return 4;",
+            "i = 1; while(i < 3) i = i*2;i;",
+            "for(a = 0; a < 10; a = a + 1)a + 1;a;",
+            "{{{{{{{{{{0;1;2;3;10;}}}}}}}}}}",
+            "for (;;) return 7;",
+            "num = 0; for (i = 1; i <= 10; i = i + 1) { num = num + i; } return num;",
+            "a = 1; b = 2; for (i = 0; i < 5; i = i + 1) { t = a; a = b; b = t; } a * 10 + b;",
+            "x = 0; if (x) y = 5; y;",
+            "s = 0; for (i = 0; i < 4; i = i + 1) for (j = 0; j < i; j = j + 1) s = s + j; s;",
+        ];
+
+        for src in cases {
+            assert_matches_interpreter(src, to_ssa);
+            assert_matches_interpreter(src, |function| {
+                to_ssa(function);
+                out_of_ssa(function);
+            });
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::ir::{Block, Function, Inst, Terminator};
+
+/// Turns the calls a function makes to itself and returns the value of straight away into jumps
+/// back to its top, so tail recursion like `return main();` runs in constant stack. The top is
+/// where `lower` clears the variables, so each round starts from zero as a new call would. Runs
+/// before SSA form, after `dead_stores`: a new round writes every variable it reads, so the jumps
+/// don't bring any store back to life.
+pub fn tail_calls(function: &mut Function) {
+    let tail = |block: &Block| match (block.insts.last(), &block.terminator) {
+        (Some(Inst::Call { dst, name }), Terminator::Return(value)) => {
+            dst == value && *name == function.name
+        }
+        _ => false,
+    };
+    if !function.blocks.iter().any(tail) {
+        return;
+    }
+    let tails: Vec<bool> = function.blocks.iter().map(tail).collect();
+
+    // the entry block can't be jumped to, so a new one goes in front of it
+    for block in &mut function.blocks {
+        for successor in block.terminator.successors_mut() {
+            *successor += 1;
+        }
+    }
+    for (block, _) in function
+        .blocks
+        .iter_mut()
+        .zip(tails)
+        .filter(|(_, tail)| *tail)
+    {
+        block.insts.pop();
+        block.terminator = Terminator::Jump(1);
+    }
+    function.blocks.insert(
+        0,
+        Block {
+            insts: vec![],
+            terminator: Terminator::Jump(1),
+            stmts: vec![],
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dce::dead_stores;
+    use crate::ir::lower;
+    use crate::{lexer::lexer, parser::parser, variable::variable_analysis};
+
+    fn lower_source(src: &str) -> Function {
+        let (tokens, ident_count) = variable_analysis(lexer(src).unwrap()).unwrap();
+        let mut function = lower(&parser(tokens).unwrap(), ident_count).unwrap();
+        dead_stores(&mut function);
+        tail_calls(&mut function);
+        function
+    }
+
+    fn calls(function: &Function) -> Vec<&str> {
+        function
+            .blocks
+            .iter()
+            .flat_map(|block| &block.insts)
+            .filter_map(|inst| match inst {
+                Inst::Call { name, .. } => Some(name.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tail_call_test() {
+        let function = lower_source("n = next(); if (n == 0) return 7; return main();");
+        assert_eq!(vec!["next"], calls(&function));
+        assert_eq!(Terminator::Jump(1), function.blocks[0].terminator);
+        assert!(function.blocks[0].insts.is_empty());
+        assert_eq!(
+            2,
+            function
+                .blocks
+                .iter()
+                .filter(|block| block.terminator == Terminator::Jump(1))
+                .count()
+        );
+        assert!(matches!(
+            function.blocks[1].insts[0],
+            Inst::Const { value: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn not_tail_call_test() {
+        for src in [
+            "return main() + 1;",
+            "a = main(); return a * 2;",
+            "return next();",
+        ] {
+            let function = lower_source(src);
+            assert_eq!(1, calls(&function).len(), "{}", src);
+            assert!(
+                function
+                    .blocks
+                    .iter()
+                    .all(|block| !matches!(block.terminator, Terminator::Jump(_))),
+                "{}",
+                src
+            );
+        }
+    }
+}
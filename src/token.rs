@@ -26,6 +26,15 @@ pub enum Token {
     RParen,
     OpenBrace,
     CloseBrace,
+    Comment(String),
+    Newline,
+}
+
+impl Token {
+    /// Comments and line breaks are only kept for tools that reproduce the source layout.
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, Token::Comment(_) | Token::Newline)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
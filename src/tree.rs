@@ -1,4 +1,5 @@
 use crate::token::Int;
+use std::collections::BTreeSet;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum NodeKind {
@@ -81,3 +82,41 @@ impl Tree {
         }
     }
 }
+
+/// The names of every variable the program uses, for backends that declare them up front.
+pub fn variables(trees: &[Tree]) -> BTreeSet<String> {
+    let mut variables = BTreeSet::new();
+    for tree in trees {
+        collect_variables(tree, &mut variables);
+    }
+    variables
+}
+
+fn collect_variables(tree: &Tree, variables: &mut BTreeSet<String>) {
+    match tree {
+        Tree::Val { name, .. } => {
+            variables.insert(name.clone());
+        }
+        Tree::Return(t) => collect_variables(t, variables),
+        Tree::If(a, b) | Tree::While(a, b) | Tree::Node(_, a, b) => {
+            collect_variables(a, variables);
+            collect_variables(b, variables);
+        }
+        Tree::IfElse(a, b, c) => {
+            for t in [a, b, c] {
+                collect_variables(t, variables);
+            }
+        }
+        Tree::For(a, b, c, d) => {
+            for t in [a, b, c, d] {
+                collect_variables(t, variables);
+            }
+        }
+        Tree::Block(trees) => {
+            for t in trees {
+                collect_variables(t, variables);
+            }
+        }
+        Tree::None | Tree::Int(_) | Tree::Func { .. } => {}
+    }
+}
@@ -0,0 +1,288 @@
+use crate::tree::*;
+use core::fmt;
+
+const INDENT: &str = "    ";
+
+pub fn unparse(trees: &[Tree]) -> String {
+    let mut s = String::new();
+    for tree in trees {
+        write_stmt(&mut s, tree, 0);
+        s.push('\n');
+    }
+    s
+}
+
+impl fmt::Display for NodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            NodeKind::Assign => "=",
+            NodeKind::Equality => "==",
+            NodeKind::Nonequality => "!=",
+            NodeKind::LessOrEqual => "<=",
+            NodeKind::Less => "<",
+            NodeKind::Add => "+",
+            NodeKind::Sub => "-",
+            NodeKind::Mul => "*",
+            NodeKind::Div => "/",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+/// Expressions are printed without a trailing semicolon, statements as they would appear at the
+/// top level of a program.
+impl fmt::Display for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = String::new();
+        if is_expr(self) {
+            write_expr(&mut s, self);
+        } else {
+            write_stmt(&mut s, self, 0);
+        }
+        write!(f, "{}", s)
+    }
+}
+
+fn is_expr(tree: &Tree) -> bool {
+    matches!(
+        tree,
+        Tree::Int(_) | Tree::Val { .. } | Tree::Func { .. } | Tree::Node(..)
+    )
+}
+
+fn precedence(tree: &Tree) -> usize {
+    match tree {
+        Tree::Node(NodeKind::Sub, lhs, _) if **lhs == Tree::Int(0) => 6,
+        Tree::Node(kind, _, _) => match kind {
+            NodeKind::Assign => 1,
+            NodeKind::Equality | NodeKind::Nonequality => 2,
+            NodeKind::LessOrEqual | NodeKind::Less => 3,
+            NodeKind::Add | NodeKind::Sub => 4,
+            NodeKind::Mul | NodeKind::Div => 5,
+        },
+        _ => 7,
+    }
+}
+
+fn write_operand(s: &mut String, tree: &Tree, min: usize) {
+    if precedence(tree) < min {
+        s.push('(');
+        write_expr(s, tree);
+        s.push(')');
+    } else {
+        write_expr(s, tree);
+    }
+}
+
+fn write_expr(s: &mut String, tree: &Tree) {
+    match tree {
+        Tree::None => {}
+        Tree::Int(n) => s.push_str(&n.to_string()),
+        Tree::Val { name, .. } => s.push_str(name),
+        Tree::Func { name } => s.push_str(&format!("{}()", name)),
+        // `-x` is parsed as `0 - x` and only accepts a primary after the sign
+        Tree::Node(NodeKind::Sub, lhs, rhs) if **lhs == Tree::Int(0) => {
+            s.push('-');
+            write_operand(s, rhs, 7);
+        }
+        Tree::Node(NodeKind::Assign, lhs, rhs) => {
+            write_operand(s, lhs, 7);
+            s.push_str(" = ");
+            write_operand(s, rhs, 1);
+        }
+        Tree::Node(kind, lhs, rhs) => {
+            let prec = precedence(tree);
+            write_operand(s, lhs, prec);
+            s.push_str(&format!(" {} ", kind));
+            write_operand(s, rhs, prec + 1);
+        }
+        // statements never appear inside an expression in a parsed tree
+        stmt => write_stmt(s, stmt, 0),
+    }
+}
+
+fn write_indent(s: &mut String, depth: usize) {
+    s.push_str(&INDENT.repeat(depth));
+}
+
+/// Writes the body of `if`/`while`/`for`, either as `{ ... }` on the same line or as an indented
+/// statement on the next line.
+fn write_body(s: &mut String, tree: &Tree, depth: usize) {
+    match tree {
+        Tree::Block(_) => {
+            s.push(' ');
+            write_stmt(s, tree, depth);
+        }
+        _ => {
+            s.push('\n');
+            write_indent(s, depth + 1);
+            write_stmt(s, tree, depth + 1);
+        }
+    }
+}
+
+/// An `if` without `else` as the last statement of a then-branch would capture the `else`.
+fn ends_with_dangling_if(tree: &Tree) -> bool {
+    match tree {
+        Tree::If(_, _) => true,
+        Tree::IfElse(_, _, stmt) | Tree::While(_, stmt) | Tree::For(_, _, _, stmt) => {
+            ends_with_dangling_if(stmt)
+        }
+        _ => false,
+    }
+}
+
+fn write_stmt(s: &mut String, tree: &Tree, depth: usize) {
+    match tree {
+        Tree::None => s.push(';'),
+        Tree::Return(expr) => {
+            s.push_str("return ");
+            write_expr(s, expr);
+            s.push(';');
+        }
+        Tree::If(expr, stmt) => {
+            s.push_str("if (");
+            write_expr(s, expr);
+            s.push(')');
+            write_body(s, stmt, depth);
+        }
+        Tree::IfElse(expr, stmt, else_stmt) => {
+            s.push_str("if (");
+            write_expr(s, expr);
+            s.push(')');
+            let stmt = if ends_with_dangling_if(stmt) {
+                Tree::new_block(vec![*stmt.clone()])
+            } else {
+                *stmt.clone()
+            };
+            write_body(s, &stmt, depth);
+            if let Tree::Block(_) = stmt {
+                s.push(' ');
+            } else {
+                s.push('\n');
+                write_indent(s, depth);
+            }
+            s.push_str("else");
+            match **else_stmt {
+                Tree::If(..) | Tree::IfElse(..) => {
+                    s.push(' ');
+                    write_stmt(s, else_stmt, depth);
+                }
+                _ => write_body(s, else_stmt, depth),
+            }
+        }
+        Tree::While(expr, stmt) => {
+            s.push_str("while (");
+            write_expr(s, expr);
+            s.push(')');
+            write_body(s, stmt, depth);
+        }
+        Tree::For(init_expr, cond_expr, loop_expr, stmt) => {
+            s.push_str("for (");
+            write_expr(s, init_expr);
+            s.push(';');
+            if **cond_expr != Tree::None {
+                s.push(' ');
+                write_expr(s, cond_expr);
+            }
+            s.push(';');
+            if **loop_expr != Tree::None {
+                s.push(' ');
+                write_expr(s, loop_expr);
+            }
+            s.push(')');
+            write_body(s, stmt, depth);
+        }
+        Tree::Block(stmts) if stmts.is_empty() => s.push_str("{}"),
+        Tree::Block(stmts) => {
+            s.push('{');
+            for stmt in stmts {
+                s.push('\n');
+                write_indent(s, depth + 1);
+                write_stmt(s, stmt, depth + 1);
+            }
+            s.push('\n');
+            write_indent(s, depth);
+            s.push('}');
+        }
+        expr => {
+            write_expr(s, expr);
+            s.push(';');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lexer::lexer,
+        parser::{parser, tests::SOURCES},
+        variable::variable_analysis,
+    };
+
+    fn parse(src: &str) -> Vec<Tree> {
+        let (tokens, _ident_count) = variable_analysis(lexer(src).unwrap()).unwrap();
+        parser(tokens).unwrap()
+    }
+
+    #[test]
+    fn expr_test() {
+        assert_eq!("1 + 2 * 3", parse("1+2*3;")[0].to_string());
+        assert_eq!("(1 + 2) * 3", parse("(1+2)*3;")[0].to_string());
+        assert_eq!("1 - (2 - 3)", parse("1-(2-3);")[0].to_string());
+        assert_eq!("1 - 2 - 3", parse("1-2-3;")[0].to_string());
+        assert_eq!("a = b = 1", parse("a=(b=1);")[0].to_string());
+        assert_eq!("-(1 + 2) * -a", parse("-(1+2)*-a;")[0].to_string());
+        assert_eq!("1 < 2 == 2 <= 3", parse("2>1==3>=2;")[0].to_string());
+    }
+
+    #[test]
+    fn stmt_test() {
+        assert_eq!(
+            "if (a < 1) {\n    a = 1;\n} else if (a == 1)\n    return a;\nelse\n    a = 2;\n",
+            unparse(&parse(
+                "if (a<1) {a=1;} else if (a==1) return a; else a = 2;"
+            ))
+        );
+        assert_eq!(
+            "for (;;) {}\nwhile (1)\n    ;\n",
+            unparse(&parse("for(;;){} while(1);"))
+        );
+    }
+
+    #[test]
+    fn dangling_else_test() {
+        let tree = Tree::new_if_else(
+            Tree::new_val("a", 8),
+            Tree::new_if(Tree::new_val("b", 16), Tree::new_int(1)),
+            Tree::new_int(2),
+        );
+
+        assert_eq!(
+            "if (a) {\n    if (b)\n        1;\n} else\n    2;",
+            tree.to_string()
+        );
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let extras = [
+            " 12 + 34 -5 ;",
+            "32/(1==1)<2;",
+            "a = 3; b = 5*6 - 8; return a + b / 2;",
+            "i = 1; while(i < 3) i = i*2;i;",
+            "for(a = 0; a < 10; a = a + 1)a + 1;a;",
+            "{{{{{{{{{{0;1;2;3;10;}}}}}}}}}}",
+            "num = 0; for (i = 1; i <= 10; i = i + 1) { num = num + 1; if (i == 2) { num = num + 1; } else { num = num + 2; } } return num;",
+            "if (a) if (b) 1; else 2;",
+            "a = -(b - -c) * -(1 / d);",
+            "foo(); x = bar() + 1;",
+        ];
+
+        for src in SOURCES.into_iter().chain(extras) {
+            let trees = parse(src);
+            assert_eq!(trees, parse(&unparse(&trees)), "{}", src);
+        }
+    }
+}
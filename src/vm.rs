@@ -0,0 +1,135 @@
+use crate::bytecode::{Op, Program};
+use crate::interp::{binary, InterpretError, Value};
+use crate::tree::NodeKind;
+
+/// Runs the bytecode and returns the value `main` would return, like `interpreter` does for the
+/// trees.
+pub fn vm(program: &Program) -> Result<Value, InterpretError> {
+    let mut frame = vec![0; program.frame_size as usize];
+    let mut stack: Vec<Value> = vec![];
+    let mut result = 0;
+    let mut pc = 0;
+
+    let pop = |stack: &mut Vec<Value>| match stack.pop() {
+        Some(value) => Ok(value),
+        None => Err("stack underflow".to_owned()),
+    };
+    let slot = |frame: &[Value], address: Value| match usize::try_from(address) {
+        Ok(n) if n < frame.len() => Ok(n),
+        _ => Err(format!("address {} is outside of the stack frame", address)),
+    };
+
+    while let Some(op) = program.code.get(pc) {
+        pc += 1;
+        match *op {
+            Op::Push(n) => stack.push(n),
+            Op::Addr(n) => stack.push(n as Value),
+            Op::Load => {
+                let address = pop(&mut stack)?;
+                stack.push(frame[slot(&frame, address)?]);
+            }
+            Op::Store => {
+                let value = pop(&mut stack)?;
+                let address = pop(&mut stack)?;
+                let n = slot(&frame, address)?;
+                frame[n] = value;
+                stack.push(value);
+            }
+            Op::Pop => result = pop(&mut stack)?,
+            Op::Jump(n) => pc = n as usize,
+            Op::JumpIfZero(n) => {
+                if pop(&mut stack)? == 0 {
+                    pc = n as usize;
+                }
+            }
+            Op::Call(n) => {
+                return Err(format!(
+                    "cannot call external function '{}'",
+                    program.functions[n as usize]
+                ))
+            }
+            Op::Ret => return pop(&mut stack),
+            op => {
+                let rhs = pop(&mut stack)?;
+                let lhs = pop(&mut stack)?;
+                let kind = match op {
+                    Op::Add => NodeKind::Add,
+                    Op::Sub => NodeKind::Sub,
+                    Op::Mul => NodeKind::Mul,
+                    Op::Div => NodeKind::Div,
+                    Op::Eq => NodeKind::Equality,
+                    Op::Ne => NodeKind::Nonequality,
+                    Op::Lt => NodeKind::Less,
+                    Op::Le => NodeKind::LessOrEqual,
+                    _ => unreachable!(),
+                };
+                stack.push(binary(&kind, lhs, rhs)?);
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{bytecode, deserialize, serialize};
+    use crate::interp::exit_status;
+    use crate::{lexer::lexer, parser::parser, variable::variable_analysis};
+
+    fn run(src: &str) -> Result<i32, InterpretError> {
+        let (tokens, ident_count) = variable_analysis(lexer(src).unwrap()).unwrap();
+        let program = bytecode(&parser(tokens).unwrap(), ident_count).unwrap();
+        vm(&deserialize(&serialize(&program)).unwrap()).map(exit_status)
+    }
+
+    #[test]
+    fn vm_test() {
+        let cases = [
+            (41, " 12 + 34 -5 ;"),
+            (4, "(3+5)/2;"),
+            (246, "-10;"),
+            (0, "32/(1==1)<2;"),
+            (1, "0>=0;"),
+            (14, "a = 3; b = 5*6 - 8; a + b / 2;"),
+            (5, "return 5; return8;"),
+            (5, "if(3 > 2)return 5; else return 4;"),
+            (4, "if(3 < 2)return 5; else return 4;"),
+            (4, "i = 1; while(i < 3) i = i*2;i;"),
+            (10, "for(a = 0; a < 10; a = a + 1)a + 1;a;"),
+            (10, "{{{{{{{{{{0;1;2;3;10;}}}}}}}}}}"),
+            (7, "for (;;) return 7;"),
+            (
+                55,
+                "num = 0; for (i = 1; i <= 10; i = i + 1) { num = num + i; } return num;",
+            ),
+        ];
+
+        for (expected, src) in cases {
+            assert_eq!(Ok(expected), run(src), "{}", src);
+        }
+    }
+
+    #[test]
+    fn error_test() {
+        assert_eq!(Err("division by zero".to_owned()), run("1 / 0;"));
+        assert_eq!(
+            Err("cannot call external function 'foo'".to_owned()),
+            run("foo();")
+        );
+        let program = Program {
+            code: vec![Op::Push(3), Op::Load],
+            ..Program::default()
+        };
+        assert_eq!(
+            Err("address 3 is outside of the stack frame".to_owned()),
+            vm(&program)
+        );
+        let program = Program {
+            code: vec![Op::Add],
+            ..Program::default()
+        };
+        assert_eq!(Err("stack underflow".to_owned()), vm(&program));
+    }
+}
@@ -0,0 +1,25 @@
+use crate::tree::Tree;
+use crate::unparse::unparse;
+use std::fmt;
+
+/// Something in a program that compiles but likely isn't what was meant.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Warning {
+    /// a statement control never reaches, left out of the output
+    Unreachable(Tree),
+    /// a variable that is given a value nothing reads
+    DeadStore(String),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::Unreachable(stmt) => write!(
+                f,
+                "unreachable statement `{}`",
+                unparse(std::slice::from_ref(stmt)).trim_end()
+            ),
+            Warning::DeadStore(var) => write!(f, "value assigned to `{}` is never read", var),
+        }
+    }
+}
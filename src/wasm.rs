@@ -0,0 +1,274 @@
+use crate::generator::GenerateError;
+use crate::tree::*;
+use std::collections::BTreeSet;
+
+/// Lowers the program to a WebAssembly text module that runs under WASI.
+///
+/// Variables become `i64` locals of `$main`, named `$v.<name>`, and `_start` exits with what
+/// `main` returns, so `wasmtime main.wat` behaves like the native binaries. Called functions are
+/// imported from `env` as `$f.<name>`, so neither clash with the module's own names.
+pub fn wat(trees: &[Tree]) -> Result<String, GenerateError> {
+    let mut function = Function::default();
+    for tree in trees {
+        function.stmt(tree)?;
+    }
+
+    let mut wat = "(module\n".to_owned();
+    wat.push_str(
+        "  (import \"wasi_snapshot_preview1\" \"proc_exit\" (func $proc_exit (param i32)))\n",
+    );
+    for callee in &function.callees {
+        wat.push_str(&format!(
+            "  (import \"env\" \"{}\" (func $f.{} (result i64)))\n",
+            callee, callee
+        ));
+    }
+    // Nothing is addressable yet, but WASI expects the module to export its memory.
+    wat.push_str("  (memory (export \"memory\") 1)\n");
+
+    wat.push_str("  (func $main (export \"main\") (result i32)\n");
+    wat.push_str("    (local $last i64)\n");
+    for name in variables(trees) {
+        wat.push_str(&format!("    (local $v.{} i64)\n", name));
+    }
+    wat.push_str(&function.body);
+    wat.push_str("    local.get $last\n    i32.wrap_i64)\n");
+
+    wat.push_str("  (func $_start (export \"_start\")\n");
+    wat.push_str("    call $main\n    i32.const 255\n    i32.and\n    call $proc_exit))\n");
+
+    Ok(wat)
+}
+
+#[derive(Default)]
+struct Function {
+    body: String,
+    /// how many blocks the next instruction is nested in
+    depth: usize,
+    callees: BTreeSet<String>,
+}
+
+impl Function {
+    fn inst(&mut self, inst: &str) {
+        self.body.push_str(&"  ".repeat(self.depth + 2));
+        self.body.push_str(inst);
+        self.body.push('\n');
+    }
+
+    fn begin(&mut self, inst: &str) {
+        self.inst(inst);
+        self.depth += 1;
+    }
+
+    fn end(&mut self) {
+        self.depth -= 1;
+        self.inst("end");
+    }
+
+    /// Branches out of the `label`th enclosing block when `cond` is zero. A missing condition,
+    /// like in `for (;;)`, is always true.
+    fn br_unless(&mut self, cond: &Tree, label: usize) -> Result<(), GenerateError> {
+        if *cond == Tree::None {
+            return Ok(());
+        }
+        self.expr(cond)?;
+        self.inst("i64.eqz");
+        self.inst(&format!("br_if {}", label));
+        Ok(())
+    }
+
+    fn stmt(&mut self, tree: &Tree) -> Result<(), GenerateError> {
+        match tree {
+            Tree::None => {}
+            Tree::Return(expr) => {
+                self.expr(expr)?;
+                self.inst("i32.wrap_i64");
+                self.inst("return");
+            }
+            Tree::If(cond, stmt) => {
+                self.begin("block");
+                self.br_unless(cond, 0)?;
+                self.stmt(stmt)?;
+                self.end();
+            }
+            Tree::IfElse(cond, stmt, else_stmt) => {
+                self.begin("block");
+                self.begin("block");
+                self.br_unless(cond, 0)?;
+                self.stmt(stmt)?;
+                self.inst("br 1");
+                self.end();
+                self.stmt(else_stmt)?;
+                self.end();
+            }
+            Tree::While(cond, stmt) => {
+                self.begin("block");
+                self.begin("loop");
+                self.br_unless(cond, 1)?;
+                self.stmt(stmt)?;
+                self.inst("br 0");
+                self.end();
+                self.end();
+            }
+            Tree::For(init, cond, step, stmt) => {
+                self.stmt(init)?;
+                self.begin("block");
+                self.begin("loop");
+                self.br_unless(cond, 1)?;
+                self.stmt(stmt)?;
+                self.stmt(step)?;
+                self.inst("br 0");
+                self.end();
+                self.end();
+            }
+            Tree::Block(trees) => {
+                for tree in trees {
+                    self.stmt(tree)?;
+                }
+            }
+            expr => {
+                self.expr(expr)?;
+                self.inst("local.set $last");
+            }
+        }
+        Ok(())
+    }
+
+    /// Leaves the `i64` value of `tree` on the operand stack.
+    fn expr(&mut self, tree: &Tree) -> Result<(), GenerateError> {
+        match tree {
+            Tree::Int(n) => self.inst(&format!("i64.const {}", *n as i64)),
+            Tree::Val { name, .. } => self.inst(&format!("local.get $v.{}", name)),
+            // `main` is defined here and returns an i32
+            Tree::Func { name } if name == "main" => {
+                self.inst("call $main");
+                self.inst("i64.extend_i32_s");
+            }
+            Tree::Func { name } => {
+                self.callees.insert(name.clone());
+                self.inst(&format!("call $f.{}", name));
+            }
+            Tree::Node(NodeKind::Assign, lhs, rhs) => {
+                let name = match &**lhs {
+                    Tree::Val { name, .. } => name,
+                    _ => {
+                        return Err(
+                            "The left-hand side value of the assignment is not a variable"
+                                .to_owned(),
+                        )
+                    }
+                };
+                self.expr(rhs)?;
+                self.inst(&format!("local.tee $v.{}", name));
+            }
+            Tree::Node(kind, lhs, rhs) => {
+                self.expr(lhs)?;
+                self.expr(rhs)?;
+                let (op, compare) = match kind {
+                    NodeKind::Add => ("i64.add", false),
+                    NodeKind::Sub => ("i64.sub", false),
+                    NodeKind::Mul => ("i64.mul", false),
+                    NodeKind::Div => ("i64.div_s", false),
+                    NodeKind::Equality => ("i64.eq", true),
+                    NodeKind::Nonequality => ("i64.ne", true),
+                    NodeKind::Less => ("i64.lt_s", true),
+                    NodeKind::LessOrEqual => ("i64.le_s", true),
+                    NodeKind::Assign => unreachable!(),
+                };
+                self.inst(op);
+                // comparisons leave an i32
+                if compare {
+                    self.inst("i64.extend_i32_u");
+                }
+            }
+            _ => return Err("unexpected statement in expression".to_owned()),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::lexer, parser::parser, variable::variable_analysis};
+
+    fn wat_of(src: &str) -> String {
+        let (tokens, _) = variable_analysis(lexer(src).unwrap()).unwrap();
+        wat(&parser(tokens).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn module_test() {
+        assert_eq!(
+            "(module
+  (import \"wasi_snapshot_preview1\" \"proc_exit\" (func $proc_exit (param i32)))
+  (import \"env\" \"foo\" (func $f.foo (result i64)))
+  (memory (export \"memory\") 1)
+  (func $main (export \"main\") (result i32)
+    (local $last i64)
+    (local $v.a i64)
+    i64.const 3
+    local.tee $v.a
+    local.set $last
+    local.get $v.a
+    call $f.foo
+    i64.lt_s
+    i64.extend_i32_u
+    local.set $last
+    local.get $last
+    i32.wrap_i64)
+  (func $_start (export \"_start\")
+    call $main
+    i32.const 255
+    i32.and
+    call $proc_exit))
+",
+            wat_of("a = 3; a < foo();")
+        );
+    }
+
+    #[test]
+    fn control_flow_test() {
+        let wat = wat_of("while (a < 3) if (a == 1) return a; else a = a + 1;");
+
+        assert!(wat.contains(
+            "    block
+      loop
+        local.get $v.a
+        i64.const 3
+        i64.lt_s
+        i64.extend_i32_u
+        i64.eqz
+        br_if 1
+        block
+          block
+"
+        ));
+        assert!(wat.contains(
+            "            i32.wrap_i64
+            return
+            br 1
+          end
+          local.get $v.a
+"
+        ));
+        assert!(wat.contains("        br 0\n      end\n    end\n"));
+    }
+
+    #[test]
+    fn names_test() {
+        let last = wat_of("last = 5; last + 1;");
+        assert_eq!(1, last.matches("(local $last i64)").count());
+        assert!(last.contains("    (local $v.last i64)\n"));
+
+        // main is called, not imported
+        let recursive = wat_of("return main();");
+        assert!(!recursive.contains("\"env\""));
+        assert!(recursive.contains("    call $main\n    i64.extend_i32_s\n"));
+
+        let exit = wat_of("proc_exit();");
+        assert_eq!(1, exit.matches("(func $proc_exit (param i32))").count());
+        assert!(exit.contains("(import \"env\" \"proc_exit\" (func $f.proc_exit (result i64)))"));
+        assert!(exit.contains("    call $f.proc_exit\n"));
+    }
+}
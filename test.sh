@@ -4,6 +4,33 @@ IOC=./target/release/ioc2
 MAINC=./source/main.c
 MAINS=./source/main.s
 MAIN=./source/main
+MAINO=./source/main.o
+RISCV_MAINS=./source/main.riscv64.s
+RISCV_MAIN=./source/main.riscv64
+RISCV_GCC=riscv64-linux-gnu-gcc
+MAINIOCB=./source/main.iocb
+MAINWAT=./source/main.wat
+MAINLL=./source/main.ll
+LLVM_MAINS=./source/main.llvm.s
+
+# The riscv64 output is checked under qemu user-mode when a cross toolchain is installed.
+if command -v qemu-riscv64 > /dev/null && command -v $RISCV_GCC > /dev/null; then
+    RISCV=1
+fi
+
+if command -v wasmtime > /dev/null; then
+    WASM=1
+fi
+
+# The LLVM IR uses opaque pointers, which llc 14 only reads with -opaque-pointers.
+if command -v llc > /dev/null; then
+    for flag in "" -opaque-pointers; do
+        if echo 'define void @f(ptr %p) { ret void }' | llc $flag -o /dev/null 2> /dev/null; then
+            LLC="llc $flag"
+            break
+        fi
+    done
+fi
 
 mkdir -p ./source
 
@@ -12,6 +39,22 @@ assert() {
     input="$2"
 
     echo $input > $MAINC
+
+    $IOC --interp $MAINC
+    interpreted="$?"
+    if [ "$interpreted" != "$expected" ]; then
+        echo "$input => $expected expected, but the interpreter got $interpreted" >&2
+        exit 1
+    fi
+
+    $IOC --emit-bytecode $MAINC $MAINIOCB
+    $IOC run $MAINIOCB
+    actual="$?"
+    if [ "$actual" != "$expected" ]; then
+        echo "$input => $expected expected, but the bytecode VM got $actual" >&2
+        exit 1
+    fi
+
     $IOC $MAINC $MAINS
     gcc $MAINS -o $MAIN
     $MAIN
@@ -23,6 +66,75 @@ assert() {
         echo "$input => $expected expected, but got $actual" >&2
         exit 1
     fi
+
+    for level in 1 2 s; do
+        $IOC -O$level $MAINC $MAINS 2> /dev/null
+        gcc $MAINS -o $MAIN
+        $MAIN
+        actual="$?"
+        if [ "$actual" != "$expected" ]; then
+            echo "$input => $expected expected, but -O$level got $actual" >&2
+            exit 1
+        fi
+    done
+
+    if [ "$(uname -m)" = x86_64 ]; then
+        $IOC -c $MAINC $MAINO
+        gcc $MAINO -o $MAIN
+        $MAIN
+        actual="$?"
+        if [ "$actual" != "$expected" ]; then
+            echo "$input => $expected expected, but the object file got $actual" >&2
+            exit 1
+        fi
+
+        $IOC --jit $MAINC
+        actual="$?"
+        if [ "$actual" != "$expected" ]; then
+            echo "$input => $expected expected, but --jit got $actual" >&2
+            exit 1
+        fi
+
+        $IOC -O1 --jit $MAINC
+        actual="$?"
+        if [ "$actual" != "$expected" ]; then
+            echo "$input => $expected expected, but -O1 --jit got $actual" >&2
+            exit 1
+        fi
+    fi
+
+    if [ -n "$LLC" ]; then
+        $IOC --emit-llvm $MAINC $MAINLL
+        $LLC $MAINLL -o $LLVM_MAINS
+        gcc $LLVM_MAINS -o $MAIN
+        $MAIN
+        actual="$?"
+        if [ "$actual" != "$expected" ]; then
+            echo "$input => $expected expected, but llc got $actual" >&2
+            exit 1
+        fi
+    fi
+
+    if [ -n "$WASM" ]; then
+        $IOC --target=wasm32-wasi $MAINC $MAINWAT
+        wasmtime $MAINWAT
+        actual="$?"
+        if [ "$actual" != "$expected" ]; then
+            echo "$input => $expected expected, but wasm32 got $actual" >&2
+            exit 1
+        fi
+    fi
+
+    if [ -n "$RISCV" ]; then
+        $IOC --target=riscv64-linux $MAINC $RISCV_MAINS
+        $RISCV_GCC -static $RISCV_MAINS -o $RISCV_MAIN
+        qemu-riscv64 $RISCV_MAIN
+        actual="$?"
+        if [ "$actual" != "$expected" ]; then
+            echo "$input => $expected expected, but riscv64 got $actual" >&2
+            exit 1
+        fi
+    fi
 }
 
 # ダブルクオーテーションの中でも*の後ろに空白文字があるとメタ文字と解釈されてファイル一覧に展開されるから注意